@@ -0,0 +1,126 @@
+use std::{net::IpAddr, str::FromStr};
+
+use crate::error::ParseError;
+
+/// A network in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A bare
+/// address is a network of just that host.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack socket show up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => prefix_matches(
+                u128::from(u32::from(net)),
+                u128::from(u32::from(ip)),
+                self.prefix,
+                32,
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), self.prefix, 128)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: u128, ip: u128, prefix: u8, bits: u8) -> bool {
+    let host_bits = u32::from(bits - prefix);
+    net.checked_shr(host_bits).unwrap_or(0) == ip.checked_shr(host_bits).unwrap_or(0)
+}
+
+impl FromStr for Network {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|_| ParseError::ConversionError)?;
+        let bits = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= bits)
+                .ok_or(ParseError::ConversionError)?,
+            None => bits,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+/// Which clients we answer. Anyone on a denied network is refused; if there
+/// are allowed networks, so is anyone not on one of them.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    allow: Vec<Network>,
+    deny: Vec<Network>,
+}
+
+impl Acl {
+    pub fn new(allow: Vec<Network>, deny: Vec<Network>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|network| network.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|network| network.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{Acl, Network};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_network_contains() {
+        let net: Network = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.200.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.1")));
+
+        let net: Network = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains(ip("2001:db8:1::1")));
+        assert!(!net.contains(ip("2001:db9::1")));
+        assert!(!net.contains(ip("10.1.0.1")));
+
+        assert!("0.0.0.0/0"
+            .parse::<Network>()
+            .unwrap()
+            .contains(ip("192.0.2.1")));
+        let host: Network = "192.0.2.1".parse().unwrap();
+        assert!(host.contains(ip("192.0.2.1")));
+        assert!(!host.contains(ip("192.0.2.2")));
+
+        assert!("10.0.0.0/33".parse::<Network>().is_err());
+        assert!("example.com/8".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn test_acl_deny_wins_over_allow() {
+        let acl = Acl::new(
+            vec!["10.0.0.0/8".parse().unwrap()],
+            vec!["10.0.0.0/24".parse().unwrap()],
+        );
+        assert!(acl.permits(ip("10.1.0.1")));
+        assert!(!acl.permits(ip("10.0.0.1")));
+        assert!(!acl.permits(ip("192.0.2.1")));
+        assert!(Acl::default().permits(ip("192.0.2.1")));
+    }
+}
@@ -3,84 +3,210 @@ use std::str;
 use crate::{
     error::{ParseError, UdpBufferError},
     header::{DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, SectionCount},
-    section::{Class, Section, SectionGroup, Type},
+    section::{Class, RData, Section, SectionGroup, Type},
 };
 pub const MAX_UDP_PACKET_SIZE: usize = 512;
+/// Largest UDP message we accept once EDNS lets peers go past 512 bytes.
+pub const MAX_EDNS_PACKET_SIZE: usize = 4096;
 pub const DNS_HEADER_SIZE: usize = 12;
+const MAX_LABEL_LENGTH: u8 = 63;
+const MAX_NAME_LENGTH: usize = 255;
 
+/// How forgiving the parser is about messages that are well-formed enough
+/// to read but break the rules: compression pointers to later offsets,
+/// RDATA shorter than its RDLENGTH and bytes after the last record.
+/// Anything that can't be read unambiguously is an error either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ParseMode {
+    #[default]
+    Strict,
+    Lenient,
+}
+
+/// A cursor over a message of any length, borrowed rather than copied.
+/// Every read is checked against the length of the message itself.
 #[derive(Debug)]
-pub struct UdpBuffer {
-    inner: [u8; MAX_UDP_PACKET_SIZE],
+pub struct UdpBuffer<'a> {
+    inner: &'a [u8],
     pos: usize,
+    mode: ParseMode,
+}
+
+/// A possibly compressed name inside a message, only decoded when its
+/// labels are asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Name<'a> {
+    message: &'a [u8],
+    offset: usize,
+    mode: ParseMode,
 }
 
-use anyhow;
+impl<'a> Name<'a> {
+    /// Where the name starts in the message.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
 
-impl UdpBuffer {
-    pub fn new(inner: [u8; MAX_UDP_PACKET_SIZE]) -> Self {
-        UdpBuffer { inner, pos: 0 }
+    /// The raw labels, following compression pointers as they come up.
+    pub fn labels(&self) -> Labels<'a> {
+        Labels {
+            buf: UdpBuffer {
+                inner: self.message,
+                pos: self.offset,
+                mode: self.mode,
+            },
+            start: self.offset,
+            length: 0,
+            pointers: Vec::new(),
+            done: false,
+        }
     }
 
-    fn unpack_domain(&mut self, is_asection: bool) -> anyhow::Result<SectionGroup> {
-        let mut jumps = 0u8;
-        let mut reset_pos = None;
+    /// The labels as owned strings, the way `SectionGroup` holds them.
+    pub fn to_labels(&self) -> Result<Vec<String>, ParseError> {
+        let mut labels = self.labels();
         let mut domain = Vec::new();
+        while let Some(label) = labels.next() {
+            let label = label?;
+            let offset = labels.buf.pos() - label.len();
+            let label = str::from_utf8(label).map_err(|_| ParseError::InvalidLabel { offset })?;
+            domain.push(label.to_owned());
+        }
+        Ok(domain)
+    }
+}
 
+/// Iterator over the labels of a `Name`.
+#[derive(Debug)]
+pub struct Labels<'a> {
+    buf: UdpBuffer<'a>,
+    start: usize,
+    /// Wire length of the name so far, counting the root label.
+    length: usize,
+    /// Offsets of the pointers followed so far.
+    pointers: Vec<usize>,
+    done: bool,
+}
+
+impl<'a> Labels<'a> {
+    fn next_label(&mut self) -> Result<Option<&'a [u8]>, ParseError> {
         loop {
-            let len = self.peek()?;
-            if len == 0 {
-                if jumps > 0 {
-                    self.seek(reset_pos.ok_or(ParseError::JumpError)?)?;
-                } else {
-                    self.read()?;
+            let offset = self.buf.pos();
+            let len = self.buf.get_u8()?;
+            if len & 0xC0 == 0xC0 {
+                let target = usize::from(len & 0b0011_1111) << 8 | usize::from(self.buf.get_u8()?);
+                if self.pointers.contains(&offset) {
+                    return Err(ParseError::PointerLoop { offset });
                 }
-                break;
+                // RFC 1035 4.1.4: pointers refer to a prior occurrence
+                if target > offset && self.buf.mode == ParseMode::Strict {
+                    return Err(ParseError::ForwardPointer { offset, target });
+                }
+                self.pointers.push(offset);
+                self.buf.seek(target)?;
+                continue;
+            }
+            if len > MAX_LABEL_LENGTH {
+                return Err(ParseError::LabelTooLong {
+                    offset,
+                    length: len,
+                });
+            }
+            self.length += 1 + usize::from(len);
+            if self.length + usize::from(len > 0) > MAX_NAME_LENGTH {
+                return Err(ParseError::NameTooLong { offset: self.start });
             }
+            if len == 0 {
+                return Ok(None);
+            }
+            return Ok(Some(self.buf.get_bytes(usize::from(len))?));
+        }
+    }
+}
+
+impl<'a> Iterator for Labels<'a> {
+    type Item = Result<&'a [u8], ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let label = self.next_label().transpose();
+        self.done = !matches!(label, Some(Ok(_)));
+        label
+    }
+}
+
+impl<'a> UdpBuffer<'a> {
+    /// A strict parser over `inner`.
+    pub fn new(inner: &'a [u8]) -> Self {
+        UdpBuffer {
+            inner,
+            pos: 0,
+            mode: ParseMode::default(),
+        }
+    }
+
+    pub fn with_mode(mut self, mode: ParseMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    /// Skips over the name at the current offset, handing back a view of it
+    /// to decode later.
+    pub fn read_name(&mut self) -> Result<Name<'a>, ParseError> {
+        let offset = self.pos;
+        loop {
+            let label_offset = self.pos;
+            let len = self.get_u8()?;
             if len & 0xC0 == 0xC0 {
-                jumps += 1;
-                let len = self.get_u16()?;
-                // so it doesn't reset_pos again
-                if reset_pos.is_none() {
-                    reset_pos = Some(self.pos);
-                }
-                self.seek(usize::try_from(len & 0b0011_1111_1111_1111)?)?;
-            } else {
-                let len = usize::try_from(self.get_u8()?)?;
-                let label = str::from_utf8(
-                    self.inner
-                        .get(self.pos..(self.pos + len))
-                        .ok_or(ParseError::SectionError)?,
-                )?
-                .to_owned();
-                self.seek(self.pos + len)?;
-                domain.push(label);
+                // a pointer always ends the name as it's written here
+                self.get_u8()?;
+                break;
+            }
+            if len > MAX_LABEL_LENGTH {
+                return Err(ParseError::LabelTooLong {
+                    offset: label_offset,
+                    length: len,
+                });
+            }
+            if len == 0 {
+                break;
             }
+            self.get_bytes(usize::from(len))?;
         }
-        let t_type = Type::try_from(self.get_u16()?)?;
-        let class = Class::try_from(self.get_u16()?)?;
-        Ok(SectionGroup::new(
-            domain,
-            t_type,
-            class,
-            match is_asection {
-                true => {
-                    let (ttl, length) = (self.get_u32()?, self.get_u16()?);
-                    let end = self.pos + usize::try_from(length)?;
-                    let data = self
-                        .inner
-                        .get(self.pos..end)
-                        .ok_or(ParseError::SectionError)?
-                        .to_vec();
-
-                    self.seek(end)?;
-                    Some((ttl, length, data))
-                }
-                false => None,
-            },
-        ))
+        Ok(Name {
+            message: self.inner,
+            offset,
+            mode: self.mode,
+        })
+    }
+
+    /// Reads a possibly compressed name, leaving the position right after
+    /// the name as it appears at the current offset.
+    pub(crate) fn unpack_name(&mut self) -> Result<Vec<String>, ParseError> {
+        self.read_name()?.to_labels()
+    }
+
+    fn unpack_domain(&mut self, is_asection: bool) -> Result<SectionGroup, ParseError> {
+        let domain = self.unpack_name()?;
+        let t_type = Type::from(self.get_u16()?);
+        let class = Class::from(self.get_u16()?);
+        let asection = match is_asection {
+            true => {
+                let (ttl, length) = (self.get_u32()?, self.get_u16()?);
+                Some((ttl, RData::unpack(self, &t_type, length)?))
+            }
+            false => None,
+        };
+        Ok(SectionGroup::new(domain, t_type, class, asection))
     }
 
-    fn unpack_section(&mut self, count: u16, is_asection: bool) -> anyhow::Result<Section> {
+    fn unpack_section(&mut self, count: u16, is_asection: bool) -> Result<Section, ParseError> {
         let start_pos = self.pos;
         let mut groups = Vec::new();
         for _ in 0..count {
@@ -96,7 +222,7 @@ impl UdpBuffer {
         ))
     }
 
-    pub fn unpack(mut self) -> anyhow::Result<(DnsHeader, [Option<Section>; 4])> {
+    pub fn unpack(mut self) -> Result<(DnsHeader, [Option<Section>; 4]), ParseError> {
         let hdr = self.unpack_dns_header()?;
         let counts = hdr.counts();
         let (qdcount, ancount, nscount, arcount) = (
@@ -121,10 +247,28 @@ impl UdpBuffer {
         if arcount > 0 {
             sections[3] = Some(self.unpack_section(arcount, true)?);
         }
+        if self.pos < self.inner.len() && self.mode == ParseMode::Strict {
+            return Err(ParseError::TrailingBytes {
+                offset: self.pos,
+                count: self.inner.len() - self.pos,
+            });
+        }
         Ok((hdr, sections))
     }
 
-    fn unpack_dns_header(&mut self) -> anyhow::Result<DnsHeader> {
+    /// The first question of a message that may not parse as a whole, e.g.
+    /// to echo it in an error reply. The header is skipped without being
+    /// checked.
+    pub fn first_question(mut self) -> Option<SectionGroup> {
+        self.seek(4).ok()?;
+        if self.get_u16().ok()? == 0 {
+            return None;
+        }
+        self.seek(DNS_HEADER_SIZE).ok()?;
+        self.unpack_domain(false).ok()
+    }
+
+    fn unpack_dns_header(&mut self) -> Result<DnsHeader, ParseError> {
         let txid = self.get_u16()?;
         let first_half = self.get_u8()?;
         let second_half = self.get_u8()?;
@@ -141,17 +285,18 @@ impl UdpBuffer {
     }
 
     fn read(&mut self) -> Result<u8, UdpBufferError> {
-        if self.pos >= MAX_UDP_PACKET_SIZE {
-            Err(UdpBufferError::EndOfBuffer)
-        } else {
-            let res = self.inner[self.pos];
-            self.pos += 1;
-            Ok(res)
-        }
+        let res = *self
+            .inner
+            .get(self.pos)
+            .ok_or(UdpBufferError::EndOfBuffer { offset: self.pos })?;
+        self.pos += 1;
+        Ok(res)
     }
 
-    fn seek(&mut self, index: usize) -> Result<(), UdpBufferError> {
-        if index >= MAX_UDP_PACKET_SIZE {
+    /// Moves to `index`; the end of the message is a valid position, e.g.
+    /// after a compressed name that is the last thing in it.
+    pub(crate) fn seek(&mut self, index: usize) -> Result<(), UdpBufferError> {
+        if index > self.inner.len() {
             Err(UdpBufferError::Seek { index })
         } else {
             self.pos = index;
@@ -159,31 +304,38 @@ impl UdpBuffer {
         }
     }
 
-    #[allow(dead_code)]
-    fn has_remaining(&mut self) -> bool {
-        self.pos >= MAX_UDP_PACKET_SIZE
+    /// Bytes left after the current position.
+    pub(crate) fn remaining(&self) -> usize {
+        self.inner.len().saturating_sub(self.pos)
     }
 
-    fn peek(&self) -> Result<u8, UdpBufferError> {
-        if self.pos >= MAX_UDP_PACKET_SIZE {
-            Err(UdpBufferError::EndOfBuffer)
-        } else {
-            Ok(self.inner[self.pos])
-        }
+    pub(crate) fn pos(&self) -> usize {
+        self.pos
+    }
+
+    pub(crate) fn get_bytes(&mut self, len: usize) -> Result<&'a [u8], UdpBufferError> {
+        let start = self.pos;
+        let end = start + len;
+        let bytes = self
+            .inner
+            .get(start..end)
+            .ok_or(UdpBufferError::Seek { index: end })?;
+        self.pos = end;
+        Ok(bytes)
     }
 
-    fn get_u8(&mut self) -> Result<u8, UdpBufferError> {
+    pub(crate) fn get_u8(&mut self) -> Result<u8, UdpBufferError> {
         self.read()
     }
 
-    fn get_u16(&mut self) -> Result<u16, UdpBufferError> {
+    pub(crate) fn get_u16(&mut self) -> Result<u16, UdpBufferError> {
         let mut res = (self.read()? as u16) << 8;
         res |= self.read()? as u16;
         Ok(res)
     }
 
-    fn get_u32(&mut self) -> Result<u32, UdpBufferError> {
-        let mut res = (self.get_u16()? as u32) << 8;
+    pub(crate) fn get_u32(&mut self) -> Result<u32, UdpBufferError> {
+        let mut res = (self.get_u16()? as u32) << 16;
         res |= self.get_u16()? as u32;
         Ok(res)
     }
@@ -197,7 +349,11 @@ mod tests {
         Truncation,
     };
 
-    use super::UdpBuffer;
+    use crate::section::{Class, RData, Type};
+
+    use crate::{error::ParseError, writer::MessageWriter};
+
+    use super::{ParseMode, UdpBuffer, MAX_UDP_PACKET_SIZE};
 
     #[test]
     fn test_parse_domain_2() {
@@ -222,8 +378,16 @@ mod tests {
             0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
             0, 0, 0, 0, 0, 0, 0u8,
         ];
-        let buf = UdpBuffer::new(buf);
-        let _hdr = buf.unpack().unwrap();
+        // padded out to 512 bytes, as it came off the wire
+        let lenient = UdpBuffer::new(&buf).with_mode(ParseMode::Lenient);
+        let _hdr = lenient.unpack().unwrap();
+        assert!(matches!(
+            UdpBuffer::new(&buf).unpack(),
+            Err(ParseError::TrailingBytes {
+                offset: 53,
+                count: 459
+            })
+        ));
     }
 
     #[test]
@@ -249,7 +413,7 @@ mod tests {
         .for_each(|(idx, elem)| {
             buf[12 + idx] = elem;
         });
-        let mut buf = UdpBuffer::new(buf);
+        let mut buf = UdpBuffer::new(&buf);
         let _hdr = buf.unpack_dns_header().unwrap();
         // buf.unpack_section(hdr.counts().qdcount()).unwrap();
         // buf[12] = 6;
@@ -266,6 +430,32 @@ mod tests {
         // });
     }
 
+    #[test]
+    fn test_parse_unknown_type_and_class() {
+        let mut buf = [0u8; 512];
+        [
+            0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 1, 7, b'e', b'x', b'a', b'm', b'p', b'l', b'e',
+            0, 0, 65, 0, 255, 0, 0, 41, 0x10, 0, 0, 0, 0, 0, 0, 4, 0, 10, 0, 0,
+        ]
+        .into_iter()
+        .enumerate()
+        .for_each(|(idx, elem)| {
+            buf[idx] = elem;
+        });
+        let (_, [qsection, _, _, arsection]) = UdpBuffer::new(&buf[..40]).unpack().unwrap();
+        let question = &qsection.unwrap().groups[0];
+        assert_eq!(question.group_type, Type::Unknown(65));
+        assert_eq!(question.class, Class::Any);
+        let additional = &arsection.unwrap().groups[0];
+        assert_eq!(additional.group_type, Type::Opt);
+        assert_eq!(additional.class, Class::Unknown(4096));
+        assert_eq!(
+            additional.asection,
+            Some((0, RData::Opt(vec![(10, Vec::new())])))
+        );
+        assert_eq!(u16::from(additional.class.clone()), 4096);
+    }
+
     #[test]
     fn test_parse_header_udp_buffer() {
         let mut buf = [0u8; 512];
@@ -273,7 +463,7 @@ mod tests {
         buf2.into_iter().enumerate().for_each(|(idx, byte)| {
             buf[idx] = byte;
         });
-        let hdr_actual = UdpBuffer::new(buf).unpack_dns_header().unwrap();
+        let hdr_actual = UdpBuffer::new(&buf).unpack_dns_header().unwrap();
         let hdr = DnsHeader::new(
             0x7f3b,
             HeaderSecondRowFirstHalf::new(
@@ -294,4 +484,194 @@ mod tests {
         assert_eq!(hdr_actual, hdr);
         assert_eq!(<[u8; 12]>::from(hdr_actual), buf2);
     }
+
+    #[test]
+    fn test_parse_message_longer_than_512_bytes() {
+        // a TXT answer of 600 bytes, compressed against the question
+        let mut buf = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 1, 0, 0, 0, 0];
+        buf.extend([1, b'a', 4, b't', b'e', b's', b't', 0, 0, 16, 0, 1]);
+        buf.extend([0xC0, 12, 0, 16, 0, 1, 0, 0, 0, 60, 0x02, 0x58]);
+        for _ in 0..3 {
+            buf.push(199);
+            buf.extend([b'x'; 199]);
+        }
+        assert!(buf.len() > MAX_UDP_PACKET_SIZE);
+
+        let (_, [_, ansection, _, _]) = UdpBuffer::new(&buf).unpack().unwrap();
+        let answer = &ansection.unwrap().groups[0];
+        assert_eq!(answer.domain, vec!["a".to_owned(), "test".to_owned()]);
+        let Some((_, RData::Txt(strings))) = &answer.asection else {
+            panic!("expected TXT data");
+        };
+        assert_eq!(strings.len(), 3);
+
+        // cut short anywhere, it's an error rather than a read past the end
+        assert!(UdpBuffer::new(&buf[..buf.len() - 1]).unpack().is_err());
+    }
+
+    #[test]
+    fn test_names_are_read_lazily() {
+        let buf = [
+            7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0, 3, b'w', b'w', b'w', 0xC0, 0,
+        ];
+        let mut reader = UdpBuffer::new(&buf);
+        reader.read_name().unwrap();
+        let name = reader.read_name().unwrap();
+        assert_eq!(reader.pos(), buf.len());
+        assert_eq!(name.offset(), 9);
+        let labels = name.labels().collect::<Result<Vec<&[u8]>, _>>().unwrap();
+        assert_eq!(labels, vec![&b"www"[..], &b"example"[..]]);
+        // the label borrows from the message itself
+        assert!(std::ptr::eq(labels[1], &buf[1..8]));
+        assert_eq!(name.to_labels().unwrap(), vec!["www", "example"]);
+    }
+
+    /// A header for one question and `ancount` answers, then `body`.
+    fn message(ancount: u8, body: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0, 1, 0, 0, 0, 1, 0, ancount, 0, 0, 0, 0];
+        buf.extend(body);
+        buf
+    }
+
+    #[test]
+    fn test_malformed_names_report_offsets() {
+        let parse = |buf: &[u8], mode| UdpBuffer::new(buf).with_mode(mode).unpack().err();
+        let parse_ok = |buf: &[u8], mode| UdpBuffer::new(buf).with_mode(mode).unpack().unwrap();
+        use ParseMode::{Lenient, Strict};
+
+        // a pointer to itself
+        let looped = message(0, &[0xC0, 12, 0, 1, 0, 1]);
+        for mode in [Strict, Lenient] {
+            assert!(matches!(
+                parse(&looped, mode),
+                Some(ParseError::PointerLoop { offset: 12 })
+            ));
+        }
+
+        // "a" then a pointer to the name right after the question
+        let forward = message(0, &[1, b'a', 0xC0, 20, 0, 1, 0, 1, 1, b'b', 0]);
+        assert!(matches!(
+            parse(&forward, Strict),
+            Some(ParseError::ForwardPointer {
+                offset: 14,
+                target: 20
+            })
+        ));
+        // the name it points to sits after the question, so lenient
+        // parsing takes it as trailing data too
+        let (_, [qsection, _, _, _]) = parse_ok(&forward, Lenient);
+        assert_eq!(qsection.unwrap().groups[0].domain, vec!["a", "b"]);
+
+        let mut long_label = message(0, &[64]);
+        long_label.extend([b'x'; 64]);
+        long_label.extend([0, 0, 1, 0, 1]);
+        assert!(matches!(
+            parse(&long_label, Lenient),
+            Some(ParseError::LabelTooLong {
+                offset: 12,
+                length: 64
+            })
+        ));
+
+        // four 63-byte labels make 256 bytes with the root
+        let mut long_name = message(0, &[]);
+        for _ in 0..4 {
+            long_name.push(63);
+            long_name.extend([b'x'; 63]);
+        }
+        long_name.extend([0, 0, 1, 0, 1]);
+        assert!(matches!(
+            parse(&long_name, Lenient),
+            Some(ParseError::NameTooLong { offset: 12 })
+        ));
+    }
+
+    #[test]
+    fn test_rdata_and_trailing_bytes_by_mode() {
+        let parse = |buf: &[u8], mode| UdpBuffer::new(buf).with_mode(mode).unpack();
+        use ParseMode::{Lenient, Strict};
+        let question = [1, b'a', 0, 0, 1, 0, 1];
+
+        // RDLENGTH 8 with only 4 bytes of RDATA left
+        let mut overrun = message(1, &question);
+        overrun.extend([0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 8, 192, 0, 2, 1]);
+        assert!(matches!(
+            parse(&overrun, Lenient),
+            Err(ParseError::RdataOverrun {
+                offset: 31,
+                length: 8
+            })
+        ));
+
+        // an A record with RDLENGTH 6
+        let mut padded = message(1, &question);
+        padded.extend([0xC0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 6, 192, 0, 2, 1, 0, 0]);
+        assert!(matches!(
+            parse(&padded, Strict),
+            Err(ParseError::RdataLength {
+                offset: 31,
+                length: 6,
+                used: 4
+            })
+        ));
+        let (_, [_, ansection, _, _]) = parse(&padded, Lenient).unwrap();
+        assert_eq!(
+            ansection.unwrap().groups[0].asection,
+            Some((60, RData::A([192, 0, 2, 1].into())))
+        );
+
+        let mut trailing = message(0, &question);
+        trailing.extend([0xFF; 3]);
+        assert!(matches!(
+            parse(&trailing, Strict),
+            Err(ParseError::TrailingBytes {
+                offset: 19,
+                count: 3
+            })
+        ));
+        assert!(parse(&trailing, Lenient).is_ok());
+    }
+
+    #[test]
+    fn test_mailbox_rdata_names_are_decompressed() {
+        let raw = [
+            &[0, 1, 0x81, 0x80, 0, 0, 0, 2, 0, 0, 0, 0][..],
+            // example.com MB mail.example.com, the suffix as a pointer
+            b"\x07example\x03com\x00",
+            &[0, 7, 0, 1, 0, 0, 0, 60, 0, 7],
+            b"\x04mail\xC0\x0C",
+            // example.com MINFO admin.example.com example.com
+            &[0xC0, 0x0C, 0, 14, 0, 1, 0, 0, 0, 60, 0, 10],
+            b"\x05admin\xC0\x0C\xC0\x0C",
+        ]
+        .concat();
+        let (hdr, [_, ansection, _, _]) = UdpBuffer::new(&raw).unpack().unwrap();
+        let mut groups = ansection.unwrap().groups;
+        let name = |name: &str| name.split('.').map(str::to_owned).collect::<Vec<String>>();
+        assert_eq!(
+            groups[0].asection,
+            Some((60, RData::Mb(name("mail.example.com"))))
+        );
+        assert_eq!(
+            groups[1].asection,
+            Some((
+                60,
+                RData::Minfo {
+                    rmailbx: name("admin.example.com"),
+                    emailbx: name("example.com"),
+                }
+            ))
+        );
+
+        // written back in another order, the names land at other offsets
+        groups.reverse();
+        let mut writer = MessageWriter::new();
+        writer.write_header(hdr);
+        groups
+            .iter()
+            .for_each(|group| writer.write_section_group(group).unwrap());
+        let raw = writer.into_bytes();
+        let (_, [_, ansection, _, _]) = UdpBuffer::new(&raw).unpack().unwrap();
+        assert_eq!(ansection.unwrap().groups, groups);
+    }
 }
@@ -0,0 +1,362 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    header::ResponseCode,
+    section::{Class, RData, SectionGroup, Type},
+};
+
+/// Lowercased owner name plus the question's type and class.
+pub type CacheKey = (Vec<String>, Type, Class);
+
+/// Response code, answer records and authority records of a cached response.
+/// Negative answers have no answer records and carry the zone's SOA as their
+/// authority instead.
+pub type CachedAnswer = (ResponseCode, Vec<SectionGroup>, Vec<SectionGroup>);
+
+pub const DEFAULT_CACHE_SIZE: usize = 1024;
+
+/// Upper bound on how long NXDOMAIN/NODATA answers are kept, whatever the
+/// SOA says (RFC 2308 section 5).
+pub const MAX_NEGATIVE_TTL: u32 = 3 * 60 * 60;
+
+#[derive(Debug, Clone)]
+struct CacheEntry {
+    answer: CachedAnswer,
+    inserted: Instant,
+    expires: Instant,
+    last_used: u64,
+    /// Tells apart entries expiring at the same instant.
+    id: u64,
+}
+
+/// Answers from upstream, kept until their smallest TTL runs out. Once
+/// `max_size` questions are cached the least recently used one is evicted.
+/// NXDOMAIN and NODATA responses are cached too, for the negative TTL taken
+/// from the SOA in their authority section (RFC 2308).
+#[derive(Debug)]
+pub struct Cache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    lru: BTreeMap<u64, CacheKey>,
+    /// Entries in the order they expire, so purging only looks at the
+    /// expired ones.
+    expiry: BTreeMap<(Instant, u64), CacheKey>,
+    max_size: usize,
+    clock: u64,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl Cache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            expiry: BTreeMap::new(),
+            max_size,
+            clock: 0,
+        }
+    }
+
+    pub fn key(domain: &[String], group_type: &Type, class: &Class) -> CacheKey {
+        (
+            domain
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+            group_type.clone(),
+            class.clone(),
+        )
+    }
+
+    /// Returns the cached answer with every TTL reduced by the time it has
+    /// spent in the cache.
+    pub fn get(&mut self, key: &CacheKey, now: Instant) -> Option<CachedAnswer> {
+        let entry = self.entries.get(key)?;
+        if entry.expires <= now {
+            self.remove(key);
+            return None;
+        }
+        let elapsed =
+            u32::try_from(now.duration_since(entry.inserted).as_secs()).unwrap_or(u32::MAX);
+        let decrement = |groups: &Vec<SectionGroup>| {
+            groups
+                .iter()
+                .cloned()
+                .map(|mut group| {
+                    if let Some((ttl, _)) = group.asection.as_mut() {
+                        *ttl = ttl.saturating_sub(elapsed);
+                    }
+                    group
+                })
+                .collect::<Vec<SectionGroup>>()
+        };
+        let (rcode, answers, authority) = &entry.answer;
+        let answer = (rcode.clone(), decrement(answers), decrement(authority));
+        self.touch(key);
+        Some(answer)
+    }
+
+    /// Caches `groups` for as long as the smallest TTL among them allows.
+    /// Empty answers and zero TTLs are not cached.
+    pub fn insert(&mut self, key: CacheKey, groups: Vec<SectionGroup>, now: Instant) {
+        let ttl = groups
+            .iter()
+            .filter_map(|group| group.asection.as_ref().map(|(ttl, _)| *ttl))
+            .min();
+        if let Some(ttl) = ttl {
+            self.insert_for(key, (ResponseCode::None, groups, Vec::new()), ttl, now);
+        }
+    }
+
+    /// Caches an NXDOMAIN (`ResponseCode::Name`) or NODATA
+    /// (`ResponseCode::None`) response. The negative TTL is the smaller of the
+    /// SOA's own TTL and its MINIMUM field, and the cached SOA carries that
+    /// TTL (RFC 2308 section 3); without an SOA nothing is cached.
+    pub fn insert_negative(
+        &mut self,
+        key: CacheKey,
+        rcode: ResponseCode,
+        mut soa: SectionGroup,
+        now: Instant,
+    ) {
+        let ttl = match &mut soa.asection {
+            Some((ttl, RData::Soa { minimum, .. })) => {
+                *ttl = (*ttl).min(*minimum).min(MAX_NEGATIVE_TTL);
+                *ttl
+            }
+            _ => return,
+        };
+        self.insert_for(key, (rcode, Vec::new(), vec![soa]), ttl, now);
+    }
+
+    fn insert_for(&mut self, key: CacheKey, answer: CachedAnswer, ttl: u32, now: Instant) {
+        if ttl == 0 || self.max_size == 0 {
+            return;
+        }
+        self.remove(&key);
+        if self.entries.len() >= self.max_size {
+            self.purge_expired(now);
+        }
+        while self.entries.len() >= self.max_size {
+            match self.lru.first_key_value() {
+                Some((_, oldest)) => self.remove(&oldest.clone()),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        let expires = now + Duration::from_secs(u64::from(ttl));
+        self.lru.insert(self.clock, key.clone());
+        self.expiry.insert((expires, self.clock), key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                answer,
+                inserted: now,
+                expires,
+                last_used: self.clock,
+                id: self.clock,
+            },
+        );
+    }
+
+    /// Changes how many questions are kept, evicting the least recently used
+    /// ones if there are more than that already.
+    pub fn resize(&mut self, max_size: usize) {
+        self.max_size = max_size;
+        while self.entries.len() > max_size {
+            match self.lru.first_key_value() {
+                Some((_, oldest)) => self.remove(&oldest.clone()),
+                None => break,
+            }
+        }
+    }
+
+    /// Drops every entry whose TTL has run out.
+    pub fn purge_expired(&mut self, now: Instant) {
+        while let Some(((expires, _), key)) = self.expiry.first_key_value() {
+            if *expires > now {
+                break;
+            }
+            self.remove(&key.clone());
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.lru.remove(&entry.last_used);
+            self.clock += 1;
+            entry.last_used = self.clock;
+            self.lru.insert(self.clock, key.clone());
+        }
+    }
+
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.expiry.remove(&(entry.expires, entry.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        header::ResponseCode,
+        section::{Class, RData, SectionGroup, Type},
+    };
+
+    use super::Cache;
+
+    fn answer(name: &str, ttl: u32) -> SectionGroup {
+        SectionGroup::new(
+            name.split('.').map(|label| label.to_owned()).collect(),
+            Type::A,
+            Class::In,
+            Some((ttl, RData::A(Ipv4Addr::new(127, 0, 0, 1)))),
+        )
+    }
+
+    fn key(name: &str) -> super::CacheKey {
+        let domain = name
+            .split('.')
+            .map(|label| label.to_owned())
+            .collect::<Vec<String>>();
+        Cache::key(&domain, &Type::A, &Class::In)
+    }
+
+    #[test]
+    fn test_cache_decrements_ttl_and_expires() {
+        let mut cache = Cache::new(8);
+        let now = Instant::now();
+        cache.insert(key("example.com"), vec![answer("example.com", 60)], now);
+
+        let hit = cache
+            .get(&key("EXAMPLE.com"), now + Duration::from_secs(15))
+            .unwrap();
+        assert_eq!(hit.1, vec![answer("example.com", 45)]);
+        assert!(cache
+            .get(&key("example.com"), now + Duration::from_secs(60))
+            .is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = Cache::new(2);
+        let now = Instant::now();
+        cache.insert(key("a.com"), vec![answer("a.com", 60)], now);
+        cache.insert(key("b.com"), vec![answer("b.com", 60)], now);
+        assert!(cache.get(&key("a.com"), now).is_some());
+        cache.insert(key("c.com"), vec![answer("c.com", 60)], now);
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&key("a.com"), now).is_some());
+        assert!(cache.get(&key("b.com"), now).is_none());
+        assert!(cache.get(&key("c.com"), now).is_some());
+    }
+
+    #[test]
+    fn test_cache_resize_keeps_most_recently_used() {
+        let mut cache = Cache::new(3);
+        let now = Instant::now();
+        for name in ["a.com", "b.com", "c.com"] {
+            cache.insert(key(name), vec![answer(name, 60)], now);
+        }
+        assert!(cache.get(&key("a.com"), now).is_some());
+
+        cache.resize(1);
+        assert_eq!(cache.len(), 1);
+        assert!(cache.get(&key("a.com"), now).is_some());
+        cache.resize(2);
+        cache.insert(key("b.com"), vec![answer("b.com", 60)], now);
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_negative_cache_uses_soa_minimum() {
+        let mut cache = Cache::new(2);
+        let now = Instant::now();
+        let soa = |ttl| {
+            SectionGroup::new(
+                vec!["com".to_owned()],
+                Type::Soa,
+                Class::In,
+                Some((
+                    ttl,
+                    RData::Soa {
+                        mname: vec!["a".to_owned(), "gtld-servers".to_owned(), "net".to_owned()],
+                        rname: vec![
+                            "nstld".to_owned(),
+                            "verisign-grs".to_owned(),
+                            "com".to_owned(),
+                        ],
+                        serial: 1,
+                        refresh: 1800,
+                        retry: 900,
+                        expire: 604800,
+                        minimum: 900,
+                    },
+                )),
+            )
+        };
+        cache.insert_negative(key("typo.com"), ResponseCode::Name, soa(3600), now);
+
+        let (rcode, answers, authority) = cache
+            .get(&key("typo.com"), now + Duration::from_secs(100))
+            .unwrap();
+        assert_eq!(rcode, ResponseCode::Name);
+        assert!(answers.is_empty());
+        assert_eq!(authority, vec![soa(800)]);
+        assert!(cache
+            .get(&key("typo.com"), now + Duration::from_secs(900))
+            .is_none());
+    }
+
+    #[test]
+    fn test_full_cache_purges_expired_before_evicting() {
+        let mut cache = Cache::new(3);
+        let now = Instant::now();
+        cache.insert(key("short.com"), vec![answer("short.com", 10)], now);
+        cache.insert(key("a.com"), vec![answer("a.com", 60)], now);
+        cache.insert(key("b.com"), vec![answer("b.com", 60)], now);
+
+        let later = now + Duration::from_secs(30);
+        cache.insert(key("c.com"), vec![answer("c.com", 60)], later);
+        assert_eq!(cache.len(), 3);
+        for name in ["a.com", "b.com", "c.com"] {
+            assert!(cache.get(&key(name), later).is_some());
+        }
+
+        cache.purge_expired(now + Duration::from_secs(90));
+        assert!(cache.is_empty());
+        assert!(cache.expiry.is_empty() && cache.lru.is_empty());
+    }
+
+    #[test]
+    fn test_cache_skips_zero_ttl() {
+        let mut cache = Cache::new(2);
+        cache.insert(key("a.com"), vec![answer("a.com", 0)], Instant::now());
+        cache.insert(key("b.com"), Vec::new(), Instant::now());
+        assert!(cache.is_empty());
+    }
+}
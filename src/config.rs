@@ -0,0 +1,701 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    acl::{Acl, Network},
+    cache::DEFAULT_CACHE_SIZE,
+    converter::transcribe::{DEFAULT_UPSTREAM_RETRIES, DEFAULT_UPSTREAM_TIMEOUT},
+    dnstap,
+    error::ConfigError,
+    log::LogConfig,
+    upstream::Strategy,
+};
+
+pub const DEFAULT_LISTEN: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 2053);
+pub const DEFAULT_UPSTREAM_SOCKETS: usize = 8;
+
+/// Everything the server binary can be told, from a config file, the
+/// command line or both. A config file looks like:
+///
+/// ```toml
+/// listen = ["127.0.0.1:53", "[::1]:53"]
+/// workers = 4
+/// zones = ["example.com.zone"]   # relative to the config file
+///
+/// [upstream]
+/// resolvers = ["9.9.9.9:53", "1.1.1.1:53"]
+/// strategy = "lowest-latency"
+/// timeout_ms = 2000
+/// retries = 2
+/// sockets = 8
+/// # recursive = true
+/// # root_hints = ["198.41.0.4:53"]
+///
+/// [[forward]]
+/// suffix = "corp.example"
+/// resolvers = ["10.0.0.53:53"]
+///
+/// [cache]
+/// size = 4096
+///
+/// [acl]
+/// allow = ["127.0.0.0/8", "::1", "10.0.0.0/8"]
+/// deny = ["10.66.0.0/16"]
+///
+/// [log]
+/// level = "info"
+/// format = "json"
+/// sample = 10                    # log one query in ten
+///
+/// [admin]
+/// listen = "127.0.0.1:2055"      # send "reload" here; SIGHUP terminates the server
+///
+/// [metrics]
+/// listen = "127.0.0.1:9153"      # serves GET /metrics
+///
+/// [dnstap]
+/// socket = "/run/dnstap.sock"    # or file = "queries.dnstap"
+/// ```
+///
+/// Only the part of TOML this needs is understood: tables, arrays of
+/// tables, and strings, integers, booleans and arrays as values.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub listen: Vec<SocketAddr>,
+    /// `None` runs one worker per available core.
+    pub workers: Option<usize>,
+    pub resolvers: Vec<SocketAddr>,
+    pub strategy: Strategy,
+    /// Name suffixes whose queries go to their own resolvers.
+    pub forwards: Vec<(Vec<String>, Vec<SocketAddr>)>,
+    pub recursive: bool,
+    pub root_hints: Option<Vec<SocketAddr>>,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub upstream_sockets: usize,
+    pub cache_size: usize,
+    pub zones: Vec<PathBuf>,
+    pub acl: Acl,
+    pub log: LogConfig,
+    /// Where to take admin commands such as `reload`.
+    pub admin: Option<SocketAddr>,
+    /// Where to serve Prometheus metrics over HTTP.
+    pub metrics: Option<SocketAddr>,
+    /// Where to send dnstap messages about the queries and responses we
+    /// see; paths are relative to the config file.
+    pub dnstap: Option<dnstap::Output>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen: vec![DEFAULT_LISTEN],
+            workers: None,
+            resolvers: Vec::new(),
+            strategy: Strategy::default(),
+            forwards: Vec::new(),
+            recursive: false,
+            root_hints: None,
+            timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            retries: DEFAULT_UPSTREAM_RETRIES,
+            upstream_sockets: DEFAULT_UPSTREAM_SOCKETS,
+            cache_size: DEFAULT_CACHE_SIZE,
+            zones: Vec::new(),
+            acl: Acl::default(),
+            log: LogConfig::default(),
+            admin: None,
+            metrics: None,
+            dnstap: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        Self::parse(&text, path)
+    }
+
+    /// Parses config file text; `path` is only used in error messages and to
+    /// find zone files.
+    pub fn parse(text: &str, path: &Path) -> Result<Self, ConfigError> {
+        let syntax = |line: usize, reason: String| ConfigError::Syntax {
+            path: path.display().to_string(),
+            line,
+            reason,
+        };
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let mut config = Config::default();
+        let mut acl = (Vec::new(), Vec::new());
+        for table in parse_tables(text).map_err(|(line, reason)| syntax(line, reason))? {
+            let mut suffix = None;
+            let mut forward_to = None;
+            for (key, value, line) in table.entries {
+                let invalid = |reason: String| syntax(line, format!("{key}: {reason}"));
+                match (table.name.as_str(), key.as_str()) {
+                    ("", "listen") => config.listen = parse_each(&value).map_err(invalid)?,
+                    ("", "workers") => config.workers = Some(positive(&value).map_err(invalid)?),
+                    ("", "zones") => {
+                        config.zones = strings(&value)
+                            .map_err(invalid)?
+                            .into_iter()
+                            .map(|zone| dir.join(zone))
+                            .collect()
+                    }
+                    ("upstream", "resolvers") => {
+                        config.resolvers = parse_each(&value).map_err(invalid)?
+                    }
+                    ("upstream", "strategy") => {
+                        config.strategy = parse_one(&value).map_err(invalid)?
+                    }
+                    ("upstream", "timeout_ms") => {
+                        config.timeout =
+                            Duration::from_millis(positive(&value).map_err(invalid)? as u64)
+                    }
+                    ("upstream", "retries") => {
+                        config.retries = integer(&value)
+                            .and_then(|retries| {
+                                u32::try_from(retries).map_err(|_| "out of range".to_owned())
+                            })
+                            .map_err(invalid)?
+                    }
+                    ("upstream", "sockets") => {
+                        config.upstream_sockets = positive(&value).map_err(invalid)?
+                    }
+                    ("upstream", "recursive") => {
+                        config.recursive = boolean(&value).map_err(invalid)?
+                    }
+                    ("upstream", "root_hints") => {
+                        config.root_hints = Some(parse_each(&value).map_err(invalid)?)
+                    }
+                    ("forward", "suffix") => {
+                        suffix = Some(domain(string(&value).map_err(invalid)?))
+                    }
+                    ("forward", "resolvers") => {
+                        forward_to = Some(parse_each(&value).map_err(invalid)?)
+                    }
+                    ("cache", "size") => config.cache_size = positive(&value).map_err(invalid)?,
+                    ("acl", "allow") => acl.0 = parse_each::<Network>(&value).map_err(invalid)?,
+                    ("acl", "deny") => acl.1 = parse_each::<Network>(&value).map_err(invalid)?,
+                    ("log", "level") => config.log.level = parse_one(&value).map_err(invalid)?,
+                    ("log", "format") => config.log.format = parse_one(&value).map_err(invalid)?,
+                    ("log", "sample") => {
+                        config.log.sample = positive(&value).map_err(invalid)? as u64
+                    }
+                    ("admin", "listen") => config.admin = Some(parse_one(&value).map_err(invalid)?),
+                    ("metrics", "listen") => {
+                        config.metrics = Some(parse_one(&value).map_err(invalid)?)
+                    }
+                    ("dnstap", "socket" | "file") if config.dnstap.is_some() => {
+                        return Err(invalid("only one of socket and file can be set".to_owned()))
+                    }
+                    ("dnstap", "socket") => {
+                        let path = dir.join(string(&value).map_err(invalid)?);
+                        config.dnstap = Some(dnstap::Output::Socket(path))
+                    }
+                    ("dnstap", "file") => {
+                        let path = dir.join(string(&value).map_err(invalid)?);
+                        config.dnstap = Some(dnstap::Output::File(path))
+                    }
+                    ("", _) => return Err(syntax(line, format!("unknown key {key:?}"))),
+                    (name, _) => {
+                        return Err(syntax(line, format!("unknown key {key:?} in [{name}]")))
+                    }
+                }
+            }
+            match (table.name.as_str(), table.repeated) {
+                ("forward", true) => match (suffix, forward_to) {
+                    (Some(suffix), Some(addrs)) if !addrs.is_empty() => {
+                        config.forwards.push((suffix, addrs))
+                    }
+                    _ => {
+                        return Err(syntax(
+                            table.line,
+                            "[[forward]] needs a suffix and at least one resolver".to_owned(),
+                        ))
+                    }
+                },
+                (
+                    "" | "upstream" | "cache" | "acl" | "log" | "admin" | "metrics" | "dnstap",
+                    false,
+                ) => {}
+                (name, _) => return Err(syntax(table.line, format!("unknown table {name:?}"))),
+            }
+        }
+        config.acl = Acl::new(acl.0, acl.1);
+        Ok(config)
+    }
+
+    /// Checks that the settings make sense together.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: &str| Err(ConfigError::Invalid(reason.to_owned()));
+        if self.listen.is_empty() {
+            return invalid("at least one listen address is needed");
+        }
+        if !self.forwards.is_empty() && self.resolvers.is_empty() {
+            return invalid("forwarding by suffix needs default resolvers too");
+        }
+        if self.recursive && !self.resolvers.is_empty() {
+            return invalid("resolvers can't be used in recursive mode");
+        }
+        if self.root_hints.is_some() && !self.recursive {
+            return invalid("root hints are only used in recursive mode");
+        }
+        if self.resolvers.is_empty() && !self.recursive && self.zones.is_empty() {
+            return invalid("nothing to answer from; give resolvers, recursive mode or zones");
+        }
+        Ok(())
+    }
+}
+
+/// Labels of a dotted domain name.
+pub fn domain(name: &str) -> Vec<String> {
+    name.split('.')
+        .filter(|label| !label.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// The `key = value` lines under one table header, with their line numbers.
+/// The top-level table has an empty name.
+#[derive(Debug)]
+struct Table {
+    name: String,
+    /// Declared as `[[name]]`.
+    repeated: bool,
+    line: usize,
+    entries: Vec<(String, Value, usize)>,
+}
+
+fn parse_tables(text: &str) -> Result<Vec<Table>, (usize, String)> {
+    let mut tables = vec![Table {
+        name: String::new(),
+        repeated: false,
+        line: 0,
+        entries: Vec::new(),
+    }];
+    let mut lines = text.lines().enumerate().map(|(idx, line)| (idx + 1, line));
+    while let Some((line_no, line)) = lines.next() {
+        let mut line = strip_comment(line).trim().to_owned();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') {
+            let (name, repeated) = match line.strip_prefix("[[").and_then(|l| l.strip_suffix("]]"))
+            {
+                Some(name) => (name, true),
+                None => match line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                    Some(name) => (name, false),
+                    None => return Err((line_no, "malformed table header".to_owned())),
+                },
+            };
+            let name = bare_key(name.trim()).map_err(|reason| (line_no, reason))?;
+            if !repeated && tables.iter().any(|table| table.name == name) {
+                return Err((line_no, format!("table [{name}] defined twice")));
+            }
+            tables.push(Table {
+                name: name.to_owned(),
+                repeated,
+                line: line_no,
+                entries: Vec::new(),
+            });
+            continue;
+        }
+        // arrays may run over several lines
+        while bracket_depth(&line) > 0 {
+            match lines.next() {
+                Some((_, more)) => {
+                    line.push(' ');
+                    line.push_str(strip_comment(more));
+                }
+                None => return Err((line_no, "unterminated array".to_owned())),
+            }
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            return Err((line_no, "expected `key = value`".to_owned()));
+        };
+        let key = bare_key(key.trim()).map_err(|reason| (line_no, reason))?;
+        let value = parse_value(value).map_err(|reason| (line_no, format!("{key}: {reason}")))?;
+        let table = tables
+            .last_mut()
+            .expect("there's always the top-level table");
+        if table.entries.iter().any(|(seen, _, _)| seen == key) {
+            return Err((line_no, format!("{key:?} given twice")));
+        }
+        table.entries.push((key.to_owned(), value, line_no));
+    }
+    Ok(tables)
+}
+
+fn bare_key(key: &str) -> Result<&str, String> {
+    match !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+    {
+        true => Ok(key),
+        false => Err(format!("invalid key {key:?}")),
+    }
+}
+
+/// The characters of `line` that aren't inside a string, with their offsets.
+fn unquoted(line: &str) -> Vec<(usize, char)> {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut chars = Vec::new();
+    for (idx, c) in line.char_indices() {
+        match quote {
+            Some('"') if escaped => escaped = false,
+            Some('"') if c == '\\' => escaped = true,
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => chars.push((idx, c)),
+        }
+    }
+    chars
+}
+
+fn strip_comment(line: &str) -> &str {
+    match unquoted(line).into_iter().find(|(_, c)| *c == '#') {
+        Some((idx, _)) => &line[..idx],
+        None => line,
+    }
+}
+
+fn bracket_depth(line: &str) -> i32 {
+    // only the value side counts; the key can't contain brackets
+    let value_start = line.find('=').map_or(0, |idx| idx + 1);
+    unquoted(&line[value_start..])
+        .into_iter()
+        .map(|(_, c)| match c {
+            '[' => 1,
+            ']' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+fn parse_value(text: &str) -> Result<Value, String> {
+    let mut rest = text;
+    let value = next_value(&mut rest)?;
+    match rest.trim() {
+        "" => Ok(value),
+        extra => Err(format!("unexpected {extra:?} after the value")),
+    }
+}
+
+fn next_value(rest: &mut &str) -> Result<Value, String> {
+    *rest = rest.trim_start();
+    if let Some(tail) = rest.strip_prefix('[') {
+        *rest = tail;
+        let mut items = Vec::new();
+        loop {
+            *rest = rest.trim_start();
+            if let Some(tail) = rest.strip_prefix(']') {
+                *rest = tail;
+                return Ok(Value::Array(items));
+            }
+            items.push(next_value(rest)?);
+            *rest = rest.trim_start();
+            match rest.strip_prefix(',') {
+                Some(tail) => *rest = tail,
+                None if rest.starts_with(']') => {}
+                None => return Err("expected `,` or `]` in array".to_owned()),
+            }
+        }
+    }
+    if let Some(tail) = rest.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = tail.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            match c {
+                '"' => {
+                    *rest = &tail[idx + 1..];
+                    return Ok(Value::String(value));
+                }
+                '\\' => match chars.next().map(|(_, c)| c) {
+                    Some('"') => value.push('"'),
+                    Some('\\') => value.push('\\'),
+                    Some('n') => value.push('\n'),
+                    Some('t') => value.push('\t'),
+                    other => return Err(format!("unsupported escape {other:?}")),
+                },
+                c => value.push(c),
+            }
+        }
+        return Err("unterminated string".to_owned());
+    }
+    if let Some(tail) = rest.strip_prefix('\'') {
+        let end = tail.find('\'').ok_or("unterminated string")?;
+        *rest = &tail[end + 1..];
+        return Ok(Value::String(tail[..end].to_owned()));
+    }
+    let end = rest
+        .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+        .unwrap_or(rest.len());
+    let (token, tail) = rest.split_at(end);
+    *rest = tail;
+    match token {
+        "" => Err("expected a value".to_owned()),
+        "true" => Ok(Value::Boolean(true)),
+        "false" => Ok(Value::Boolean(false)),
+        _ => token
+            .replace('_', "")
+            .parse()
+            .map(Value::Integer)
+            .map_err(|_| format!("invalid value {token:?}")),
+    }
+}
+
+fn string(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::String(s) => Ok(s),
+        _ => Err("expected a string".to_owned()),
+    }
+}
+
+/// A list of strings; a lone string is a list of one.
+fn strings(value: &Value) -> Result<Vec<&str>, String> {
+    match value {
+        Value::Array(items) => items.iter().map(string).collect(),
+        _ => Ok(vec![string(value).map_err(|_| {
+            "expected a string or an array of strings".to_owned()
+        })?]),
+    }
+}
+
+fn integer(value: &Value) -> Result<i64, String> {
+    match value {
+        Value::Integer(n) => Ok(*n),
+        _ => Err("expected an integer".to_owned()),
+    }
+}
+
+fn positive(value: &Value) -> Result<usize, String> {
+    integer(value).and_then(|n| {
+        usize::try_from(n)
+            .ok()
+            .filter(|n| *n > 0)
+            .ok_or_else(|| format!("expected a positive integer, not {n}"))
+    })
+}
+
+fn boolean(value: &Value) -> Result<bool, String> {
+    match value {
+        Value::Boolean(b) => Ok(*b),
+        _ => Err("expected true or false".to_owned()),
+    }
+}
+
+fn parse_one<T: FromStr>(value: &Value) -> Result<T, String> {
+    let s = string(value)?;
+    s.parse().map_err(|_| format!("invalid value {s:?}"))
+}
+
+fn parse_each<T: FromStr>(value: &Value) -> Result<Vec<T>, String> {
+    strings(value)?
+        .into_iter()
+        .map(|s| s.parse().map_err(|_| format!("invalid value {s:?}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, path::Path, time::Duration};
+
+    use crate::{
+        dnstap,
+        error::ConfigError,
+        log::{LogConfig, LogFormat, LogLevel},
+        upstream::Strategy,
+    };
+
+    use super::{Config, DEFAULT_LISTEN};
+
+    fn parse(text: &str) -> Result<Config, ConfigError> {
+        Config::parse(text, Path::new("/etc/dns/server.toml"))
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_full_config() {
+        let config = parse(
+            r#"
+            # listen on both families
+            listen = [
+                "127.0.0.1:5300",  # v4
+                "[::1]:5300",
+            ]
+            workers = 2
+            zones = ["example.com.zone", "/var/zones/other.zone"]
+
+            [upstream]
+            resolvers = ["9.9.9.9:53", "1.1.1.1:53"]
+            strategy = "round-robin"
+            timeout_ms = 1_500
+            retries = 3
+
+            [[forward]]
+            suffix = "corp.example."
+            resolvers = "10.0.0.53:53"
+
+            [[forward]]
+            suffix = 'lab.corp.example'
+            resolvers = ["10.1.0.53:53"]
+
+            [cache]
+            size = 4096
+
+            [acl]
+            allow = ["127.0.0.0/8", "::1"]
+            deny = ["127.0.0.2"]
+
+            [log]
+            level = "debug"
+            format = "json"
+            sample = 100
+
+            [admin]
+            listen = "127.0.0.1:2055"
+
+            [metrics]
+            listen = "127.0.0.1:9153"
+
+            [dnstap]
+            socket = "dnstap.sock"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen,
+            vec![addr("127.0.0.1:5300"), addr("[::1]:5300")]
+        );
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(
+            config.zones,
+            vec![
+                Path::new("/etc/dns/example.com.zone"),
+                Path::new("/var/zones/other.zone")
+            ]
+        );
+        assert_eq!(
+            config.resolvers,
+            vec![addr("9.9.9.9:53"), addr("1.1.1.1:53")]
+        );
+        assert_eq!(config.strategy, Strategy::RoundRobin);
+        assert_eq!(config.timeout, Duration::from_millis(1500));
+        assert_eq!(config.retries, 3);
+        assert_eq!(
+            config.forwards,
+            vec![
+                (
+                    vec!["corp".to_owned(), "example".to_owned()],
+                    vec![addr("10.0.0.53:53")]
+                ),
+                (
+                    vec!["lab".to_owned(), "corp".to_owned(), "example".to_owned()],
+                    vec![addr("10.1.0.53:53")]
+                ),
+            ]
+        );
+        assert_eq!(config.cache_size, 4096);
+        assert!(config.acl.permits("127.0.0.1".parse().unwrap()));
+        assert!(!config.acl.permits("127.0.0.2".parse().unwrap()));
+        assert!(!config.acl.permits("192.0.2.1".parse().unwrap()));
+        assert_eq!(
+            config.log,
+            LogConfig {
+                level: LogLevel::Debug,
+                format: LogFormat::Json,
+                sample: 100
+            }
+        );
+        assert_eq!(config.admin, Some(addr("127.0.0.1:2055")));
+        assert_eq!(config.metrics, Some(addr("127.0.0.1:9153")));
+        assert_eq!(
+            config.dnstap,
+            Some(dnstap::Output::Socket("/etc/dns/dnstap.sock".into()))
+        );
+        assert!(config.validate().is_ok());
+
+        let config = parse("").unwrap();
+        assert_eq!(config.listen, vec![DEFAULT_LISTEN]);
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        let error = |text: &str| parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("[upstream]\nresolvers = [\"9.9.9.9\"]"),
+            "/etc/dns/server.toml:2: resolvers: invalid value \"9.9.9.9\""
+        );
+        assert_eq!(
+            error("\n\nworkers = 0"),
+            "/etc/dns/server.toml:3: workers: expected a positive integer, not 0"
+        );
+        assert_eq!(
+            error("[cache]\nsize = 10\nttl = 5"),
+            "/etc/dns/server.toml:3: unknown key \"ttl\" in [cache]"
+        );
+        assert_eq!(
+            error("[caches]"),
+            "/etc/dns/server.toml:1: unknown table \"caches\""
+        );
+        assert_eq!(
+            error("[[forward]]\nsuffix = \"corp\""),
+            "/etc/dns/server.toml:1: [[forward]] needs a suffix and at least one resolver"
+        );
+        assert_eq!(
+            error("listen = [\"127.0.0.1:53\",\n"),
+            "/etc/dns/server.toml:1: unterminated array"
+        );
+        assert_eq!(
+            error("[log]\nlevel = \"loud\""),
+            "/etc/dns/server.toml:2: level: invalid value \"loud\""
+        );
+        assert_eq!(
+            error("workers = 2 3"),
+            "/etc/dns/server.toml:1: workers: unexpected \"3\" after the value"
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_conflicting_settings() {
+        let invalid = |text: &str| parse(text).unwrap().validate().is_err();
+        assert!(invalid(
+            "[upstream]\nresolvers = [\"9.9.9.9:53\"]\nrecursive = true"
+        ));
+        assert!(invalid(
+            "zones = [\"a.zone\"]\n[[forward]]\nsuffix = \"corp\"\nresolvers = [\"10.0.0.1:53\"]"
+        ));
+        assert!(invalid("listen = []\nzones = [\"a.zone\"]"));
+        assert!(!invalid("[upstream]\nrecursive = true"));
+        // upstream sockets are bound for each address family in use
+        assert!(!invalid(
+            "[upstream]\nresolvers = [\"9.9.9.9:53\", \"[2620:fe::fe]:53\"]"
+        ));
+    }
+}
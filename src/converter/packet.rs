@@ -1,43 +1,126 @@
-use std::net::SocketAddr;
+use std::{net::SocketAddr, sync::mpsc::Sender, time::Instant};
 
 use crate::{
+    buffer::MAX_UDP_PACKET_SIZE,
+    edns::{Edns, EDNS_UDP_PAYLOAD_SIZE},
+    error::ParseError,
     header::{
         AuthAnswer, DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, OpCode,
         QueryResponse, RecursionAvailablity, RecursionDesired, ResponseCode, SectionCount,
         Truncation,
     },
     section::{Section, SectionGroup},
+    writer::MessageWriter,
 };
 
 pub type PendingHeaderPacket = (SocketAddr, u16, OpCode, RecursionDesired);
 
+/// Answer, authority and additional records of a response, in that order.
+pub type ResponseSections = (Vec<SectionGroup>, Vec<SectionGroup>, Vec<SectionGroup>);
+
 #[derive(Debug, Clone)]
 pub struct PendingPacket {
     addr_hdr: PendingHeaderPacket,
     rcode: ResponseCode,
+    /// The upper eight bits of an extended RCODE an upstream answered with.
+    extended_rcode: u8,
     capacity: usize,
+    answered: usize,
     qsection: Section,
     a_section_groups: Vec<SectionGroup>,
+    ns_section_groups: Vec<SectionGroup>,
+    ar_section_groups: Vec<SectionGroup>,
+    edns: Option<Edns>,
+    aa: AuthAnswer,
+    ra: RecursionAvailablity,
+    listener: usize,
+    received: Instant,
+    upstream: Option<SocketAddr>,
+    cache_hit: bool,
+    /// Where the finished response goes when it isn't sent over UDP.
+    responder: Option<Sender<PendingPacket>>,
 }
 
 #[derive(Debug, Clone)]
 pub struct UdpPacket {
     raw: Vec<u8>,
+    listener: usize,
 }
 
 impl UdpPacket {
+    /// Serializes a response of at most `max_size` bytes. Answer or authority
+    /// records that don't fit are dropped and the TC bit is set so the client
+    /// can retry over TCP; additional records are just left out. Fails if a
+    /// record can't be written, e.g. one with a name that's too long.
     pub fn new(
         addr_hdr: (SocketAddr, DnsHeader),
-        qsection: Section,
-        a_section_groups: Vec<SectionGroup>,
-    ) -> (Self, SocketAddr) {
+        qsection: &Section,
+        sections: ResponseSections,
+        edns: Option<Edns>,
+        max_size: usize,
+    ) -> Result<(Self, SocketAddr), ParseError> {
         let (addr, hdr) = (addr_hdr.0, addr_hdr.1);
-        let mut raw = <[u8; 12]>::from(hdr).to_vec();
-        raw.extend(Vec::<u8>::from(qsection));
-        a_section_groups.into_iter().for_each(|group| {
-            raw.extend(Vec::<u8>::try_from(group).expect("Conversion failed for some reason..."));
-        });
-        (UdpPacket { raw }, addr)
+        let mut writer = MessageWriter::new();
+        writer.write_header(hdr.clone());
+        for group in &qsection.groups {
+            writer.write_section_group(group)?;
+        }
+        let opt = edns.map(SectionGroup::from);
+        let reserved = match &opt {
+            Some(opt) => Vec::<u8>::try_from(opt.clone())?.len(),
+            None => 0,
+        };
+        let mut truncated = false;
+        let mut counts = [0u16; 3];
+        let (answers, authority, additional) = sections;
+        'sections: for (idx, groups) in [answers, authority, additional].iter().enumerate() {
+            for group in groups {
+                let mark = writer.len();
+                writer.write_section_group(group)?;
+                if writer.len() + reserved > max_size {
+                    writer.truncate(mark);
+                    truncated = idx < 2;
+                    break 'sections;
+                }
+                counts[idx] += 1;
+            }
+        }
+        if let Some(opt) = &opt {
+            writer.write_section_group(opt)?;
+        }
+        let fh = hdr.header_first_half();
+        writer.rewrite_header(DnsHeader::new(
+            hdr.txid(),
+            HeaderSecondRowFirstHalf::new(
+                fh.qr().clone(),
+                fh.opcode().clone(),
+                fh.aa().clone(),
+                match truncated {
+                    true => Truncation::Truncated,
+                    false => fh.tc().clone(),
+                },
+                fh.rd().clone(),
+            ),
+            hdr.header_second_half().clone(),
+            SectionCount::new(
+                hdr.counts().qdcount(),
+                counts[0],
+                counts[1],
+                counts[2] + u16::from(opt.is_some()),
+            ),
+        ));
+        Ok((
+            UdpPacket {
+                raw: writer.into_bytes(),
+                listener: 0,
+            },
+            addr,
+        ))
+    }
+
+    /// Index of the listening socket the response goes out of.
+    pub fn listener(&self) -> usize {
+        self.listener
     }
 }
 
@@ -48,7 +131,12 @@ impl From<UdpPacket> for Vec<u8> {
 }
 
 impl PendingPacket {
-    pub fn new(addr_hdr: PendingHeaderPacket, capacity: usize, qsection: Section) -> Self {
+    pub fn new(
+        addr_hdr: PendingHeaderPacket,
+        capacity: usize,
+        qsection: Section,
+        edns: Option<Edns>,
+    ) -> Self {
         let rcode = match addr_hdr.2 {
             OpCode::Query => ResponseCode::None,
             _ => ResponseCode::NotImplemented,
@@ -56,39 +144,383 @@ impl PendingPacket {
         PendingPacket {
             addr_hdr,
             rcode,
+            extended_rcode: 0,
             capacity,
+            answered: 0,
             qsection,
             a_section_groups: Vec::new(),
+            ns_section_groups: Vec::new(),
+            ar_section_groups: Vec::new(),
+            edns,
+            aa: AuthAnswer::NotAuthoritative,
+            ra: RecursionAvailablity::NoRecursionAvailable,
+            listener: 0,
+            received: Instant::now(),
+            upstream: None,
+            cache_hit: false,
+            responder: None,
         }
     }
 
-    pub fn into_packet(self) -> (UdpPacket, SocketAddr) {
+    pub fn set_authoritative(&mut self, aa: AuthAnswer) {
+        self.aa = aa;
+    }
+
+    pub fn set_recursion_available(&mut self, ra: RecursionAvailablity) {
+        self.ra = ra;
+    }
+
+    /// Remembers which of our listening sockets the query came in on, so the
+    /// response goes back out of the same one.
+    pub fn set_listener(&mut self, listener: usize) {
+        self.listener = listener;
+    }
+
+    /// Hands the finished response to `responder` instead of sending it out
+    /// of a listening socket, e.g. for a query that came in over TCP.
+    pub fn set_responder(&mut self, responder: Sender<PendingPacket>) {
+        self.responder = Some(responder);
+    }
+
+    pub fn take_responder(&mut self) -> Option<Sender<PendingPacket>> {
+        self.responder.take()
+    }
+
+    /// Notes the upstream that answered, or was last asked.
+    pub fn set_upstream(&mut self, upstream: SocketAddr) {
+        self.upstream = Some(upstream);
+    }
+
+    /// Notes that a question was answered from the cache.
+    pub fn set_cache_hit(&mut self) {
+        self.cache_hit = true;
+    }
+
+    /// When the query came in.
+    pub fn received(&self) -> Instant {
+        self.received
+    }
+
+    pub fn upstream(&self) -> Option<SocketAddr> {
+        self.upstream
+    }
+
+    pub fn cache_hit(&self) -> bool {
+        self.cache_hit
+    }
+
+    pub fn edns(&self) -> Option<&Edns> {
+        self.edns.as_ref()
+    }
+
+    /// Builds the UDP response, sized to what the client advertised.
+    pub fn into_packet(self) -> Result<(UdpPacket, SocketAddr), ParseError> {
+        let max_size = self
+            .edns
+            .as_ref()
+            .map_or(MAX_UDP_PACKET_SIZE, Edns::max_response_size);
+        self.into_packet_with_max_size(max_size)
+    }
+
+    /// Builds the response for a transport with its own size limit, e.g. TCP.
+    /// If the records we have for it can't be written, the client gets a
+    /// SERVFAIL without them instead.
+    pub fn into_packet_with_max_size(
+        self,
+        max_size: usize,
+    ) -> Result<(UdpPacket, SocketAddr), ParseError> {
         let (socket_addr, txid, opcode, rd) = self.addr_hdr;
-        let rcode = self.rcode;
+        // an extended RCODE can't be told to a client without EDNS
+        let rcode = match (&self.edns, self.extended_rcode) {
+            (None, 1..) => ResponseCode::ServerFailure,
+            _ => self.rcode,
+        };
         let qsection = self.qsection;
-        let a_section_groups = self.a_section_groups;
-        let hdr_sr_fh = HeaderSecondRowFirstHalf::new(
-            QueryResponse::Response,
-            opcode,
-            AuthAnswer::NotAuthoritative,
-            Truncation::NotTruncated,
-            rd,
+        let sections = (
+            self.a_section_groups,
+            self.ns_section_groups,
+            self.ar_section_groups,
         );
-        let hdr_sr_sh =
-            HeaderSecondRowSecondHalf::new(RecursionAvailablity::NoRecursionAvailable, 0, rcode)
+        // answer EDNS with EDNS, advertising our own payload size
+        let edns = |extended_rcode| {
+            self.edns.as_ref().map(|edns| {
+                Edns::new(
+                    EDNS_UDP_PAYLOAD_SIZE,
+                    extended_rcode,
+                    0,
+                    edns.dnssec_ok(),
+                    Vec::new(),
+                )
+            })
+        };
+        let hdr = |rcode| {
+            let hdr_sr_fh = HeaderSecondRowFirstHalf::new(
+                QueryResponse::Response,
+                opcode.clone(),
+                self.aa.clone(),
+                Truncation::NotTruncated,
+                rd.clone(),
+            );
+            let hdr_sr_sh = HeaderSecondRowSecondHalf::new(self.ra.clone(), 0, rcode)
                 .expect("Should work anyways");
-        let counts = SectionCount::new(
-            qsection.groups.len() as u16,
-            a_section_groups.len() as u16,
-            0,
-            0,
+            // the real counts are filled in once we know what fits
+            let counts = SectionCount::new(qsection.groups.len() as u16, 0, 0, 0);
+            DnsHeader::new(txid, hdr_sr_fh, hdr_sr_sh, counts)
+        };
+        let (mut pkt, socket_addr) = UdpPacket::new(
+            (socket_addr, hdr(rcode)),
+            &qsection,
+            sections,
+            edns(self.extended_rcode),
+            max_size,
+        )
+        .or_else(|err| {
+            crate::error!("Error writing response; {err}");
+            UdpPacket::new(
+                (socket_addr, hdr(ResponseCode::ServerFailure)),
+                &qsection,
+                (Vec::new(), Vec::new(), Vec::new()),
+                edns(0),
+                max_size,
+            )
+        })?;
+        pkt.listener = self.listener;
+        Ok((pkt, socket_addr))
+    }
+
+    /// Adds the full answer to one of the questions; returns whether every
+    /// question has now been answered. The first error `rcode` among the
+    /// answers becomes the response's RCODE.
+    pub fn insert_answers(
+        &mut self,
+        rcode: ResponseCode,
+        section_groups: Vec<SectionGroup>,
+    ) -> bool {
+        self.insert_sections(rcode, (section_groups, Vec::new(), Vec::new()))
+    }
+
+    /// Like `insert_answers`, but also carries the authority and additional
+    /// records that came with the answer. Authority and additional records
+    /// already given for another question aren't repeated.
+    pub fn insert_sections(&mut self, rcode: ResponseCode, sections: ResponseSections) -> bool {
+        self.insert_extended_sections(rcode, 0, sections)
+    }
+
+    /// Like `insert_sections`, for an answer whose OPT record carried the
+    /// upper bits of an extended RCODE (RFC 6891 section 6.1.3). They go
+    /// back out in the OPT record of our response.
+    pub fn insert_extended_sections(
+        &mut self,
+        rcode: ResponseCode,
+        extended_rcode: u8,
+        sections: ResponseSections,
+    ) -> bool {
+        if self.rcode == ResponseCode::None && self.extended_rcode == 0 {
+            self.rcode = rcode;
+            self.extended_rcode = extended_rcode;
+        }
+        let (answers, authority, additional) = sections;
+        self.a_section_groups.extend(answers);
+        for (groups, merged) in [
+            (authority, &mut self.ns_section_groups),
+            (additional, &mut self.ar_section_groups),
+        ] {
+            for group in groups {
+                if !merged.contains(&group) {
+                    merged.push(group);
+                }
+            }
+        }
+        self.answered += 1;
+        self.is_complete()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.answered == self.capacity
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::{
+        buffer::{UdpBuffer, MAX_UDP_PACKET_SIZE},
+        edns::Edns,
+        header::{
+            AuthAnswer, DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, OpCode,
+            QueryResponse, RecursionAvailablity, RecursionDesired, ResponseCode, SectionCount,
+            Truncation,
+        },
+        section::{Class, RData, Section, SectionGroup, Type},
+    };
+
+    use super::{PendingPacket, UdpPacket};
+
+    #[test]
+    fn test_udp_packet_truncates_to_max_size() {
+        let domain = vec!["example".to_owned(), "com".to_owned()];
+        let hdr = DnsHeader::new(
+            7,
+            HeaderSecondRowFirstHalf::new(
+                QueryResponse::Response,
+                OpCode::Query,
+                AuthAnswer::NotAuthoritative,
+                Truncation::NotTruncated,
+                RecursionDesired::IWantRecursion,
+            ),
+            HeaderSecondRowSecondHalf::new(
+                RecursionAvailablity::NoRecursionAvailable,
+                0,
+                ResponseCode::None,
+            )
+            .unwrap(),
+            SectionCount::new(1, 0, 0, 0),
+        );
+        let qsection = Section::new(
+            vec![SectionGroup::new(domain.clone(), Type::A, Class::In, None)],
+            Vec::new(),
         );
-        let hdr = DnsHeader::new(txid, hdr_sr_fh, hdr_sr_sh, counts);
-        UdpPacket::new((socket_addr, hdr), qsection, a_section_groups)
+        let answers = (0..100)
+            .map(|idx| {
+                SectionGroup::new(
+                    domain.clone(),
+                    Type::A,
+                    Class::In,
+                    Some((60, RData::A(Ipv4Addr::new(10, 0, 0, idx)))),
+                )
+            })
+            .collect::<Vec<SectionGroup>>();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 53));
+        let edns = Edns::new(4096, 0, 0, false, Vec::new());
+
+        let (packet, _) = UdpPacket::new(
+            (addr, hdr.clone()),
+            &qsection,
+            (answers.clone(), Vec::new(), Vec::new()),
+            Some(edns.clone()),
+            MAX_UDP_PACKET_SIZE,
+        )
+        .unwrap();
+        let raw = Vec::<u8>::from(packet);
+        assert!(raw.len() <= MAX_UDP_PACKET_SIZE);
+        let (hdr_actual, [_, ansection, _, arsection]) = UdpBuffer::new(&raw).unpack().unwrap();
+        assert_eq!(hdr_actual.header_first_half().tc(), &Truncation::Truncated);
+        assert_eq!(
+            usize::from(hdr_actual.counts().ancount()),
+            ansection.unwrap().groups.len()
+        );
+        assert_eq!(
+            Edns::from_section(&arsection.unwrap()).unwrap().unwrap(),
+            edns
+        );
+
+        let (packet, _) = UdpPacket::new(
+            (addr, hdr),
+            &qsection,
+            (answers, Vec::new(), Vec::new()),
+            Some(edns),
+            4096,
+        )
+        .unwrap();
+        let (hdr_actual, _) = UdpBuffer::new(&Vec::<u8>::from(packet)).unpack().unwrap();
+        assert_eq!(
+            hdr_actual.header_first_half().tc(),
+            &Truncation::NotTruncated
+        );
+        assert_eq!(hdr_actual.counts().ancount(), 100);
+    }
+
+    #[test]
+    fn test_pending_packet_merges_all_sections() {
+        let group = |name: &str, group_type: Type, rdata: RData| {
+            SectionGroup::new(
+                vec![name.to_owned(), "com".to_owned()],
+                group_type,
+                Class::In,
+                Some((60, rdata)),
+            )
+        };
+        let ns = group("example", Type::Ns, RData::Ns(vec!["ns".to_owned()]));
+        let glue = group("ns", Type::A, RData::A(Ipv4Addr::new(192, 0, 2, 1)));
+        let qsection = Section::new(
+            vec![
+                SectionGroup::new(vec!["a".to_owned()], Type::A, Class::In, None),
+                SectionGroup::new(vec!["b".to_owned()], Type::A, Class::In, None),
+            ],
+            Vec::new(),
+        );
+        let mut pending = PendingPacket::new(
+            (
+                SocketAddr::from(([127, 0, 0, 1], 53)),
+                7,
+                OpCode::Query,
+                RecursionDesired::IWantRecursion,
+            ),
+            2,
+            qsection,
+            None,
+        );
+        for addr in [1, 2] {
+            let answers = vec![
+                group("a", Type::A, RData::A(Ipv4Addr::new(10, 0, 0, addr))),
+                group("a", Type::A, RData::A(Ipv4Addr::new(10, 0, 1, addr))),
+            ];
+            pending.insert_sections(
+                ResponseCode::None,
+                (answers, vec![ns.clone()], vec![glue.clone()]),
+            );
+        }
+        assert!(pending.is_complete());
+
+        let (packet, _) = pending.into_packet().unwrap();
+        let (hdr, [_, ansection, nssection, arsection]) =
+            UdpBuffer::new(&Vec::<u8>::from(packet)).unpack().unwrap();
+        assert_eq!(hdr.counts().ancount(), 4);
+        assert_eq!(ansection.unwrap().groups.len(), 4);
+        assert_eq!(nssection.unwrap().groups, vec![ns]);
+        assert_eq!(arsection.unwrap().groups, vec![glue]);
     }
 
-    pub fn insert_section_group(&mut self, section_group: SectionGroup) -> bool {
-        self.a_section_groups.push(section_group);
-        self.a_section_groups.len() == self.capacity
+    #[test]
+    fn test_unwritable_answer_becomes_servfail() {
+        let qsection = Section::new(
+            vec![SectionGroup::new(
+                vec!["example".to_owned()],
+                Type::A,
+                Class::In,
+                None,
+            )],
+            Vec::new(),
+        );
+        let mut pending = PendingPacket::new(
+            (
+                SocketAddr::from(([127, 0, 0, 1], 53)),
+                7,
+                OpCode::Query,
+                RecursionDesired::IWantRecursion,
+            ),
+            1,
+            qsection.clone(),
+            None,
+        );
+        // five 63 byte labels make a name over the 255 byte limit
+        let too_long = SectionGroup::new(
+            vec!["a".repeat(63); 5],
+            Type::A,
+            Class::In,
+            Some((60, RData::A(Ipv4Addr::new(192, 0, 2, 1)))),
+        );
+        pending.insert_answers(ResponseCode::None, vec![too_long]);
+
+        let (packet, _) = pending.into_packet().unwrap();
+        let (hdr, [questions, ansection, _, _]) =
+            UdpBuffer::new(&Vec::<u8>::from(packet)).unpack().unwrap();
+        assert_eq!(
+            hdr.header_second_half().rcode(),
+            &ResponseCode::ServerFailure
+        );
+        assert_eq!(questions.unwrap().groups, qsection.groups);
+        assert!(ansection.is_none());
     }
 }
@@ -1,52 +1,467 @@
-use std::{cell::RefCell, collections::HashMap, net::SocketAddr, rc::Rc};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
 
-use crate::section::SectionGroup;
+use crate::{header::ResponseCode, recursive::same_name, section::SectionGroup};
 
-use super::packet::{PendingPacket, UdpPacket};
+use super::packet::{PendingPacket, ResponseSections};
 
+pub const DEFAULT_UPSTREAM_TIMEOUT: Duration = Duration::from_secs(1);
+pub const DEFAULT_UPSTREAM_RETRIES: u32 = 2;
+
+/// Which of our upstream sockets a query goes out on, where it goes and the
+/// question it asks.
+pub type Sent = (usize, SocketAddr, SectionGroup);
+
+/// Index of the upstream socket to send on, destination and message.
+pub type Outgoing = (usize, SocketAddr, Vec<u8>);
+
+/// Every query that timed out with where it was sent, and responses for
+/// clients whose queries ran out of attempts. Timed out queries with
+/// attempts left are still tracked and should be sent again.
+pub type Expired = (Vec<(u16, Sent)>, Vec<PendingPacket>);
+
+/// How long we wait for the upstream before asking again, and how many
+/// times we ask again. The wait doubles with every retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    timeout: Duration,
+    retries: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_UPSTREAM_TIMEOUT, DEFAULT_UPSTREAM_RETRIES)
+    }
+}
+
+impl RetryPolicy {
+    pub fn new(timeout: Duration, retries: u32) -> Self {
+        Self { timeout, retries }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// How long to wait on the `attempt`th try, counting from 0.
+    fn timeout_for(&self, attempt: u32) -> Duration {
+        2u32.checked_pow(attempt)
+            .and_then(|factor| self.timeout.checked_mul(factor))
+            .unwrap_or(Duration::MAX)
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    pending_packet: Arc<Mutex<PendingPacket>>,
+    query: Vec<u8>,
+    sent: Sent,
+    sent_at: Instant,
+    attempt: u32,
+    deadline: Instant,
+}
+
+/// The queries we have in flight upstream, keyed by txid. Shared between
+/// the threads that send queries, read responses and time queries out.
 #[derive(Debug, Default)]
 pub struct Transcriber {
-    txid_to_pending: HashMap<u16, Rc<RefCell<PendingPacket>>>,
-    txid: u16,
+    txid_to_pending: Mutex<HashMap<u16, InFlight>>,
+    /// Signalled when a query is added, as it may be due before the rest.
+    added: Condvar,
+    policy: Mutex<RetryPolicy>,
 }
 
 impl Transcriber {
-    pub fn insert(&mut self, pending_packet: Rc<RefCell<PendingPacket>>) {
-        if self.txid == u16::MAX {
-            self.txid = 0;
+    pub fn new(policy: RetryPolicy) -> Self {
+        Self {
+            policy: Mutex::new(policy),
+            ..Self::default()
+        }
+    }
+
+    pub fn policy(&self) -> RetryPolicy {
+        *self.policy.lock().expect("retry policy lock poisoned")
+    }
+
+    /// Changes how queries are timed out from here on, including retries of
+    /// those already in flight.
+    pub fn set_policy(&self, policy: RetryPolicy) {
+        *self.policy.lock().expect("retry policy lock poisoned") = policy;
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<u16, InFlight>> {
+        self.txid_to_pending
+            .lock()
+            .expect("pending queries lock poisoned")
+    }
+
+    /// Tracks `query` under a random txid no outstanding query is using,
+    /// writing it into the query's header. Returns what to send, or `None`
+    /// if every txid is taken.
+    pub fn insert(
+        &self,
+        pending_packet: Arc<Mutex<PendingPacket>>,
+        mut query: Vec<u8>,
+        sent: Sent,
+        now: Instant,
+    ) -> Option<Outgoing> {
+        let mut txid_to_pending = self.lock();
+        if txid_to_pending.len() > usize::from(u16::MAX) {
+            return None;
+        }
+        let txid = loop {
+            let txid = rand::random::<u16>();
+            if !txid_to_pending.contains_key(&txid) {
+                break txid;
+            }
+        };
+        if let Some(header_txid) = query.get_mut(..2) {
+            header_txid.copy_from_slice(&txid.to_be_bytes());
         }
-        self.txid_to_pending.insert(self.txid, pending_packet);
-        self.txid += 1;
+        let outgoing = (sent.0, sent.1, query.clone());
+        txid_to_pending.insert(
+            txid,
+            InFlight {
+                pending_packet,
+                query,
+                sent,
+                sent_at: now,
+                attempt: 0,
+                deadline: now + self.policy().timeout_for(0),
+            },
+        );
+        self.added.notify_all();
+        Some(outgoing)
     }
 
-    pub fn txid(&self) -> u16 {
-        self.txid
+    pub fn contains(&self, txid: u16) -> bool {
+        self.lock().contains_key(&txid)
     }
 
+    /// Whether a response is the answer to our query `txid`: it has to come
+    /// back to the socket the query left from, from the server it was sent
+    /// to, and repeat the question.
+    pub fn matches(
+        &self,
+        txid: u16,
+        socket: usize,
+        source: SocketAddr,
+        question: Option<&SectionGroup>,
+    ) -> bool {
+        let txid_to_pending = self.lock();
+        let Some(in_flight) = txid_to_pending.get(&txid) else {
+            return false;
+        };
+        let (sent_socket, upstream, asked) = &in_flight.sent;
+        *sent_socket == socket
+            && *upstream == source
+            && question.is_some_and(|question| {
+                same_name(question.domain(), asked.domain())
+                    && question.group_type() == asked.group_type()
+                    && question.class() == asked.class()
+            })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// When the earliest outstanding query times out.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        Self::earliest(&self.lock())
+    }
+
+    fn earliest(txid_to_pending: &HashMap<u16, InFlight>) -> Option<Instant> {
+        txid_to_pending
+            .values()
+            .map(|in_flight| in_flight.deadline)
+            .min()
+    }
+
+    /// Blocks until an outstanding query's deadline has passed.
+    pub fn wait_for_deadline(&self) {
+        let mut txid_to_pending = self.lock();
+        loop {
+            let now = Instant::now();
+            txid_to_pending = match Self::earliest(&txid_to_pending) {
+                Some(deadline) if deadline <= now => return,
+                Some(deadline) => {
+                    self.added
+                        .wait_timeout(txid_to_pending, deadline - now)
+                        .expect("pending queries lock poisoned")
+                        .0
+                }
+                None => self
+                    .added
+                    .wait(txid_to_pending)
+                    .expect("pending queries lock poisoned"),
+            };
+        }
+    }
+
+    /// Goes over the queries whose deadline has passed: ones with attempts
+    /// left get a new deadline, the rest are dropped and answered with
+    /// SERVFAIL.
+    pub fn expire(&self, now: Instant) -> Expired {
+        let policy = self.policy();
+        let mut txid_to_pending = self.lock();
+        let (mut timed_out, mut out_of_attempts) = (Vec::new(), Vec::new());
+        for (txid, in_flight) in txid_to_pending.iter_mut() {
+            if in_flight.deadline > now {
+                continue;
+            }
+            timed_out.push((*txid, in_flight.sent.clone()));
+            if in_flight.attempt < policy.retries {
+                in_flight.attempt += 1;
+                in_flight.deadline = now + policy.timeout_for(in_flight.attempt);
+            } else {
+                out_of_attempts.push(*txid);
+            }
+        }
+        let failed = out_of_attempts
+            .into_iter()
+            .filter_map(|txid| {
+                let in_flight = txid_to_pending.remove(&txid)?;
+                Self::complete(
+                    in_flight.pending_packet,
+                    in_flight.sent.1,
+                    (ResponseCode::ServerFailure, 0),
+                    (Vec::new(), Vec::new(), Vec::new()),
+                )
+            })
+            .collect();
+        (timed_out, failed)
+    }
+
+    /// Points query `txid` at another socket and upstream for its next
+    /// attempt, returning the query to send.
+    pub fn redirect(
+        &self,
+        txid: u16,
+        (socket, upstream): (usize, SocketAddr),
+        now: Instant,
+    ) -> Option<Outgoing> {
+        let mut txid_to_pending = self.lock();
+        let in_flight = txid_to_pending.get_mut(&txid)?;
+        in_flight.sent.0 = socket;
+        in_flight.sent.1 = upstream;
+        in_flight.sent_at = now;
+        Some((socket, upstream, in_flight.query.clone()))
+    }
+
+    /// Time since query `txid` was last sent.
+    pub fn elapsed(&self, txid: u16, now: Instant) -> Option<Duration> {
+        self.lock()
+            .get(&txid)
+            .map(|in_flight| now.saturating_duration_since(in_flight.sent_at))
+    }
+
+    /// Adds the upstream's answer for `txid` to its client response, which
+    /// is returned once every question in it is answered and nobody else is
+    /// still adding to it. Unknown txids are ignored. The RCODE comes with
+    /// the upper bits the answer's OPT record extends it by, 0 without one.
     pub fn receive_and_delete(
-        &mut self,
+        &self,
         txid: u16,
-        section_group: SectionGroup,
-    ) -> Option<(UdpPacket, SocketAddr)> {
-        let pending_packet = self
-            .txid_to_pending
+        (rcode, extended_rcode): (ResponseCode, u8),
+        sections: ResponseSections,
+    ) -> Option<PendingPacket> {
+        let in_flight = self.lock().remove(&txid)?;
+        Self::complete(
+            in_flight.pending_packet,
+            in_flight.sent.1,
+            (rcode, extended_rcode),
+            sections,
+        )
+    }
+
+    /// Stops tracking query `txid`, whose answer is to be finished some other
+    /// way, handing back the response it's for. Pass that to `complete`.
+    pub fn take(&self, txid: u16) -> Option<Arc<Mutex<PendingPacket>>> {
+        self.lock()
             .remove(&txid)
-            .expect("should've been inserted since we're single threaded?");
-        let full = pending_packet
-            .borrow_mut()
-            .insert_section_group(section_group);
-        if full {
-            let opt = Rc::into_inner(pending_packet)
-                .expect("Somehow there was another strong reference?");
-            let pending_packet = opt.into_inner();
-            Some(pending_packet.into_packet())
-        } else {
-            None
+            .map(|in_flight| in_flight.pending_packet)
+    }
+
+    /// Adds an answer to a client response. Every holder of a response
+    /// lets go of it this way (or with `Arc::into_inner`), so whoever lets
+    /// go last gets it back, by then with every question answered.
+    pub fn complete(
+        pending_packet: Arc<Mutex<PendingPacket>>,
+        upstream: SocketAddr,
+        (rcode, extended_rcode): (ResponseCode, u8),
+        sections: ResponseSections,
+    ) -> Option<PendingPacket> {
+        {
+            let mut pending_packet = pending_packet.lock().expect("pending packet lock poisoned");
+            pending_packet.set_upstream(upstream);
+            pending_packet.insert_extended_sections(rcode, extended_rcode, sections);
         }
-        // if pending_packet.get_mut().insert_section_group(section_group) {
-        //     Some(pending_packet.into_inner().into_packet())
-        // } else {
-        //     None
-        // }
+        Arc::into_inner(pending_packet).map(|pending_packet| {
+            pending_packet
+                .into_inner()
+                .expect("pending packet lock poisoned")
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{
+        buffer::UdpBuffer,
+        converter::packet::PendingPacket,
+        header::{OpCode, RecursionDesired, ResponseCode},
+        section::{Class, Section, SectionGroup, Type},
+    };
+
+    use super::{RetryPolicy, Transcriber};
+
+    fn pending(question: &SectionGroup) -> Arc<Mutex<PendingPacket>> {
+        Arc::new(Mutex::new(PendingPacket::new(
+            (
+                SocketAddr::from(([127, 0, 0, 1], 53)),
+                9,
+                OpCode::Query,
+                RecursionDesired::IWantRecursion,
+            ),
+            1,
+            Section::new(vec![question.clone()], Vec::new()),
+            None,
+        )))
+    }
+
+    #[test]
+    fn test_expire_retries_with_backoff_then_fails() {
+        let transcriber = Transcriber::new(RetryPolicy::new(Duration::from_secs(1), 2));
+        let question = SectionGroup::new(vec!["a".to_owned()], Type::A, Class::In, None);
+        let upstream = SocketAddr::from(([192, 0, 2, 1], 53));
+        let now = Instant::now();
+        let (_, _, query) = transcriber
+            .insert(
+                pending(&question),
+                vec![0, 0, 3],
+                (0, upstream, question.clone()),
+                now,
+            )
+            .unwrap();
+        let txid = u16::from_be_bytes([query[0], query[1]]);
+        assert!(transcriber.contains(txid));
+
+        let at = |secs| now + Duration::from_secs(secs);
+        assert!(transcriber.expire(at(0)).0.is_empty());
+        assert_eq!(
+            transcriber.expire(at(1)).0,
+            vec![(txid, (0, upstream, question.clone()))]
+        );
+        // the second retry waits twice as long
+        assert!(transcriber.expire(at(2)).0.is_empty());
+        assert_eq!(transcriber.next_deadline(), Some(at(3)));
+        assert_eq!(transcriber.expire(at(3)).0.len(), 1);
+        let other = SocketAddr::from(([192, 0, 2, 2], 53));
+        assert_eq!(
+            transcriber.redirect(txid, (1, other), at(3)),
+            Some((1, other, query))
+        );
+        assert_eq!(
+            transcriber.elapsed(txid, at(5)),
+            Some(Duration::from_secs(2))
+        );
+        assert!(transcriber.expire(at(6)).1.is_empty());
+
+        let (timed_out, failed) = transcriber.expire(at(7));
+        assert_eq!(timed_out, vec![(txid, (1, other, question))]);
+        let [pending] = &failed[..] else {
+            panic!("expected one SERVFAIL response");
+        };
+        assert_eq!(pending.upstream(), Some(other));
+        let (packet, _) = pending.clone().into_packet().unwrap();
+        let (hdr, _) = UdpBuffer::new(&Vec::<u8>::from(packet)).unpack().unwrap();
+        assert_eq!(hdr.txid(), 9);
+        assert_eq!(
+            hdr.header_second_half().rcode(),
+            &ResponseCode::ServerFailure
+        );
+        assert!(transcriber.is_empty());
+    }
+
+    #[test]
+    fn test_responses_must_match_socket_source_and_question() {
+        let transcriber = Transcriber::default();
+        let question = SectionGroup::new(vec!["a".to_owned()], Type::A, Class::In, None);
+        let upstream = SocketAddr::from(([192, 0, 2, 1], 53));
+        let insert = || {
+            let (_, _, query) = transcriber
+                .insert(
+                    pending(&question),
+                    vec![0; 12],
+                    (3, upstream, question.clone()),
+                    Instant::now(),
+                )
+                .unwrap();
+            u16::from_be_bytes([query[0], query[1]])
+        };
+        let txid = insert();
+        assert_ne!(insert(), txid);
+        assert_eq!(transcriber.len(), 2);
+
+        let upper = SectionGroup::new(vec!["A".to_owned()], Type::A, Class::In, None);
+        assert!(transcriber.matches(txid, 3, upstream, Some(&upper)));
+        assert!(!transcriber.matches(txid, 2, upstream, Some(&question)));
+        let spoofed = SocketAddr::from(([192, 0, 2, 1], 5353));
+        assert!(!transcriber.matches(txid, 3, spoofed, Some(&question)));
+        let other = SectionGroup::new(vec!["a".to_owned()], Type::Aaaa, Class::In, None);
+        assert!(!transcriber.matches(txid, 3, upstream, Some(&other)));
+        assert!(!transcriber.matches(txid, 3, upstream, None));
+        // an unknown txid is ignored rather than a panic
+        let unknown = (0..=u16::MAX)
+            .find(|txid| !transcriber.contains(*txid))
+            .unwrap();
+        let nothing = (Vec::new(), Vec::new(), Vec::new());
+        assert!(transcriber
+            .receive_and_delete(unknown, (ResponseCode::None, 0), nothing)
+            .is_none());
+        assert!(transcriber.contains(txid));
+    }
+
+    #[test]
+    fn test_wait_for_deadline_wakes_for_new_queries() {
+        let transcriber = Arc::new(Transcriber::new(RetryPolicy::new(
+            Duration::from_millis(20),
+            0,
+        )));
+        let waiter = {
+            let transcriber = Arc::clone(&transcriber);
+            thread::spawn(move || transcriber.wait_for_deadline())
+        };
+        thread::sleep(Duration::from_millis(20));
+        let question = SectionGroup::new(vec!["a".to_owned()], Type::A, Class::In, None);
+        let upstream = SocketAddr::from(([192, 0, 2, 1], 53));
+        transcriber.insert(
+            pending(&question),
+            vec![0; 12],
+            (0, upstream, question),
+            Instant::now(),
+        );
+        waiter.join().unwrap();
+        assert_eq!(transcriber.expire(Instant::now()).1.len(), 1);
     }
 }
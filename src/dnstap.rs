@@ -0,0 +1,427 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Read, Write},
+    iter,
+    net::{IpAddr, SocketAddr},
+    os::unix::net::UnixStream,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        OnceLock,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::log::Protocol;
+
+/// The content type dnstap readers expect in the Frame Streams handshake.
+pub const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+/// How many encoded messages may wait on a slow output before new ones are
+/// dropped, so the server never waits on it.
+pub const QUEUE_SIZE: usize = 4096;
+/// How long the reader on a socket has to accept the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long to wait before reconnecting to a socket that went away.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Frame Streams control frame types and fields
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+/// Longest control frame we'll read, as in the fstrm library.
+const MAX_CONTROL_FRAME: usize = 512;
+
+/// Where dnstap messages are written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    /// A Unix socket a collector such as `fstrm_capture` listens on; the
+    /// bidirectional Frame Streams handshake is used.
+    Socket(PathBuf),
+    /// A file, truncated when the server starts.
+    File(PathBuf),
+}
+
+/// The dnstap message types we emit (`Message.Type` in dnstap.proto).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+impl MessageType {
+    fn is_query(self) -> bool {
+        matches!(self, MessageType::ClientQuery | MessageType::ForwarderQuery)
+    }
+}
+
+/// One DNS message passing through the server. The query address is the
+/// side that sent the query, the response address the side answering it;
+/// either may be unknown.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event<'a> {
+    pub kind: MessageType,
+    pub protocol: Protocol,
+    pub query_address: Option<SocketAddr>,
+    pub response_address: Option<SocketAddr>,
+    /// The message as it went over the wire.
+    pub message: &'a [u8],
+}
+
+/// Hands encoded events to a thread that writes them to the output.
+#[derive(Debug)]
+struct Dnstap {
+    tx: SyncSender<Vec<u8>>,
+    dropped: AtomicU64,
+}
+
+static DNSTAP: OnceLock<Dnstap> = OnceLock::new();
+
+/// Starts writing events to `output`. Only the first call does anything;
+/// the output is opened before this returns so mistakes show up at startup.
+///
+/// The output stays open for as long as the process runs. The server has
+/// no orderly shutdown, since it's ended by a signal it doesn't catch, so
+/// the stream isn't ended with a STOP frame then: a reader sees it end
+/// after the last whole frame, as the writer flushes whenever it catches
+/// up. STOP is only written when we give up on an output after an error.
+pub fn start(output: Output) -> io::Result<()> {
+    let writer = open(&output)?;
+    let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+    if DNSTAP
+        .set(Dnstap {
+            tx,
+            dropped: AtomicU64::new(0),
+        })
+        .is_ok()
+    {
+        thread::spawn(move || write_frames(output, writer, rx));
+    }
+    Ok(())
+}
+
+/// Sends the event `event` builds to the dnstap output if there is one;
+/// it's only built if so.
+pub fn tap<'a>(event: impl FnOnce() -> Event<'a>) {
+    if let Some(dnstap) = DNSTAP.get() {
+        dnstap.send(&event());
+    }
+}
+
+impl Dnstap {
+    fn send(&self, event: &Event) {
+        match self.tx.try_send(encode(event, SystemTime::now())) {
+            Ok(()) | Err(TrySendError::Disconnected(_)) => {}
+            Err(TrySendError::Full(_)) => {
+                // warn on the first drop, then ever less often while it lasts
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                if dropped.is_power_of_two() {
+                    crate::warn!(
+                        "Dropping dnstap message, the output is falling behind; {dropped} so far"
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Writes queued messages until the queue closes, then ends the stream;
+/// the queue of the output `start` opens never closes, see there. A socket
+/// is reconnected to if the collector goes away; messages are dropped while
+/// it's gone.
+fn write_frames(output: Output, writer: Box<dyn Write + Send>, rx: Receiver<Vec<u8>>) {
+    let mut writer = Some(writer);
+    let mut last_attempt = Instant::now();
+    while let Ok(payload) = rx.recv() {
+        if writer.is_none()
+            && matches!(output, Output::Socket(_))
+            && last_attempt.elapsed() >= RECONNECT_DELAY
+        {
+            last_attempt = Instant::now();
+            writer = open(&output)
+                .map_err(|err| crate::warn!("Error reconnecting to dnstap socket; {err}"))
+                .ok();
+        }
+        let Some(out) = writer.as_mut() else {
+            continue;
+        };
+        // flush once we've caught up, so a reader never waits long
+        let written = iter::once(payload)
+            .chain(rx.try_iter())
+            .try_for_each(|payload| write_data_frame(out, &payload))
+            .and_then(|()| out.flush());
+        if let Err(err) = written {
+            crate::error!("Error writing dnstap output; {err}");
+            // the collector may still be reading, so say we're done with it
+            if let Some(out) = writer.take() {
+                let _ = finish(out);
+            }
+            last_attempt = Instant::now();
+        }
+    }
+    if let Some(out) = writer {
+        if let Err(err) = finish(out) {
+            crate::error!("Error ending dnstap output; {err}");
+        }
+    }
+}
+
+/// Writes the Frame Streams end of stream to `out`.
+fn finish(mut out: Box<dyn Write + Send>) -> io::Result<()> {
+    out.write_all(&control_frame(CONTROL_STOP))?;
+    out.flush()
+}
+
+/// Opens `output` and writes the Frame Streams start of stream to it.
+fn open(output: &Output) -> io::Result<Box<dyn Write + Send>> {
+    let mut writer: Box<dyn Write + Send> = match output {
+        Output::File(path) => Box::new(BufWriter::new(File::create(path)?)),
+        Output::Socket(path) => {
+            let mut stream = UnixStream::connect(path)?;
+            stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+            stream.write_all(&control_frame(CONTROL_READY))?;
+            if read_control_frame(&mut stream)? != CONTROL_ACCEPT {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "dnstap reader didn't accept the connection",
+                ));
+            }
+            Box::new(BufWriter::new(stream))
+        }
+    };
+    writer.write_all(&control_frame(CONTROL_START))?;
+    writer.flush()?;
+    Ok(writer)
+}
+
+/// A control frame of type `kind`, carrying our content type unless it's a
+/// STOP, which has no fields.
+fn control_frame(kind: u32) -> Vec<u8> {
+    let mut body = kind.to_be_bytes().to_vec();
+    if kind != CONTROL_STOP {
+        body.extend(FIELD_CONTENT_TYPE.to_be_bytes());
+        body.extend((CONTENT_TYPE.len() as u32).to_be_bytes());
+        body.extend(CONTENT_TYPE);
+    }
+    // an escape (a zero data frame length) marks a control frame
+    let mut frame = 0u32.to_be_bytes().to_vec();
+    frame.extend((body.len() as u32).to_be_bytes());
+    frame.extend(body);
+    frame
+}
+
+/// Reads a control frame and returns its type; its fields are skipped.
+fn read_control_frame(stream: &mut impl Read) -> io::Result<u32> {
+    let mut word = [0u8; 4];
+    stream.read_exact(&mut word)?;
+    if word != [0; 4] {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    stream.read_exact(&mut word)?;
+    let len = u32::from_be_bytes(word) as usize;
+    if len > MAX_CONTROL_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "control frame too long",
+        ));
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+    body.get(..4)
+        .map(|kind| u32::from_be_bytes([kind[0], kind[1], kind[2], kind[3]]))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "empty control frame"))
+}
+
+fn write_data_frame(out: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    out.write_all(&(payload.len() as u32).to_be_bytes())?;
+    out.write_all(payload)
+}
+
+/// Encodes `event`, seen at `now`, as a `Dnstap` protobuf message.
+pub fn encode(event: &Event, now: SystemTime) -> Vec<u8> {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let family = event
+        .query_address
+        .or(event.response_address)
+        .map(|addr| match addr {
+            SocketAddr::V4(_) => 1,
+            SocketAddr::V6(_) => 2,
+        });
+    let protocol = match event.protocol {
+        Protocol::Udp => 1,
+        Protocol::Tcp => 2,
+    };
+
+    let mut message = Vec::new();
+    varint_field(&mut message, 1, event.kind as u64);
+    if let Some(family) = family {
+        varint_field(&mut message, 2, family);
+    }
+    varint_field(&mut message, 3, protocol);
+    if let Some(addr) = event.query_address {
+        bytes_field(&mut message, 4, &ip_bytes(addr.ip()));
+    }
+    if let Some(addr) = event.response_address {
+        bytes_field(&mut message, 5, &ip_bytes(addr.ip()));
+    }
+    if let Some(addr) = event.query_address {
+        varint_field(&mut message, 6, u64::from(addr.port()));
+    }
+    if let Some(addr) = event.response_address {
+        varint_field(&mut message, 7, u64::from(addr.port()));
+    }
+    let (sec, nsec, msg) = match event.kind.is_query() {
+        true => (8, 9, 10),
+        false => (12, 13, 14),
+    };
+    varint_field(&mut message, sec, since_epoch.as_secs());
+    fixed32_field(&mut message, nsec, since_epoch.subsec_nanos());
+    bytes_field(&mut message, msg, event.message);
+
+    let mut dnstap = Vec::new();
+    bytes_field(
+        &mut dnstap,
+        2,
+        concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    bytes_field(&mut dnstap, 14, &message);
+    // Dnstap.Type MESSAGE
+    varint_field(&mut dnstap, 15, 1);
+    dnstap
+}
+
+fn ip_bytes(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(v4) => v4.octets().to_vec(),
+        IpAddr::V6(v6) => v6.octets().to_vec(),
+    }
+}
+
+fn varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn varint_field(out: &mut Vec<u8>, field: u64, value: u64) {
+    varint(out, field << 3);
+    varint(out, value);
+}
+
+fn fixed32_field(out: &mut Vec<u8>, field: u64, value: u32) {
+    varint(out, field << 3 | 5);
+    out.extend(value.to_le_bytes());
+}
+
+fn bytes_field(out: &mut Vec<u8>, field: u64, value: &[u8]) {
+    varint(out, field << 3 | 2);
+    varint(out, value.len() as u64);
+    out.extend(value);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor, Write},
+        net::SocketAddr,
+        path::PathBuf,
+        sync::{mpsc, Arc, Mutex},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    use crate::log::Protocol;
+
+    use super::{
+        control_frame, encode, read_control_frame, varint, write_frames, Event, MessageType,
+        Output, CONTROL_READY, CONTROL_STOP,
+    };
+
+    /// A writer whose output the test can still see once it's handed off.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut out = Vec::new();
+        varint(&mut out, 1);
+        varint(&mut out, 300);
+        assert_eq!(out, [0x01, 0xAC, 0x02]);
+    }
+
+    #[test]
+    fn test_encode_client_query() {
+        let event = Event {
+            kind: MessageType::ClientQuery,
+            protocol: Protocol::Udp,
+            query_address: Some(SocketAddr::from(([192, 0, 2, 1], 40000))),
+            response_address: Some(SocketAddr::from(([127, 0, 0, 1], 53))),
+            message: &[0xAB, 0xCD],
+        };
+        let encoded = encode(&event, UNIX_EPOCH + Duration::new(300, 7));
+        let message = [
+            0x08, 0x05, // type CLIENT_QUERY
+            0x10, 0x01, // socket_family INET
+            0x18, 0x01, // socket_protocol UDP
+            0x22, 0x04, 192, 0, 2, 1, // query_address
+            0x2A, 0x04, 127, 0, 0, 1, // response_address
+            0x30, 0xC0, 0xB8, 0x02, // query_port 40000
+            0x38, 0x35, // response_port 53
+            0x40, 0xAC, 0x02, // query_time_sec 300
+            0x4D, 0x07, 0x00, 0x00, 0x00, // query_time_nsec 7
+            0x52, 0x02, 0xAB, 0xCD, // query_message
+        ];
+        assert!(encoded.starts_with(&[0x12]));
+        let tail = [&[0x72, message.len() as u8][..], &message, &[0x78, 0x01]].concat();
+        assert!(encoded.ends_with(&tail));
+    }
+
+    #[test]
+    fn test_control_frame_round_trip() {
+        let frame = control_frame(CONTROL_READY);
+        assert_eq!(&frame[..8], &[0, 0, 0, 0, 0, 0, 0, 34]);
+        assert_eq!(&frame[16..20], &[0, 0, 0, 22]);
+        assert_eq!(
+            read_control_frame(&mut Cursor::new(frame)).unwrap(),
+            CONTROL_READY
+        );
+        assert!(read_control_frame(&mut Cursor::new(vec![0, 0, 0, 5])).is_err());
+    }
+
+    #[test]
+    fn test_stream_ends_with_stop_frame() {
+        let out = Shared::default();
+        let (tx, rx) = mpsc::sync_channel(4);
+        tx.send(vec![0xAB]).unwrap();
+        drop(tx);
+        write_frames(
+            Output::File(PathBuf::from("unused")),
+            Box::new(out.clone()),
+            rx,
+        );
+
+        let stop = control_frame(CONTROL_STOP);
+        assert_eq!(stop, [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]);
+        let written = out.0.lock().unwrap().clone();
+        assert_eq!(written, [&[0, 0, 0, 1, 0xAB][..], &stop].concat());
+    }
+}
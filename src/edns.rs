@@ -0,0 +1,156 @@
+use crate::{
+    buffer::{MAX_EDNS_PACKET_SIZE, MAX_UDP_PACKET_SIZE},
+    error::ParseError,
+    section::{Class, RData, Section, SectionGroup, Type},
+};
+
+/// Option code and data of a single EDNS option (RFC 6891 section 6.1.2).
+pub type EdnsOption = (u16, Vec<u8>);
+
+/// Payload size we advertise to clients and upstream resolvers.
+pub const EDNS_UDP_PAYLOAD_SIZE: u16 = MAX_EDNS_PACKET_SIZE as u16;
+
+/// The upper eight bits of BADVERS (16), the extended RCODE for an OPT
+/// record of a version we don't speak.
+pub const BADVERS: u8 = 1;
+
+const DNSSEC_OK: u32 = 0x8000;
+
+/// The fields carried by an OPT pseudo-record, which reuses CLASS for the
+/// requestor's UDP payload size and TTL for the extended RCODE, version and
+/// flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edns {
+    udp_payload_size: u16,
+    extended_rcode: u8,
+    version: u8,
+    dnssec_ok: bool,
+    options: Vec<EdnsOption>,
+}
+
+impl Edns {
+    pub fn new(
+        udp_payload_size: u16,
+        extended_rcode: u8,
+        version: u8,
+        dnssec_ok: bool,
+        options: Vec<EdnsOption>,
+    ) -> Self {
+        Self {
+            udp_payload_size,
+            extended_rcode,
+            version,
+            dnssec_ok,
+            options,
+        }
+    }
+
+    /// Finds the OPT record in an additional section, if the sender used EDNS.
+    pub fn from_section(section: &Section) -> Option<Result<Self, ParseError>> {
+        section
+            .groups
+            .iter()
+            .find(|group| group.group_type == Type::Opt)
+            .map(Edns::try_from)
+    }
+
+    pub fn udp_payload_size(&self) -> u16 {
+        self.udp_payload_size
+    }
+
+    /// How large a UDP response to this sender may be; anything advertised
+    /// below 512 is treated as 512.
+    pub fn max_response_size(&self) -> usize {
+        usize::from(self.udp_payload_size.min(EDNS_UDP_PAYLOAD_SIZE)).max(MAX_UDP_PACKET_SIZE)
+    }
+
+    pub fn extended_rcode(&self) -> u8 {
+        self.extended_rcode
+    }
+
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn dnssec_ok(&self) -> bool {
+        self.dnssec_ok
+    }
+
+    pub fn options(&self) -> &Vec<EdnsOption> {
+        &self.options
+    }
+}
+
+impl TryFrom<&SectionGroup> for Edns {
+    type Error = ParseError;
+
+    fn try_from(value: &SectionGroup) -> Result<Self, Self::Error> {
+        if value.group_type != Type::Opt || !value.domain.is_empty() {
+            return Err(ParseError::ConversionError);
+        }
+        match &value.asection {
+            Some((ttl, RData::Opt(options))) => Ok(Edns::new(
+                u16::from(value.class.clone()),
+                (ttl >> 24) as u8,
+                ((ttl >> 16) & 0xFF) as u8,
+                ttl & DNSSEC_OK != 0,
+                options.clone(),
+            )),
+            _ => Err(ParseError::ConversionError),
+        }
+    }
+}
+
+impl From<Edns> for SectionGroup {
+    fn from(value: Edns) -> Self {
+        let ttl = (u32::from(value.extended_rcode) << 24)
+            | (u32::from(value.version) << 16)
+            | if value.dnssec_ok { DNSSEC_OK } else { 0 };
+        SectionGroup::new(
+            Vec::new(),
+            Type::Opt,
+            Class::from(value.udp_payload_size),
+            Some((ttl, RData::Opt(value.options))),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::section::{Class, RData, SectionGroup, Type};
+
+    use super::Edns;
+
+    #[test]
+    fn test_edns_from_opt_record() {
+        let record = SectionGroup::new(
+            Vec::new(),
+            Type::Opt,
+            Class::Unknown(1232),
+            Some((0x0100_8000, RData::Opt(vec![(10, vec![1, 2, 3, 4])]))),
+        );
+        let edns = Edns::try_from(&record).unwrap();
+        assert_eq!(edns.udp_payload_size(), 1232);
+        assert_eq!(edns.extended_rcode(), 1);
+        assert_eq!(edns.version(), 0);
+        assert!(edns.dnssec_ok());
+        assert_eq!(edns.options(), &vec![(10, vec![1, 2, 3, 4])]);
+        assert_eq!(SectionGroup::from(edns), record);
+    }
+
+    #[test]
+    fn test_edns_max_response_size() {
+        assert_eq!(
+            Edns::new(100, 0, 0, false, Vec::new()).max_response_size(),
+            512
+        );
+        assert_eq!(
+            Edns::new(1232, 0, 0, false, Vec::new()).max_response_size(),
+            1232
+        );
+        assert_eq!(
+            Edns::new(u16::MAX, 0, 0, false, Vec::new()).max_response_size(),
+            4096
+        );
+    }
+}
@@ -12,12 +12,119 @@ pub enum ParseError {
     SectionError,
     #[error("jump error during parsing")]
     JumpError,
+    #[error(transparent)]
+    Buffer(#[from] UdpBufferError),
+    #[error("compression pointer at offset {offset} leads back to itself")]
+    PointerLoop { offset: usize },
+    #[error("compression pointer at offset {offset} points forward to {target}")]
+    ForwardPointer { offset: usize, target: usize },
+    #[error("label at offset {offset} is {length} bytes long; at most 63 are allowed")]
+    LabelTooLong { offset: usize, length: u8 },
+    #[error("name at offset {offset} is longer than 255 bytes")]
+    NameTooLong { offset: usize },
+    #[error("label at offset {offset} isn't valid UTF-8")]
+    InvalidLabel { offset: usize },
+    #[error("RDLENGTH {length} at offset {offset} runs past the end of the message")]
+    RdataOverrun { offset: usize, length: u16 },
+    #[error("RDATA at offset {offset} takes {used} bytes but its RDLENGTH is {length}")]
+    RdataLength {
+        offset: usize,
+        length: u16,
+        used: usize,
+    },
+    #[error("{count} bytes of trailing data at offset {offset}")]
+    TrailingBytes { offset: usize, count: usize },
+}
+
+impl ParseError {
+    /// The variant's name, to tell kinds of failure apart without their
+    /// details.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ParseError::ConversionError => "ConversionError",
+            ParseError::UnimplementedError => "UnimplementedError",
+            ParseError::OverflowError { .. } => "OverflowError",
+            ParseError::SectionError => "SectionError",
+            ParseError::JumpError => "JumpError",
+            ParseError::Buffer(_) => "Buffer",
+            ParseError::PointerLoop { .. } => "PointerLoop",
+            ParseError::ForwardPointer { .. } => "ForwardPointer",
+            ParseError::LabelTooLong { .. } => "LabelTooLong",
+            ParseError::NameTooLong { .. } => "NameTooLong",
+            ParseError::InvalidLabel { .. } => "InvalidLabel",
+            ParseError::RdataOverrun { .. } => "RdataOverrun",
+            ParseError::RdataLength { .. } => "RdataLength",
+            ParseError::TrailingBytes { .. } => "TrailingBytes",
+        }
+    }
 }
 
 #[derive(Debug, Error)]
 pub enum UdpBufferError {
-    #[error("EOB reached")]
-    EndOfBuffer,
+    #[error("EOB reached at offset {offset}")]
+    EndOfBuffer { offset: usize },
     #[error("could not reach the index requested; tried to reach {index:?}")]
     Seek { index: usize },
 }
+
+#[derive(Debug, Error)]
+pub enum ResolveError {
+    #[error("no name server could be reached for {domain:?}")]
+    NoServers { domain: String },
+    #[error("gave up on {domain:?} after too many referrals")]
+    TooManyReferrals { domain: String },
+    #[error("CNAME chain for {domain:?} is too long")]
+    CnameChain { domain: String },
+    #[error("referral for {domain:?} doesn't move closer to the answer")]
+    LameDelegation { domain: String },
+    #[error("name server lookups nested too deeply while resolving {domain:?}")]
+    TooDeep { domain: String },
+    #[error("name server {server} answered {rcode} for {domain:?}")]
+    ServerFailed {
+        server: std::net::SocketAddr,
+        rcode: crate::header::ResponseCode,
+        domain: String,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum ZoneError {
+    #[error("couldn't read zone file {path:?}; {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}:{line}: {reason}")]
+    Syntax {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+    #[error("{path}: $INCLUDE nested too deeply")]
+    IncludeDepth { path: String },
+    #[error("zone file {path:?} needs exactly one SOA record; found {found}")]
+    Soa { path: String, found: usize },
+    #[error("{name:?} is outside of zone {origin:?}")]
+    OutOfZone { name: String, origin: String },
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("couldn't read config file {path:?}; {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("{path}:{line}: {reason}")]
+    Syntax {
+        path: String,
+        line: usize,
+        reason: String,
+    },
+    #[error("{flag}: {reason}")]
+    Flag { flag: String, reason: String },
+    #[error("invalid configuration; {0}")]
+    Invalid(String),
+    #[error(transparent)]
+    Zone(#[from] ZoneError),
+}
@@ -1,3 +1,5 @@
+use std::fmt;
+
 use crate::error::ParseError;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -169,7 +171,7 @@ impl HeaderSecondRowFirstHalf {
 impl From<HeaderSecondRowFirstHalf> for u8 {
     fn from(value: HeaderSecondRowFirstHalf) -> Self {
         let qr = value.qr as u8;
-        let opcode = value.opcode as u8;
+        let opcode = u8::from(&value.opcode);
         let aa = value.aa as u8;
         let tc = value.tc as u8;
         let rd = value.rd as u8;
@@ -285,10 +287,33 @@ pub enum RecursionAvailablity {
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OpCode {
-    Query = 0,
-    IQuery = 1,
-    Status = 2,
-    FutureUse = 3,
+    Query,
+    IQuery,
+    Status,
+    /// Any of 3 to 15, kept so replies can echo it.
+    FutureUse(u8),
+}
+
+impl fmt::Display for OpCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpCode::Query => f.write_str("QUERY"),
+            OpCode::IQuery => f.write_str("IQUERY"),
+            OpCode::Status => f.write_str("STATUS"),
+            OpCode::FutureUse(value) => write!(f, "OPCODE{value}"),
+        }
+    }
+}
+
+impl From<&OpCode> for u8 {
+    fn from(value: &OpCode) -> Self {
+        match value {
+            OpCode::Query => 0,
+            OpCode::IQuery => 1,
+            OpCode::Status => 2,
+            OpCode::FutureUse(value) => *value,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -301,6 +326,19 @@ pub enum ResponseCode {
     Refused = 5,
 }
 
+impl fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ResponseCode::None => "NOERROR",
+            ResponseCode::Format => "FORMERR",
+            ResponseCode::ServerFailure => "SERVFAIL",
+            ResponseCode::Name => "NXDOMAIN",
+            ResponseCode::NotImplemented => "NOTIMP",
+            ResponseCode::Refused => "REFUSED",
+        })
+    }
+}
+
 impl TryFrom<u8> for ResponseCode {
     type Error = ParseError;
 
@@ -332,7 +370,7 @@ impl TryFrom<u8> for OpCode {
             2 => Ok(Self::Status),
             _ => {
                 if (3..=15).contains(&value) {
-                    Ok(Self::FutureUse)
+                    Ok(Self::FutureUse(value))
                 } else {
                     Err(ParseError::ConversionError)
                 }
@@ -1,11 +1,24 @@
 #![forbid(unsafe_code)]
 #![warn(missing_debug_implementations)]
 
+pub mod acl;
 pub mod buffer;
+pub mod cache;
+pub mod config;
 pub mod converter;
+pub mod dnstap;
+pub mod edns;
 pub mod error;
 pub mod header;
+pub mod log;
+pub mod message;
+pub mod metrics;
+pub mod recursive;
 pub mod section;
+pub mod transport;
+pub mod upstream;
+pub mod writer;
+pub mod zone;
 
 fn big_endian_convert_u32_to_u8_array(num: u32) -> [u8; 4] {
     let mut res = [0u8; 4];
@@ -0,0 +1,489 @@
+use std::{
+    fmt,
+    io::{self, Write},
+    net::SocketAddr,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    buffer::{UdpBuffer, DNS_HEADER_SIZE},
+    error::ParseError,
+    header::{OpCode, ResponseCode},
+    section::Type,
+};
+
+/// How much the server tells about what it's doing. Queries are logged at
+/// `Info`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(ParseError::ConversionError),
+        }
+    }
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        })
+    }
+}
+
+/// How each log line is written: `key=value` pairs or a JSON object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(ParseError::ConversionError),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub format: LogFormat,
+    /// Only one query in this many is logged.
+    pub sample: u64,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LogLevel::default(),
+            format: LogFormat::default(),
+            sample: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Protocol {
+    Udp,
+    Tcp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Udp => "udp",
+            Protocol::Tcp => "tcp",
+        })
+    }
+}
+
+/// What's logged about a query we answered.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryRecord {
+    pub client: SocketAddr,
+    pub protocol: Protocol,
+    pub txid: u16,
+    pub opcode: Option<OpCode>,
+    /// The first question, if the query had one we could read.
+    pub qname: Option<String>,
+    pub qtype: Option<Type>,
+    pub rcode: Option<ResponseCode>,
+    pub answers: u16,
+    /// The last upstream asked, if any was.
+    pub upstream: Option<SocketAddr>,
+    pub latency: Duration,
+    pub cache_hit: bool,
+}
+
+impl QueryRecord {
+    /// Reads the record off the response we sent `client` for a query that
+    /// came in at `received`.
+    pub fn from_response(
+        client: SocketAddr,
+        protocol: Protocol,
+        response: &[u8],
+        received: Instant,
+    ) -> Self {
+        let header = response.get(..DNS_HEADER_SIZE).unwrap_or_default();
+        let question = UdpBuffer::new(response).first_question();
+        Self {
+            client,
+            protocol,
+            txid: header
+                .get(..2)
+                .map_or(0, |txid| u16::from_be_bytes([txid[0], txid[1]])),
+            opcode: header
+                .get(2)
+                .and_then(|flags| OpCode::try_from((flags >> 3) & 0x0F).ok()),
+            qname: question.as_ref().map(|question| fqdn(question.domain())),
+            qtype: question.map(|question| question.group_type),
+            rcode: header
+                .get(3)
+                .and_then(|flags| ResponseCode::try_from(flags & 0x0F).ok()),
+            answers: header
+                .get(6..8)
+                .map_or(0, |count| u16::from_be_bytes([count[0], count[1]])),
+            upstream: None,
+            latency: received.elapsed(),
+            cache_hit: false,
+        }
+    }
+}
+
+/// `labels` as a fully qualified name, e.g. `example.com.`
+pub fn fqdn(labels: &[String]) -> String {
+    match labels.is_empty() {
+        true => ".".to_owned(),
+        false => labels.iter().map(|label| format!("{label}.")).collect(),
+    }
+}
+
+/// Writes log lines to stderr. Its settings can be changed while it's in
+/// use, e.g. on reload.
+#[derive(Debug, Default)]
+pub struct Logger {
+    config: RwLock<LogConfig>,
+    queries: AtomicU64,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// The logger the `error!`, `warn!`, `info!` and `debug!` macros write to.
+pub fn logger() -> &'static Logger {
+    LOGGER.get_or_init(Logger::default)
+}
+
+impl Logger {
+    pub fn new(config: LogConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            queries: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> LogConfig {
+        *self.config.read().expect("log config lock poisoned")
+    }
+
+    pub fn configure(&self, config: LogConfig) {
+        *self.config.write().expect("log config lock poisoned") = config;
+    }
+
+    pub fn enabled(&self, level: LogLevel) -> bool {
+        level <= self.config().level
+    }
+
+    pub fn message(&self, level: LogLevel, args: fmt::Arguments) {
+        let config = self.config();
+        if level <= config.level {
+            write_line(&format_message(
+                config.format,
+                SystemTime::now(),
+                level,
+                &args.to_string(),
+            ));
+        }
+    }
+
+    /// Logs the record `record` builds if queries are logged at all and
+    /// this one is picked by sampling; it's only built if so.
+    pub fn query(&self, record: impl FnOnce() -> QueryRecord) {
+        let config = self.config();
+        if LogLevel::Info > config.level {
+            return;
+        }
+        // every `sample`th query is logged, starting with the first
+        let turn = self.queries.fetch_add(1, Ordering::Relaxed) % config.sample.max(1);
+        if turn == 0 {
+            write_line(&format_query(config.format, SystemTime::now(), &record()));
+        }
+    }
+}
+
+fn write_line(line: &str) {
+    // nowhere left to report a failure to write the log
+    let _ = writeln!(io::stderr().lock(), "{line}");
+}
+
+pub fn format_message(format: LogFormat, now: SystemTime, level: LogLevel, msg: &str) -> String {
+    match format {
+        LogFormat::Text => format!("{} {level} {}", timestamp(now), text_value(msg)),
+        LogFormat::Json => format!(
+            r#"{{"ts":"{}","level":"{level}","msg":{}}}"#,
+            timestamp(now),
+            json_string(msg)
+        ),
+    }
+}
+
+pub fn format_query(format: LogFormat, now: SystemTime, record: &QueryRecord) -> String {
+    let latency_ms = record.latency.as_secs_f64() * 1000.0;
+    match format {
+        LogFormat::Text => {
+            let or_dash =
+                |value: Option<String>| value.map_or("-".to_owned(), |value| text_value(&value));
+            format!(
+                "{} info query client={} proto={} txid={} opcode={} qname={} qtype={} rcode={} answers={} upstream={} latency_ms={latency_ms:.3} cache_hit={}",
+                timestamp(now),
+                record.client,
+                record.protocol,
+                record.txid,
+                or_dash(record.opcode.as_ref().map(OpCode::to_string)),
+                or_dash(record.qname.clone()),
+                or_dash(record.qtype.as_ref().map(Type::to_string)),
+                or_dash(record.rcode.as_ref().map(ResponseCode::to_string)),
+                record.answers,
+                or_dash(record.upstream.as_ref().map(SocketAddr::to_string)),
+                record.cache_hit,
+            )
+        }
+        LogFormat::Json => {
+            let or_null = |value: Option<String>| {
+                value.map_or("null".to_owned(), |value| json_string(&value))
+            };
+            format!(
+                r#"{{"ts":"{}","level":"info","msg":"query","client":"{}","proto":"{}","txid":{},"opcode":{},"qname":{},"qtype":{},"rcode":{},"answers":{},"upstream":{},"latency_ms":{latency_ms:.3},"cache_hit":{}}}"#,
+                timestamp(now),
+                record.client,
+                record.protocol,
+                record.txid,
+                or_null(record.opcode.as_ref().map(OpCode::to_string)),
+                or_null(record.qname.clone()),
+                or_null(record.qtype.as_ref().map(Type::to_string)),
+                or_null(record.rcode.as_ref().map(ResponseCode::to_string)),
+                record.answers,
+                or_null(record.upstream.as_ref().map(SocketAddr::to_string)),
+                record.cache_hit,
+            )
+        }
+    }
+}
+
+/// `s` as a logfmt value: as it is if that reads back unambiguously, and
+/// quoted and escaped like a JSON string if not, so a hostile qname can't
+/// add fields or lines of its own.
+fn text_value(s: &str) -> String {
+    let plain = !s.is_empty()
+        && s != "-"
+        && !s
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || matches!(c, '=' | '"' | '\\'));
+    match plain {
+        true => s.to_owned(),
+        false => json_string(s),
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// `now` in RFC 3339 form, in UTC to the millisecond.
+fn timestamp(now: SystemTime) -> String {
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+    // days since the epoch to a civil date, as in Howard Hinnant's
+    // `civil_from_days`
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs_of_day / 3_600,
+        secs_of_day % 3_600 / 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::log::logger().message($crate::log::LogLevel::Error, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::log::logger().message($crate::log::LogLevel::Warn, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::log::logger().message($crate::log::LogLevel::Info, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::log::logger().message($crate::log::LogLevel::Debug, format_args!($($arg)*))
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+    };
+
+    use crate::{
+        header::{OpCode, ResponseCode},
+        message::Message,
+        section::Type,
+    };
+
+    use super::{format_message, format_query, LogFormat, LogLevel, Protocol, QueryRecord};
+
+    fn at() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(1_700_000_000_042)
+    }
+
+    fn record() -> QueryRecord {
+        QueryRecord {
+            client: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            protocol: Protocol::Udp,
+            txid: 4242,
+            opcode: Some(OpCode::Query),
+            qname: Some("example.com.".to_owned()),
+            qtype: Some(Type::Aaaa),
+            rcode: Some(ResponseCode::Name),
+            answers: 0,
+            upstream: Some(SocketAddr::from(([192, 0, 2, 1], 53))),
+            latency: Duration::from_micros(1500),
+            cache_hit: false,
+        }
+    }
+
+    #[test]
+    fn test_format_query_as_text_and_json() {
+        assert_eq!(
+            format_query(LogFormat::Text, at(), &record()),
+            "2023-11-14T22:13:20.042Z info query client=127.0.0.1:40000 proto=udp txid=4242 \
+             opcode=QUERY qname=example.com. qtype=AAAA rcode=NXDOMAIN answers=0 upstream=192.0.2.1:53 \
+             latency_ms=1.500 cache_hit=false"
+        );
+        let local = QueryRecord {
+            upstream: None,
+            cache_hit: true,
+            ..record()
+        };
+        assert_eq!(
+            format_query(LogFormat::Json, at(), &local),
+            r#"{"ts":"2023-11-14T22:13:20.042Z","level":"info","msg":"query","client":"127.0.0.1:40000","proto":"udp","txid":4242,"opcode":"QUERY","qname":"example.com.","qtype":"AAAA","rcode":"NXDOMAIN","answers":0,"upstream":null,"latency_ms":1.500,"cache_hit":true}"#
+        );
+    }
+
+    #[test]
+    fn test_format_query_quotes_hostile_text() {
+        let hostile = QueryRecord {
+            qname: Some("a\nb c=d\"\u{7f}.org.".to_owned()),
+            ..record()
+        };
+        let line = format_query(LogFormat::Text, at(), &hostile);
+        assert_eq!(line.lines().count(), 1);
+        assert!(
+            line.contains(r#" qname="a\nb c=d\"\u007f.org." qtype=AAAA "#),
+            "{line}"
+        );
+        let dash = QueryRecord {
+            qname: Some("-".to_owned()),
+            ..record()
+        };
+        assert!(format_query(LogFormat::Text, at(), &dash).contains(r#" qname="-" "#));
+    }
+
+    #[test]
+    fn test_format_message_escapes_json() {
+        assert_eq!(
+            format_message(LogFormat::Json, at(), LogLevel::Warn, "bad \"name\"\n"),
+            r#"{"ts":"2023-11-14T22:13:20.042Z","level":"warn","msg":"bad \"name\"\n"}"#
+        );
+        assert_eq!(
+            format_message(LogFormat::Text, UNIX_EPOCH, LogLevel::Error, "oops"),
+            "1970-01-01T00:00:00.000Z error oops"
+        );
+        // a name from a query can't forge a line of its own
+        assert_eq!(
+            format_message(
+                LogFormat::Text,
+                UNIX_EPOCH,
+                LogLevel::Warn,
+                "bad a.test\n1970-01-01T00:00:00.000Z error forged"
+            ),
+            r#"1970-01-01T00:00:00.000Z warn "bad a.test\n1970-01-01T00:00:00.000Z error forged""#
+        );
+    }
+
+    #[test]
+    fn test_record_from_response() {
+        let query = Message::query("example.com", Type::A).with_txid(7);
+        let response = Message::response_to(&query).with_rcode(ResponseCode::Refused);
+        let raw = Vec::<u8>::try_from(response).unwrap();
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let record = QueryRecord::from_response(client, Protocol::Tcp, &raw, Instant::now());
+        assert_eq!(record.txid, 7);
+        assert_eq!(record.opcode, Some(OpCode::Query));
+        assert_eq!(record.qname.as_deref(), Some("example.com."));
+        assert_eq!(record.qtype, Some(Type::A));
+        assert_eq!(record.rcode, Some(ResponseCode::Refused));
+        assert_eq!(record.answers, 0);
+
+        let record = QueryRecord::from_response(client, Protocol::Udp, &raw[..5], Instant::now());
+        assert_eq!(record.qname, None);
+    }
+}
@@ -1,138 +1,1806 @@
 use std::{
-    cell::RefCell,
+    collections::HashSet,
     env,
-    net::{SocketAddr, UdpSocket},
-    rc::Rc,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    path::PathBuf,
+    process,
     str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Receiver, Sender, SyncSender, TrySendError},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use dns_starter_rust::{
-    buffer::{UdpBuffer, MAX_UDP_PACKET_SIZE},
-    converter::{packet::PendingPacket, transcribe::Transcriber},
-    header::{
-        DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, OpCode, QueryResponse,
-        SectionCount, Truncation,
+    acl::Acl,
+    buffer::{UdpBuffer, MAX_EDNS_PACKET_SIZE},
+    cache::Cache,
+    config::{self, Config},
+    converter::{
+        packet::{PendingPacket, ResponseSections},
+        transcribe::{Outgoing, RetryPolicy, Transcriber},
     },
+    dnstap::{self, Event, MessageType},
+    edns::{Edns, BADVERS, EDNS_UDP_PAYLOAD_SIZE},
+    error::{ConfigError, ParseError, ZoneError},
+    header::{DnsHeader, OpCode, QueryResponse, RecursionAvailablity, ResponseCode, Truncation},
+    log::{self, LogConfig, Protocol, QueryRecord},
+    message::Message,
+    metrics,
+    recursive::{Recursor, Resolution},
+    section::{Section, SectionGroup, Type},
+    transport::{self, SocketPool, TcpLimits},
+    upstream::{self, Routes, Upstreams},
+    zone::{Zone, Zones},
+    {debug, error, info, warn},
 };
-use nom::AsBytes;
 
-fn main() {
-    let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
-    let mut buf = [0; MAX_UDP_PACKET_SIZE];
-    let mut args: Vec<String> = env::args().collect();
-    if args.len() != 3 || args.get(1).unwrap() != "--resolver" {
-        panic!("{} --resolver <ip:port>", args.first().unwrap());
+const USAGE: &str = "[--config <file>] [--check-config] [--listen <ip:port>]... [--admin <ip:port>] [--metrics <ip:port>] \
+                     [--dnstap-socket <path> | --dnstap-file <path>] \
+                     [--resolver <ip:port>,... [--strategy <strategy>] [--forward <suffix>=<ip:port>,...]... | --recursive [--root-hints <ip:port>,...]] \
+                     [--zone <file>]... [--cache-size <entries>] \
+                     [--timeout <ms>] [--retries <count>] [--upstream-sockets <count>] \
+                     [--workers <count>] [--log-level <level>] [--log-format <text|json>]";
+
+/// How long an admin connection may sit idle before it's closed.
+const ADMIN_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a metrics scrape may take to send its request, all of it.
+const METRICS_READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// The longest metrics request we read.
+const METRICS_MAX_REQUEST: usize = 8192;
+/// How often we look for down upstreams that are due a probe.
+const PROBE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Threads for the work that waits on the network for long, iterative
+/// resolution and asking again over TCP, so the workers never do.
+const BLOCKING_THREADS: usize = 16;
+/// How much of that work may wait for one of those threads; beyond it, the
+/// clients get what we have, or SERVFAIL.
+const BLOCKING_QUEUE: usize = 1024;
+
+#[derive(Debug)]
+struct Args {
+    listen: Vec<SocketAddr>,
+    admin: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+    dnstap: Option<dnstap::Output>,
+    acl: Acl,
+    upstream: Upstream,
+    zones: Zones,
+    cache_size: usize,
+    retry_policy: RetryPolicy,
+    upstream_sockets: usize,
+    workers: usize,
+    log: LogConfig,
+}
+
+/// Where answers we don't have in our zones or cache come from.
+#[derive(Debug, Clone)]
+enum Upstream {
+    /// Forward to these resolvers; the TCP and UDP sides share their health.
+    Resolver(Routes),
+    Recursive(Recursor),
+    /// Only answer from our own zones and refuse everything else.
+    Authoritative,
+}
+
+/// What the command line asks for.
+#[derive(Debug)]
+struct Cli {
+    config: Config,
+    /// Only check the configuration and exit.
+    check: bool,
+}
+
+/// Reads the config file given with `--config`, if any, and lays the other
+/// flags over it. A flag replaces the file's setting rather than adding to
+/// it, though repeated flags add up.
+fn parse_args(args: &[String]) -> Result<Cli, ConfigError> {
+    let mut check = false;
+    let mut config_path = None;
+    let mut flags = Vec::new();
+    let mut iter = args.iter();
+    while let Some(flag) = iter.next() {
+        match flag.as_str() {
+            "--check-config" => check = true,
+            "--recursive" => flags.push((flag.as_str(), "")),
+            "--config" | "--listen" | "--admin" | "--metrics" | "--dnstap-socket"
+            | "--dnstap-file" | "--resolver" | "--strategy" | "--forward" | "--root-hints"
+            | "--zone" | "--cache-size" | "--timeout" | "--retries" | "--upstream-sockets"
+            | "--workers" | "--log-level" | "--log-format" => {
+                let value = iter.next().ok_or_else(|| ConfigError::Flag {
+                    flag: flag.to_owned(),
+                    reason: "needs a value".to_owned(),
+                })?;
+                match flag.as_str() {
+                    "--config" => config_path = Some(value),
+                    _ => flags.push((flag.as_str(), value.as_str())),
+                }
+            }
+            _ => {
+                return Err(ConfigError::Flag {
+                    flag: flag.to_owned(),
+                    reason: "unknown option".to_owned(),
+                })
+            }
+        }
     }
-    let address = args.swap_remove(2);
-    let resolver_server = SocketAddr::from_str(&address).expect("Unable to parse socket address");
-    let mut transcriber = Transcriber::default();
+    let mut config = match config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let mut seen = HashSet::new();
+    for (flag, value) in flags {
+        apply_flag(&mut config, flag, value, seen.insert(flag)).map_err(|reason| {
+            ConfigError::Flag {
+                flag: flag.to_owned(),
+                reason,
+            }
+        })?;
+    }
+    Ok(Cli { config, check })
+}
 
-    loop {
-        match udp_socket.recv_from(&mut buf) {
-            Ok((_size, source)) => {
-                let udp_buf = UdpBuffer::new(buf);
-                match udp_buf.unpack() {
-                    Ok((header, [qsection, ansection, _nssection, _arsection])) => {
-                        match header.header_first_half().qr() {
-                            QueryResponse::Query => {
-                                if let Some(qsection) = qsection {
-                                    let pending_pkt = Rc::new(RefCell::new(PendingPacket::new(
-                                        (
-                                            source,
-                                            header.txid(),
-                                            header.header_first_half().opcode().to_owned(),
-                                            header.header_first_half().rd().to_owned(),
-                                        ),
-                                        qsection.groups.len(),
-                                        qsection.clone(),
-                                    )));
-                                    for group in qsection.groups {
-                                        let mut hdr = <[u8; 12]>::from(DnsHeader::new(
-                                            transcriber.txid(),
-                                            header.header_first_half().clone(),
-                                            header.header_second_half().clone(),
-                                            SectionCount::new(
-                                                1,
-                                                header.counts().ancount(),
-                                                header.counts().nscount(),
-                                                header.counts().arcount(),
-                                            ),
-                                        ))
-                                        .to_vec();
-                                        match Vec::<u8>::try_from(group) {
-                                            Ok(arr) => {
-                                                hdr.extend(arr);
-                                                let arr = hdr;
-                                                if let Err(msg) = udp_socket
-                                                    .send_to(arr.as_bytes(), resolver_server)
-                                                {
-                                                    eprintln!("Error sending; {msg}");
-                                                } else {
-                                                    transcriber.insert(Rc::clone(&pending_pkt))
-                                                }
-                                            }
-                                            Err(msg) => {
-                                                eprintln!("Error parsing; {msg}");
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    eprintln!("Couldn't get a qsection here...")
-                                }
-                            }
-                            QueryResponse::Response => match ansection {
-                                Some(ansection) => {
-                                    for group in ansection.groups {
-                                        match transcriber.receive_and_delete(header.txid(), group) {
-                                            Some((pkt, source)) => {
-                                                if let Err(msg) = udp_socket.send_to(
-                                                    Vec::<u8>::from(pkt).as_bytes(),
-                                                    source,
-                                                ) {
-                                                    eprintln!("{:?}", msg);
-                                                }
-                                            }
-                                            None => {
-                                                eprintln!("Not in one whole yet...")
-                                            }
-                                        }
-                                    }
-                                }
-
-                                None => {
-                                    // stupid cheese to pass part 5?
-                                    let fh = HeaderSecondRowFirstHalf::new(
-                                        QueryResponse::Response,
-                                        header.header_first_half().opcode().clone(),
-                                        dns_starter_rust::header::AuthAnswer::NotAuthoritative,
-                                        Truncation::NotTruncated,
-                                        header.header_first_half().rd().clone(),
-                                    );
-                                    let sh = HeaderSecondRowSecondHalf::new(header.header_second_half().ra().clone(), 0, match header.header_first_half().opcode() {
-                                        OpCode::Query => dns_starter_rust::header::ResponseCode::None,
-                                        _ => dns_starter_rust::header::ResponseCode::NotImplemented
-                                    }).expect("should work");
-                                    let res = <[u8; 12]>::from(DnsHeader::new(
-                                        header.txid(),
-                                        fh,
-                                        sh,
-                                        header.counts().clone(),
-                                    ));
-                                    if let Err(err_msg) = udp_socket.send_to(&res, source) {
-                                        eprintln!("Error sending message; got {err_msg}");
-                                    }
-                                }
-                            },
+/// Sets what `flag` stands for in `config`; `first` is whether it's the
+/// first time the flag was given.
+fn apply_flag(config: &mut Config, flag: &str, value: &str, first: bool) -> Result<(), String> {
+    let addrs = |value: &str| {
+        value
+            .split(',')
+            .map(|addr| SocketAddr::from_str(addr).map_err(|_| format!("invalid address {addr:?}")))
+            .collect::<Result<Vec<SocketAddr>, String>>()
+    };
+    let positive = |value: &str| {
+        value
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| format!("expected a positive integer, not {value:?}"))
+    };
+    match flag {
+        "--listen" => {
+            if first {
+                config.listen.clear();
+            }
+            config.listen.extend(addrs(value)?);
+        }
+        "--admin" => {
+            config.admin = Some(
+                SocketAddr::from_str(value).map_err(|_| format!("invalid address {value:?}"))?,
+            )
+        }
+        "--metrics" => {
+            config.metrics = Some(
+                SocketAddr::from_str(value).map_err(|_| format!("invalid address {value:?}"))?,
+            )
+        }
+        "--dnstap-socket" => config.dnstap = Some(dnstap::Output::Socket(value.into())),
+        "--dnstap-file" => config.dnstap = Some(dnstap::Output::File(value.into())),
+        "--resolver" => {
+            if first {
+                config.resolvers.clear();
+            }
+            config.resolvers.extend(addrs(value)?);
+        }
+        "--strategy" => {
+            config.strategy = value
+                .parse()
+                .map_err(|_| format!("unknown strategy {value:?}"))?
+        }
+        "--forward" => {
+            let (suffix, to) = value
+                .split_once('=')
+                .ok_or_else(|| "expected <suffix>=<ip:port>,...".to_owned())?;
+            if first {
+                config.forwards.clear();
+            }
+            config.forwards.push((config::domain(suffix), addrs(to)?));
+        }
+        "--recursive" => config.recursive = true,
+        "--root-hints" => config.root_hints = Some(addrs(value)?),
+        "--zone" => {
+            if first {
+                config.zones.clear();
+            }
+            config.zones.push(PathBuf::from(value));
+        }
+        "--cache-size" => config.cache_size = positive(value)?,
+        "--timeout" => config.timeout = Duration::from_millis(positive(value)? as u64),
+        "--retries" => {
+            config.retries = value
+                .parse()
+                .map_err(|_| format!("expected a count, not {value:?}"))?
+        }
+        "--upstream-sockets" => config.upstream_sockets = positive(value)?,
+        "--workers" => config.workers = Some(positive(value)?),
+        "--log-level" => {
+            config.log.level = value
+                .parse()
+                .map_err(|_| format!("expected error, warn, info or debug, not {value:?}"))?
+        }
+        "--log-format" => {
+            config.log.format = value
+                .parse()
+                .map_err(|_| format!("expected text or json, not {value:?}"))?
+        }
+        _ => return Err("unknown option".to_owned()),
+    }
+    Ok(())
+}
+
+/// Checks the configuration and loads everything it refers to.
+fn build(config: Config) -> Result<Args, ConfigError> {
+    config.validate()?;
+    let zones = config
+        .zones
+        .iter()
+        .map(Zone::load)
+        .collect::<Result<Vec<Zone>, ZoneError>>()?;
+    let upstream = if config.recursive {
+        Upstream::Recursive(config.root_hints.map(Recursor::new).unwrap_or_default())
+    } else if !config.resolvers.is_empty() {
+        let strategy = config.strategy;
+        Upstream::Resolver(Routes::new(
+            Upstreams::new(config.resolvers, strategy),
+            config
+                .forwards
+                .into_iter()
+                .map(|(suffix, addrs)| (suffix, Upstreams::new(addrs, strategy)))
+                .collect(),
+        ))
+    } else {
+        Upstream::Authoritative
+    };
+    Ok(Args {
+        listen: config.listen,
+        admin: config.admin,
+        metrics: config.metrics,
+        dnstap: config.dnstap,
+        acl: config.acl,
+        upstream,
+        zones: Zones::new(zones),
+        cache_size: config.cache_size,
+        retry_policy: RetryPolicy::new(config.timeout, config.retries),
+        upstream_sockets: config.upstream_sockets,
+        workers: config
+            .workers
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from)),
+        log: config.log,
+    })
+}
+
+/// The response to build up for a query, with the questions to answer.
+fn pending_packet(
+    source: SocketAddr,
+    header: &DnsHeader,
+    sections: [Option<Section>; 4],
+) -> Option<(PendingPacket, Vec<SectionGroup>)> {
+    let [qsection, _, _, arsection] = sections;
+    // a malformed OPT record was already answered by `unsupported`
+    let edns = arsection
+        .as_ref()
+        .and_then(Edns::from_section)
+        .and_then(Result::ok);
+    let qsection = qsection?;
+    let groups = qsection.groups.clone();
+    let pending_pkt = PendingPacket::new(
+        (
+            source,
+            header.txid(),
+            header.header_first_half().opcode().to_owned(),
+            header.header_first_half().rd().to_owned(),
+        ),
+        groups.len(),
+        qsection,
+        edns,
+    );
+    Some((pending_pkt, groups))
+}
+
+fn resolve_recursively(recursor: &Recursor, question: &SectionGroup) -> Resolution {
+    recursor.resolve(question).unwrap_or_else(|msg| {
+        error!("Error resolving recursively; {msg}");
+        (ResponseCode::ServerFailure, Vec::new(), Vec::new())
+    })
+}
+
+/// Why we won't answer a query that parsed fine, if we won't, with the OPT
+/// record to answer with: we only implement standard queries, those need a
+/// question, and an OPT record has to be well formed and of EDNS version 0
+/// (RFC 6891 section 6.1.3).
+fn unsupported(
+    header: &DnsHeader,
+    sections: &[Option<Section>; 4],
+) -> Option<(ResponseCode, Option<Edns>)> {
+    let edns = sections[3].as_ref().and_then(Edns::from_section);
+    if header.header_first_half().opcode() != &OpCode::Query {
+        Some((ResponseCode::NotImplemented, None))
+    } else if sections[0].is_none() {
+        Some((ResponseCode::Format, None))
+    } else {
+        match edns {
+            Some(Err(_)) => Some((ResponseCode::Format, None)),
+            Some(Ok(edns)) if edns.version() > 0 => Some((
+                ResponseCode::None,
+                Some(Edns::new(
+                    EDNS_UDP_PAYLOAD_SIZE,
+                    BADVERS,
+                    0,
+                    edns.dnssec_ok(),
+                    Vec::new(),
+                )),
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// The address socket `idx` of `sockets` is bound to.
+fn local_addr(sockets: &[UdpSocket], idx: usize) -> Option<SocketAddr> {
+    sockets.get(idx).and_then(|socket| socket.local_addr().ok())
+}
+
+/// Counts a response we sent in the metrics and logs it.
+fn report(record: QueryRecord) {
+    metrics::metrics().query(&record);
+    log::logger().query(|| record);
+}
+
+/// The reply with `rcode`, and `edns` if given, to a query we can't parse or
+/// won't answer, so the client gives up right away rather than trying again.
+fn error_reply(query: &[u8], rcode: ResponseCode, edns: Option<Edns>) -> Option<Vec<u8>> {
+    let reply = Message::error_reply(query, rcode)?;
+    Vec::<u8>::try_from(match edns {
+        Some(edns) => reply.with_edns(edns),
+        None => reply,
+    })
+    .map_err(|msg| error!("Error writing reply; {msg}"))
+    .ok()
+}
+
+/// Builds the single-question query we send upstream for one of the
+/// client's questions.
+fn upstream_query(
+    txid: u16,
+    header: &DnsHeader,
+    group: SectionGroup,
+    edns: Option<Edns>,
+) -> Result<Vec<u8>, ParseError> {
+    let fh = header.header_first_half();
+    let query = Message::new(txid)
+        .with_opcode(fh.opcode().clone())
+        .with_recursion_desired(fh.rd().clone())
+        .with_question(group);
+    Vec::<u8>::try_from(match edns {
+        Some(edns) => query.with_edns(edns),
+        None => query,
+    })
+}
+
+/// Answer, authority and additional records of an upstream reply. The
+/// upstream's OPT record is dropped since we answer with our own; see
+/// `extended_rcode` for what of it we keep.
+fn response_sections(
+    ansection: Option<Section>,
+    nssection: Option<Section>,
+    arsection: Option<Section>,
+) -> ResponseSections {
+    let groups =
+        |section: Option<Section>| section.map(|section| section.groups).unwrap_or_default();
+    let additional = groups(arsection)
+        .into_iter()
+        .filter(|group| group.group_type() != &Type::Opt)
+        .collect();
+    (groups(ansection), groups(nssection), additional)
+}
+
+/// The upper bits of the RCODE the OPT record in an upstream reply's
+/// additional section extends the header's by, 0 if there's none.
+fn extended_rcode(arsection: Option<&Section>) -> u8 {
+    arsection
+        .and_then(Edns::from_section)
+        .and_then(Result::ok)
+        .map_or(0, |edns| edns.extended_rcode())
+}
+
+/// Asks the upstream again over TCP when its UDP answer didn't fit, falling
+/// back to the partial answer if that fails. Also says whether the sections
+/// we ended up with are still truncated.
+fn retry_if_truncated(
+    header: &DnsHeader,
+    group: &SectionGroup,
+    sections: ResponseSections,
+    resolver_server: SocketAddr,
+) -> (ResponseSections, Truncation) {
+    let truncation = header.header_first_half().tc().to_owned();
+    if truncation == Truncation::NotTruncated {
+        return (sections, truncation);
+    }
+    let tap = |kind, message: &[u8]| {
+        dnstap::tap(|| Event {
+            kind,
+            protocol: Protocol::Tcp,
+            query_address: None,
+            response_address: Some(resolver_server),
+            message,
+        })
+    };
+    let retried = upstream_query(header.txid(), header, group.clone(), None)
+        .map_err(anyhow::Error::from)
+        .and_then(|query| {
+            tap(MessageType::ForwarderQuery, &query);
+            Ok(transport::query_tcp(resolver_server, &query)?)
+        })
+        .and_then(|response| {
+            tap(MessageType::ForwarderResponse, &response);
+            Ok(UdpBuffer::new(&response).unpack()?)
+        });
+    match retried {
+        Ok((header, [_, ansection, nssection, arsection])) => (
+            response_sections(ansection, nssection, arsection),
+            header.header_first_half().tc().to_owned(),
+        ),
+        Err(msg) => {
+            warn!("Error retrying over TCP; {msg}");
+            (sections, truncation)
+        }
+    }
+}
+
+/// The part of the configuration a reload swaps in. Each query is answered
+/// with the state as it was when the query came in.
+#[derive(Debug)]
+struct State {
+    upstream: Upstream,
+    zones: Zones,
+    acl: Acl,
+}
+
+/// Everything the UDP side shares between its worker threads.
+#[derive(Debug)]
+struct Forwarder {
+    /// The sockets we listen on, by listener index.
+    listeners: Vec<UdpSocket>,
+    upstream_sockets: SocketPool,
+    state: RwLock<Arc<State>>,
+    transcriber: Transcriber,
+    cache: Mutex<Cache>,
+    dropped: Dropped,
+    /// Where work that would hold up a worker goes.
+    blocking: SyncSender<Blocking>,
+}
+
+/// Work that waits on the network for long, done on threads of its own.
+#[derive(Debug)]
+enum Blocking {
+    /// Resolve one of the questions in a client's query iteratively.
+    Resolve(Arc<Mutex<PendingPacket>>, Recursor, SectionGroup),
+    /// Ask `upstream` again over TCP for an answer that was truncated.
+    Retry {
+        pending_pkt: Arc<Mutex<PendingPacket>>,
+        upstream: SocketAddr,
+        header: DnsHeader,
+        question: SectionGroup,
+        rcode: (ResponseCode, u8),
+        sections: ResponseSections,
+    },
+}
+
+/// Replies we threw away rather than pass on to a client.
+#[derive(Debug, Default)]
+struct Dropped {
+    /// Not the answer to any query we have in flight.
+    unmatched: AtomicU64,
+    /// Couldn't be parsed.
+    malformed: AtomicU64,
+    /// Sent to the socket clients talk to rather than an upstream one.
+    unsolicited: AtomicU64,
+}
+
+impl Dropped {
+    /// Counts one more drop, returning how many there have been.
+    fn count(counter: &AtomicU64) -> u64 {
+        counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+}
+
+impl Forwarder {
+    fn state(&self) -> Arc<State> {
+        Arc::clone(&self.state.read().expect("state lock poisoned"))
+    }
+
+    /// Swaps in a reloaded configuration. The cache and the queries in
+    /// flight are kept.
+    fn swap(&self, state: State, cache_size: usize, retry_policy: RetryPolicy) {
+        self.cache
+            .lock()
+            .expect("cache lock poisoned")
+            .resize(cache_size);
+        self.transcriber.set_policy(retry_policy);
+        *self.state.write().expect("state lock poisoned") = Arc::new(state);
+    }
+
+    fn handle_query(
+        &self,
+        listener: usize,
+        source: SocketAddr,
+        header: DnsHeader,
+        sections: [Option<Section>; 4],
+    ) {
+        let Some((mut pending_pkt, groups)) = pending_packet(source, &header, sections) else {
+            // `unsupported` answers these with FORMERR before we get here
+            debug!(
+                "Dropping query {:#06x} from {source} without a question section",
+                header.txid()
+            );
+            return;
+        };
+        pending_pkt.set_listener(listener);
+        self.answer(Arc::new(Mutex::new(pending_pkt)), &header, groups);
+    }
+
+    /// Answers a query read off a TCP connection, returning the response
+    /// with the upstream that was asked last, if any was, and whether the
+    /// cache helped. Upstream queries go out over UDP like any other, under
+    /// a txid of their own, and the answer comes back to this thread.
+    fn answer_over_tcp(
+        &self,
+        source: SocketAddr,
+        query: &[u8],
+    ) -> Option<(Vec<u8>, Option<SocketAddr>, bool)> {
+        let (header, sections) = match UdpBuffer::new(query).unpack() {
+            Ok(parsed) => parsed,
+            Err(msg) => {
+                debug!("Error parsing; {msg}");
+                metrics::metrics().parse_failure(&msg);
+                return error_reply(query, ResponseCode::Format, None)
+                    .map(|reply| (reply, None, false));
+            }
+        };
+        if let Some((rcode, edns)) = unsupported(&header, &sections) {
+            return error_reply(query, rcode, edns).map(|reply| (reply, None, false));
+        }
+        let (mut pending_pkt, groups) = pending_packet(source, &header, sections)?;
+        let (responder, answered) = mpsc::channel();
+        pending_pkt.set_responder(responder);
+        self.answer(Arc::new(Mutex::new(pending_pkt)), &header, groups);
+        let Ok(pending_pkt) = answered.recv() else {
+            error!("Lost the response to a query over TCP");
+            return error_reply(query, ResponseCode::ServerFailure, None)
+                .map(|reply| (reply, None, false));
+        };
+        let (upstream, cache_hit) = (pending_pkt.upstream(), pending_pkt.cache_hit());
+        match pending_pkt.into_packet_with_max_size(usize::from(u16::MAX)) {
+            Ok((pkt, _)) => Some((Vec::<u8>::from(pkt), upstream, cache_hit)),
+            Err(msg) => {
+                error!("Error writing reply; {msg}");
+                error_reply(query, ResponseCode::ServerFailure, None)
+                    .map(|reply| (reply, upstream, cache_hit))
+            }
+        }
+    }
+
+    /// Answers each of the questions in `pending_pkt` from our zones, the
+    /// cache or the upstream. Whichever answer comes in last sends the reply.
+    fn answer(
+        &self,
+        pending_pkt: Arc<Mutex<PendingPacket>>,
+        header: &DnsHeader,
+        groups: Vec<SectionGroup>,
+    ) {
+        let state = self.state();
+        let lock = || pending_pkt.lock().expect("pending packet lock poisoned");
+        let dnssec_ok = lock().edns().is_some_and(Edns::dnssec_ok);
+        if let Upstream::Recursive(_) = state.upstream {
+            lock().set_recursion_available(RecursionAvailablity::RecursionAvailable);
+        }
+        let now = Instant::now();
+        for group in groups {
+            if let Some((aa, rcode, sections)) = state.zones.answer(&group) {
+                let mut pending_pkt = lock();
+                pending_pkt.set_authoritative(aa);
+                pending_pkt.insert_sections(rcode, sections);
+                continue;
+            }
+            let key = Cache::key(group.domain(), group.group_type(), group.class());
+            let cached = self
+                .cache
+                .lock()
+                .expect("cache lock poisoned")
+                .get(&key, now);
+            metrics::metrics().cache_lookup(cached.is_some());
+            if let Some((rcode, answers, authority)) = cached {
+                let mut pending_pkt = lock();
+                pending_pkt.set_cache_hit();
+                pending_pkt.insert_sections(rcode, (answers, authority, Vec::new()));
+                continue;
+            }
+            let resolver_server = match &state.upstream {
+                Upstream::Resolver(routes) => routes
+                    .route(group.domain())
+                    .lock()
+                    .expect("upstreams lock poisoned")
+                    .select(None),
+                Upstream::Recursive(recursor) => {
+                    let pending_pkt = Arc::clone(&pending_pkt);
+                    self.run_blocking(Blocking::Resolve(pending_pkt, recursor.clone(), group));
+                    continue;
+                }
+                Upstream::Authoritative => {
+                    lock().insert_answers(ResponseCode::Refused, Vec::new());
+                    continue;
+                }
+            };
+            // always offer upstream our full payload size so
+            // large answers don't come back truncated
+            let opt = Edns::new(EDNS_UDP_PAYLOAD_SIZE, 0, 0, dnssec_ok, Vec::new());
+            // the transcriber fills in a txid of its own
+            match upstream_query(0, header, group.clone(), Some(opt)) {
+                Ok(arr) => {
+                    let Some(socket) = self.upstream_sockets.pick(resolver_server) else {
+                        error!("No upstream socket to reach {resolver_server} from");
+                        lock().insert_answers(ResponseCode::ServerFailure, Vec::new());
+                        continue;
+                    };
+                    // tracked before it's sent so a quick answer finds it, and
+                    // retried or answered with SERVFAIL even if sending fails
+                    match self.transcriber.insert(
+                        Arc::clone(&pending_pkt),
+                        arr,
+                        (socket, resolver_server, group),
+                        Instant::now(),
+                    ) {
+                        Some(outgoing) => self.send_upstream(&outgoing),
+                        None => {
+                            warn!("Too many queries in flight");
+                            lock().insert_answers(ResponseCode::ServerFailure, Vec::new());
                         }
                     }
+                }
+                Err(msg) => {
+                    error!("Error parsing; {msg}");
+                    lock().insert_answers(ResponseCode::ServerFailure, Vec::new());
+                }
+            }
+        }
+        // sent here if every question was answered locally, or the other
+        // answers all came in while we were still busy with it
+        self.release(pending_pkt);
+    }
+
+    /// Lets go of a client response, sending it if nobody else holds it.
+    fn release(&self, pending_pkt: Arc<Mutex<PendingPacket>>) {
+        if let Some(pending_pkt) = Arc::into_inner(pending_pkt) {
+            self.send_reply(
+                pending_pkt
+                    .into_inner()
+                    .expect("pending packet lock poisoned"),
+            );
+        }
+    }
+
+    /// Hands `job` to the blocking threads, or if too much is waiting on
+    /// them already, answers without it.
+    fn run_blocking(&self, job: Blocking) {
+        let job = match self.blocking.try_send(job) {
+            Ok(()) => return,
+            Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) => job,
+        };
+        warn!("Too much work waiting on the network; answering without it");
+        match job {
+            Blocking::Resolve(pending_pkt, _, _) => {
+                pending_pkt
+                    .lock()
+                    .expect("pending packet lock poisoned")
+                    .insert_answers(ResponseCode::ServerFailure, Vec::new());
+                self.release(pending_pkt);
+            }
+            Blocking::Retry {
+                pending_pkt,
+                upstream,
+                question,
+                rcode,
+                sections,
+                ..
+            } => self.finish_answer(
+                pending_pkt,
+                (upstream, &question),
+                rcode,
+                (sections, Truncation::Truncated),
+            ),
+        }
+    }
+
+    /// Does a job off the blocking queue.
+    fn handle_blocking(&self, job: Blocking) {
+        match job {
+            Blocking::Resolve(pending_pkt, recursor, group) => {
+                let (rcode, answers, authority) = resolve_recursively(&recursor, &group);
+                self.cache_answer(&group, &rcode, &answers, &authority);
+                pending_pkt
+                    .lock()
+                    .expect("pending packet lock poisoned")
+                    .insert_sections(rcode, (answers, authority, Vec::new()));
+                self.release(pending_pkt);
+            }
+            Blocking::Retry {
+                pending_pkt,
+                upstream,
+                header,
+                question,
+                rcode,
+                sections,
+            } => {
+                let retried = retry_if_truncated(&header, &question, sections, upstream);
+                self.finish_answer(pending_pkt, (upstream, &question), rcode, retried);
+            }
+        }
+    }
+
+    /// Adds what `upstream` answered to `question` to its client response,
+    /// caching it if it's whole, and sends the response if it's done.
+    fn finish_answer(
+        &self,
+        pending_pkt: Arc<Mutex<PendingPacket>>,
+        (upstream, question): (SocketAddr, &SectionGroup),
+        rcode: (ResponseCode, u8),
+        (sections, truncation): (ResponseSections, Truncation),
+    ) {
+        // a partial answer would be served from the cache as if it were
+        // whole, and the cache keeps no extended RCODE
+        if truncation == Truncation::NotTruncated && rcode.1 == 0 {
+            self.cache_answer(question, &rcode.0, &sections.0, &sections.1);
+        }
+        if let Some(pending_pkt) = Transcriber::complete(pending_pkt, upstream, rcode, sections) {
+            self.send_reply(pending_pkt);
+        }
+    }
+
+    /// Handles a response that came in on upstream socket `socket`.
+    fn handle_response(
+        &self,
+        socket: usize,
+        source: SocketAddr,
+        header: DnsHeader,
+        sections: [Option<Section>; 4],
+    ) {
+        let [qsection, ansection, nssection, arsection] = sections;
+        let question = qsection
+            .as_ref()
+            .and_then(|qsection| qsection.groups.first());
+        let Some(question) = question.filter(|question| {
+            self.transcriber
+                .matches(header.txid(), socket, source, Some(question))
+        }) else {
+            warn!(
+                "Dropping response from {source} that doesn't match a query; {} so far",
+                Dropped::count(&self.dropped.unmatched)
+            );
+            return;
+        };
+        let rtt = self.transcriber.elapsed(header.txid(), Instant::now());
+        // a reload may have stopped us forwarding since the query went out
+        if let (Some(rtt), Upstream::Resolver(routes)) = (rtt, &self.state().upstream) {
+            routes
+                .route(question.domain())
+                .lock()
+                .expect("upstreams lock poisoned")
+                .record_success(source, rtt);
+        }
+        let rcode = (
+            header.header_second_half().rcode().clone(),
+            extended_rcode(arsection.as_ref()),
+        );
+        let sections = response_sections(ansection, nssection, arsection);
+        let Some(pending_pkt) = self.transcriber.take(header.txid()) else {
+            return;
+        };
+        let question = question.clone();
+        match header.header_first_half().tc() {
+            Truncation::Truncated => self.run_blocking(Blocking::Retry {
+                pending_pkt,
+                upstream: source,
+                header,
+                question,
+                rcode,
+                sections,
+            }),
+            Truncation::NotTruncated => self.finish_answer(
+                pending_pkt,
+                (source, &question),
+                rcode,
+                (sections, Truncation::NotTruncated),
+            ),
+        }
+    }
+
+    /// Sends a finished response to a client from the socket its query came
+    /// in on, and logs it.
+    fn send_reply(&self, mut pending_pkt: PendingPacket) {
+        // queries read off a TCP connection are answered by its own thread
+        if let Some(responder) = pending_pkt.take_responder() {
+            // the connection may have gone away in the meantime
+            let _ = responder.send(pending_pkt);
+            return;
+        }
+        let (received, upstream, cache_hit) = (
+            pending_pkt.received(),
+            pending_pkt.upstream(),
+            pending_pkt.cache_hit(),
+        );
+        let (pkt, source) = match pending_pkt.into_packet() {
+            Ok(packet) => packet,
+            Err(msg) => return error!("Error writing reply; {msg}"),
+        };
+        let listener = pkt.listener();
+        let raw = Vec::<u8>::from(pkt);
+        self.send_to_client(listener, source, &raw);
+        report(QueryRecord {
+            upstream,
+            cache_hit,
+            ..QueryRecord::from_response(source, Protocol::Udp, &raw, received)
+        });
+    }
+
+    fn send_to_client(&self, listener: usize, source: SocketAddr, msg: &[u8]) {
+        dnstap::tap(|| Event {
+            kind: MessageType::ClientResponse,
+            protocol: Protocol::Udp,
+            query_address: Some(source),
+            response_address: local_addr(&self.listeners, listener),
+            message: msg,
+        });
+        let sent = match self.listeners.get(listener) {
+            Some(udp_socket) => udp_socket.send_to(msg, source),
+            None => return error!("No listening socket {listener}"),
+        };
+        if let Err(msg) = sent {
+            error!("Error replying; {msg}");
+        }
+    }
+
+    fn send_upstream(&self, (socket, upstream, query): &Outgoing) {
+        dnstap::tap(|| Event {
+            kind: MessageType::ForwarderQuery,
+            protocol: Protocol::Udp,
+            query_address: self.upstream_sockets.local_addr(*socket),
+            response_address: Some(*upstream),
+            message: query,
+        });
+        if let Err(msg) = self.upstream_sockets.send_to(*socket, query, *upstream) {
+            error!("Error sending; {msg}");
+        }
+    }
+
+    /// Resends upstream queries that timed out and answers SERVFAIL for
+    /// those out of retries.
+    fn expire(&self) {
+        let now = Instant::now();
+        let (timed_out, failed) = self.transcriber.expire(now);
+        let timeout = self.transcriber.policy().timeout();
+        let state = self.state();
+        let mut resend = Vec::new();
+        for (txid, (socket, upstream, question)) in timed_out {
+            metrics::metrics().upstream_timeout(upstream);
+            let next = match &state.upstream {
+                Upstream::Resolver(routes) => {
+                    let mut upstreams = routes
+                        .route(question.domain())
+                        .lock()
+                        .expect("upstreams lock poisoned");
+                    upstreams.record_timeout(upstream, timeout, now);
+                    // retries go to another upstream if there is one
+                    upstreams.select(Some(upstream))
+                }
+                // forwarding was turned off by a reload; finish what's in flight
+                _ => upstream,
+            };
+            // stay put if there's no socket for the next one yet
+            let next = self
+                .upstream_sockets
+                .pick(next)
+                .map_or((socket, upstream), |socket| (socket, next));
+            resend.extend(self.transcriber.redirect(txid, next, now));
+        }
+        resend
+            .iter()
+            .for_each(|outgoing| self.send_upstream(outgoing));
+        failed.into_iter().for_each(|reply| self.send_reply(reply));
+    }
+
+    /// Caches positive answers as they are, and NXDOMAIN/NODATA answers for
+    /// as long as the SOA in their authority section allows.
+    fn cache_answer(
+        &self,
+        question: &SectionGroup,
+        rcode: &ResponseCode,
+        answers: &[SectionGroup],
+        authority: &[SectionGroup],
+    ) {
+        let key = Cache::key(question.domain(), question.group_type(), question.class());
+        let soa = authority
+            .iter()
+            .find(|group| group.group_type() == &Type::Soa);
+        let mut cache = self.cache.lock().expect("cache lock poisoned");
+        match (rcode, soa) {
+            (ResponseCode::None, _) if !answers.is_empty() => {
+                cache.insert(key, answers.to_vec(), Instant::now())
+            }
+            (ResponseCode::None | ResponseCode::Name, Some(soa)) => {
+                cache.insert_negative(key, rcode.clone(), soa.clone(), Instant::now())
+            }
+            _ => {}
+        }
+    }
+
+    /// Dispatches a datagram one of the socket readers picked up.
+    fn handle(&self, incoming: Incoming) {
+        match incoming {
+            Incoming::Client(listener, source, msg) => self.handle_client(listener, source, &msg),
+            Incoming::Upstream(socket, source, msg) => self.handle_upstream(socket, source, &msg),
+        }
+    }
+
+    fn handle_client(&self, listener: usize, source: SocketAddr, msg: &[u8]) {
+        let received = Instant::now();
+        dnstap::tap(|| Event {
+            kind: MessageType::ClientQuery,
+            protocol: Protocol::Udp,
+            query_address: Some(source),
+            response_address: local_addr(&self.listeners, listener),
+            message: msg,
+        });
+        let reply_error =
+            |rcode, edns| self.reply_error(listener, source, msg, (rcode, edns), received);
+        if !self.state().acl.permits(source.ip()) {
+            return reply_error(ResponseCode::Refused, None);
+        }
+        match UdpBuffer::new(msg).unpack() {
+            Ok((header, sections)) => match header.header_first_half().qr() {
+                QueryResponse::Query => match unsupported(&header, &sections) {
+                    Some((rcode, edns)) => reply_error(rcode, edns),
+                    None => self.handle_query(listener, source, header, sections),
+                },
+                QueryResponse::Response => warn!(
+                    "Dropping response from {source} sent to our listening socket; {} so far",
+                    Dropped::count(&self.dropped.unsolicited)
+                ),
+            },
+            Err(err) => {
+                debug!("Error parsing; {err}");
+                metrics::metrics().parse_failure(&err);
+                reply_error(ResponseCode::Format, None);
+            }
+        }
+    }
+
+    fn handle_upstream(&self, socket: usize, source: SocketAddr, msg: &[u8]) {
+        dnstap::tap(|| Event {
+            kind: MessageType::ForwarderResponse,
+            protocol: Protocol::Udp,
+            query_address: self.upstream_sockets.local_addr(socket),
+            response_address: Some(source),
+            message: msg,
+        });
+        match UdpBuffer::new(msg).unpack() {
+            Ok((header, sections)) => match header.header_first_half().qr() {
+                QueryResponse::Response => self.handle_response(socket, source, header, sections),
+                QueryResponse::Query => {
+                    warn!("Dropping query from {source} sent to an upstream socket")
+                }
+            },
+            Err(err) => {
+                warn!("Error parsing; {err}");
+                metrics::metrics().parse_failure(&err);
+                Dropped::count(&self.dropped.malformed);
+            }
+        }
+    }
+
+    fn reply_error(
+        &self,
+        listener: usize,
+        source: SocketAddr,
+        query: &[u8],
+        (rcode, edns): (ResponseCode, Option<Edns>),
+        received: Instant,
+    ) {
+        if let Some(reply) = error_reply(query, rcode, edns) {
+            self.send_to_client(listener, source, &reply);
+            report(QueryRecord::from_response(
+                source,
+                Protocol::Udp,
+                &reply,
+                received,
+            ));
+        }
+    }
+}
+
+/// Takes datagrams off the channel the socket readers share and handles
+/// them, until the readers are gone.
+fn work(forwarder: &Forwarder, rx: &Mutex<Receiver<Incoming>>) {
+    // the lock is only held while waiting for the next datagram
+    while let Ok(incoming) = rx.lock().expect("receiver lock poisoned").recv() {
+        forwarder.handle(incoming);
+    }
+}
+
+/// Runs a blocking thread: does the jobs the workers hand it until the
+/// forwarder is gone.
+fn work_blocking(forwarder: &Forwarder, rx: &Mutex<Receiver<Blocking>>) {
+    while let Ok(job) = rx.lock().expect("receiver lock poisoned").recv() {
+        forwarder.handle_blocking(job);
+    }
+}
+
+/// Probes the down upstreams as their probes come due, each on a thread of
+/// its own so a silent upstream doesn't hold up the others.
+fn probe_upstreams(forwarder: &Forwarder) {
+    loop {
+        thread::sleep(PROBE_CHECK_INTERVAL);
+        let state = forwarder.state();
+        // a reload may have stopped us forwarding
+        let Upstream::Resolver(routes) = &state.upstream else {
+            continue;
+        };
+        for upstreams in routes.all() {
+            let due = upstreams
+                .lock()
+                .expect("upstreams lock poisoned")
+                .due_probes(Instant::now());
+            for addr in due {
+                let upstreams = Arc::clone(upstreams);
+                thread::spawn(move || upstream::probe(&upstreams, addr));
+            }
+        }
+    }
+}
+
+/// Serves client TCP connections on `tcp_listener`, each on a thread of its
+/// own, closing those beyond what `limits` allows.
+fn serve_tcp<F>(tcp_listener: TcpListener, handler: Arc<F>, limits: TcpLimits)
+where
+    F: Fn(SocketAddr, Vec<u8>) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    for stream in tcp_listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let Some(slot) = limits.admit() else {
+                    warn!(
+                        "Closing TCP connection from {}; too many connections already",
+                        stream
+                            .peer_addr()
+                            .map_or_else(|_| "unknown".to_owned(), |peer| peer.to_string())
+                    );
+                    continue;
+                };
+                let (handler, limits) = (Arc::clone(&handler), limits.clone());
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Err(msg) = transport::serve_tcp_connection(stream, handler, &limits) {
+                        debug!("Error on TCP connection; {msg}");
+                    }
+                });
+            }
+            Err(msg) => error!("Error accepting TCP connection; {msg}"),
+        }
+    }
+}
+
+/// Re-reads the configuration given on the command line the server was
+/// started with.
+#[derive(Debug)]
+struct Reloader {
+    flags: Vec<String>,
+    /// Settings that only take effect on restart, as the server started.
+    listen: Vec<SocketAddr>,
+    workers: usize,
+    upstream_sockets: usize,
+    admin: Option<SocketAddr>,
+    metrics: Option<SocketAddr>,
+    dnstap: Option<dnstap::Output>,
+    /// Where readers of newly bound upstream sockets hand datagrams.
+    tx: Sender<Incoming>,
+}
+
+impl Reloader {
+    /// Loads the configuration and zones afresh and swaps them in, or leaves
+    /// the running state alone if anything is wrong with them.
+    fn reload(&self, forwarder: &Forwarder) -> Result<(), ConfigError> {
+        let args = build(parse_args(&self.flags)?.config)?;
+        bind_upstream_sockets(&forwarder.upstream_sockets, &args.upstream, &self.tx).map_err(
+            |err| ConfigError::Invalid(format!("couldn't bind the upstream sockets; {err}")),
+        )?;
+        if args.listen != self.listen
+            || args.workers != self.workers
+            || args.upstream_sockets != self.upstream_sockets
+            || args.admin != self.admin
+            || args.metrics != self.metrics
+            || args.dnstap != self.dnstap
+        {
+            warn!("Listen addresses, workers, upstream sockets, the admin and metrics addresses and the dnstap output only change on restart");
+        }
+        let state = State {
+            upstream: args.upstream,
+            zones: args.zones,
+            acl: args.acl,
+        };
+        forwarder.swap(state, args.cache_size, args.retry_policy);
+        log::logger().configure(args.log);
+        Ok(())
+    }
+}
+
+/// Takes admin commands, a line each, from the clients the ACL lets query
+/// us, each connection on a thread of its own so an idle one holds up no
+/// other. `reload` re-reads the configuration and zones; reloads are done
+/// one at a time. SIGHUP isn't caught, as that takes unsafe code or a crate
+/// we don't depend on, so it terminates the server like any other process
+/// that doesn't handle it; `reload` is the way to reload.
+fn serve_admin(listener: TcpListener, forwarder: &Arc<Forwarder>, reloader: Reloader) {
+    let reloader = Arc::new(Mutex::new(reloader));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(msg) => {
+                warn!("Error accepting admin connection; {msg}");
+                continue;
+            }
+        };
+        let (forwarder, reloader) = (Arc::clone(forwarder), Arc::clone(&reloader));
+        thread::spawn(move || {
+            if let Err(msg) = admin_session(stream, &forwarder, &reloader) {
+                warn!("Error on admin connection; {msg}");
+            }
+        });
+    }
+}
+
+/// Runs the commands sent on one admin connection until it closes or goes
+/// idle.
+fn admin_session(
+    stream: TcpStream,
+    forwarder: &Forwarder,
+    reloader: &Mutex<Reloader>,
+) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    if !forwarder.state().acl.permits(peer.ip()) {
+        warn!("Refusing admin connection from {peer}");
+        return Ok(());
+    }
+    stream.set_read_timeout(Some(ADMIN_IDLE_TIMEOUT))?;
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let reply = match line?.trim() {
+            "" => continue,
+            "reload" => {
+                let reloaded = reloader
+                    .lock()
+                    .expect("reloader lock poisoned")
+                    .reload(forwarder);
+                match reloaded {
+                    Ok(()) => {
+                        info!("Reloaded configuration");
+                        "ok".to_owned()
+                    }
                     Err(err) => {
-                        eprintln!("Error parsing; {err}")
+                        error!("Reload failed, keeping the running configuration; {err}");
+                        format!("error: {err}")
                     }
                 }
             }
-            Err(err) => {
-                eprintln!("Error parsing; {err}");
+            other => format!("error: unknown command {other:?}"),
+        };
+        writeln!(writer, "{reply}")?;
+    }
+    Ok(())
+}
+
+/// Answers Prometheus scrapes from the clients the ACL lets query us, each
+/// connection on a thread of its own. Each connection gets one response and
+/// is closed.
+fn serve_metrics(listener: TcpListener, forwarder: &Arc<Forwarder>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(msg) => {
+                debug!("Error accepting metrics connection; {msg}");
+                continue;
+            }
+        };
+        let forwarder = Arc::clone(forwarder);
+        thread::spawn(move || {
+            if let Err(msg) = scrape(stream, &forwarder) {
+                debug!("Error on metrics connection; {msg}");
+            }
+        });
+    }
+}
+
+/// Answers the request on one metrics connection.
+fn scrape(mut stream: TcpStream, forwarder: &Forwarder) -> io::Result<()> {
+    let peer = stream.peer_addr()?;
+    if !forwarder.state().acl.permits(peer.ip()) {
+        warn!("Refusing metrics connection from {peer}");
+        return Ok(());
+    }
+    let head = read_request_head(&mut stream, Instant::now() + METRICS_READ_TIMEOUT)?;
+    let head = String::from_utf8_lossy(&head);
+    let request_line = head.lines().next().unwrap_or_default();
+    let response = metrics::http_response(request_line, || {
+        metrics::metrics().render(forwarder.transcriber.len())
+    });
+    stream.set_write_timeout(Some(METRICS_READ_TIMEOUT))?;
+    stream.write_all(response.as_bytes())
+}
+
+/// Reads an HTTP request up to the blank line after its headers, which
+/// don't change what we answer but are read so the client doesn't see its
+/// request cut off. Gives up on requests longer than
+/// `METRICS_MAX_REQUEST` or not sent by `deadline`.
+fn read_request_head(stream: &mut TcpStream, deadline: Instant) -> io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !(head.windows(4).any(|end| end == b"\r\n\r\n")
+        || head.windows(2).any(|end| end == b"\n\n"))
+    {
+        if head.len() >= METRICS_MAX_REQUEST {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request too long",
+            ));
+        }
+        let too_slow = || io::Error::new(io::ErrorKind::TimedOut, "request too slow");
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(too_slow());
+        }
+        stream.set_read_timeout(Some(left))?;
+        let want = buf.len().min(METRICS_MAX_REQUEST - head.len());
+        match stream.read(&mut buf[..want]) {
+            // whatever came before the client stopped sending is all we get
+            Ok(0) => break,
+            Ok(size) => head.extend_from_slice(&buf[..size]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Err(too_slow()),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(head)
+}
+
+/// A datagram read off one of our UDP sockets.
+#[derive(Debug)]
+enum Incoming {
+    /// From a client, on the listening socket with this index.
+    Client(usize, SocketAddr, Vec<u8>),
+    /// On the upstream socket with this index in the pool.
+    Upstream(usize, SocketAddr, Vec<u8>),
+}
+
+/// Makes sure the pool has sockets for every resolver `upstream` forwards
+/// to, reading from any it had to bind.
+fn bind_upstream_sockets(
+    pool: &SocketPool,
+    upstream: &Upstream,
+    tx: &Sender<Incoming>,
+) -> io::Result<()> {
+    let Upstream::Resolver(routes) = upstream else {
+        return Ok(());
+    };
+    for addr in routes.addrs() {
+        for (idx, socket) in pool.bind_for(addr)? {
+            spawn_reader(socket, tx.clone(), move |source, msg| {
+                Incoming::Upstream(idx, source, msg)
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Reads datagrams off `socket` on its own thread and hands them to the
+/// main loop.
+fn spawn_reader<F>(socket: UdpSocket, tx: Sender<Incoming>, tag: F)
+where
+    F: Fn(SocketAddr, Vec<u8>) -> Incoming + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf = [0; MAX_EDNS_PACKET_SIZE];
+        loop {
+            match socket.recv_from(&mut buf) {
+                Ok((size, source)) => {
+                    if tx.send(tag(source, buf[..size].to_vec())).is_err() {
+                        return;
+                    }
+                }
+                Err(err) => error!("Error receiving; {err}"),
+            }
+        }
+    });
+}
+
+fn main() {
+    let argv: Vec<String> = env::args().collect();
+    let program = argv.first().map(String::as_str).unwrap_or_default();
+    if argv.iter().any(|arg| arg == "--help" || arg == "-h") {
+        return println!("usage: {program} {USAGE}");
+    }
+    let flags = argv.get(1..).unwrap_or_default().to_vec();
+    let cli = parse_args(&flags).unwrap_or_else(|err| match err {
+        ConfigError::Flag { .. } => {
+            eprintln!("{program}: {err}\nusage: {program} {USAGE}");
+            process::exit(2)
+        }
+        _ => {
+            eprintln!("{program}: {err}");
+            process::exit(1)
+        }
+    });
+    let check = cli.check;
+    let args = build(cli.config).unwrap_or_else(|err| {
+        eprintln!("{program}: {err}");
+        process::exit(1)
+    });
+    if check {
+        return println!("configuration OK");
+    }
+    log::logger().configure(args.log);
+    let bind_failed = |addr: &SocketAddr, err: io::Error| -> ! {
+        eprintln!("{program}: couldn't listen on {addr}; {err}");
+        process::exit(1)
+    };
+    let (udp_sockets, tcp_listeners): (Vec<UdpSocket>, Vec<TcpListener>) = args
+        .listen
+        .iter()
+        .map(
+            |addr| match (UdpSocket::bind(addr), TcpListener::bind(addr)) {
+                (Ok(udp_socket), Ok(tcp_listener)) => (udp_socket, tcp_listener),
+                (Err(err), _) | (_, Err(err)) => bind_failed(addr, err),
+            },
+        )
+        .unzip();
+    let admin_listener = args
+        .admin
+        .map(|addr| TcpListener::bind(addr).unwrap_or_else(|err| bind_failed(&addr, err)));
+    let metrics_listener = args
+        .metrics
+        .map(|addr| TcpListener::bind(addr).unwrap_or_else(|err| bind_failed(&addr, err)));
+    if let Some(output) = args.dnstap.clone() {
+        dnstap::start(output).unwrap_or_else(|err| {
+            eprintln!("{program}: couldn't open the dnstap output; {err}");
+            process::exit(1)
+        });
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let upstream_sockets = SocketPool::new(args.upstream_sockets);
+    bind_upstream_sockets(&upstream_sockets, &args.upstream, &tx).unwrap_or_else(|err| {
+        eprintln!("{program}: couldn't bind the upstream sockets; {err}");
+        process::exit(1)
+    });
+    let clone = |socket: &UdpSocket| socket.try_clone().expect("Failed to clone socket");
+    for (idx, socket) in udp_sockets.iter().enumerate() {
+        spawn_reader(clone(socket), tx.clone(), move |source, msg| {
+            Incoming::Client(idx, source, msg)
+        });
+    }
+    let reloader = Reloader {
+        flags,
+        listen: args.listen,
+        workers: args.workers,
+        upstream_sockets: args.upstream_sockets,
+        admin: args.admin,
+        metrics: args.metrics,
+        dnstap: args.dnstap.clone(),
+        tx,
+    };
+    let (blocking_tx, blocking_rx) = mpsc::sync_channel(BLOCKING_QUEUE);
+    let forwarder = Arc::new(Forwarder {
+        listeners: udp_sockets,
+        upstream_sockets,
+        state: RwLock::new(Arc::new(State {
+            upstream: args.upstream,
+            zones: args.zones,
+            acl: args.acl,
+        })),
+        transcriber: Transcriber::new(args.retry_policy),
+        cache: Mutex::new(Cache::new(args.cache_size)),
+        dropped: Dropped::default(),
+        blocking: blocking_tx,
+    });
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..args.workers {
+        let (forwarder, rx) = (Arc::clone(&forwarder), Arc::clone(&rx));
+        thread::spawn(move || work(&forwarder, &rx));
+    }
+    let blocking_rx = Arc::new(Mutex::new(blocking_rx));
+    for _ in 0..BLOCKING_THREADS {
+        let (forwarder, rx) = (Arc::clone(&forwarder), Arc::clone(&blocking_rx));
+        thread::spawn(move || work_blocking(&forwarder, &rx));
+    }
+
+    // shared by every listener, so they're caps on the server as a whole
+    let tcp_limits = TcpLimits::default();
+    for tcp_listener in tcp_listeners {
+        let tcp_forwarder = Arc::clone(&forwarder);
+        let local = tcp_listener.local_addr().ok();
+        let handler = Arc::new(move |source: SocketAddr, query: Vec<u8>| {
+            let received = Instant::now();
+            let tap = |kind, message: &[u8]| {
+                dnstap::tap(|| Event {
+                    kind,
+                    protocol: Protocol::Tcp,
+                    query_address: Some(source),
+                    response_address: local,
+                    message,
+                })
+            };
+            tap(MessageType::ClientQuery, &query);
+            let (reply, upstream, cache_hit) = if tcp_forwarder.state().acl.permits(source.ip()) {
+                tcp_forwarder.answer_over_tcp(source, &query)?
+            } else {
+                (
+                    error_reply(&query, ResponseCode::Refused, None)?,
+                    None,
+                    false,
+                )
+            };
+            tap(MessageType::ClientResponse, &reply);
+            report(QueryRecord {
+                upstream,
+                cache_hit,
+                ..QueryRecord::from_response(source, Protocol::Tcp, &reply, received)
+            });
+            Some(reply)
+        });
+        let limits = tcp_limits.clone();
+        thread::spawn(move || serve_tcp(tcp_listener, handler, limits));
+    }
+    if let Some(admin_listener) = admin_listener {
+        let forwarder = Arc::clone(&forwarder);
+        thread::spawn(move || serve_admin(admin_listener, &forwarder, reloader));
+    }
+    if let Some(metrics_listener) = metrics_listener {
+        let forwarder = Arc::clone(&forwarder);
+        thread::spawn(move || serve_metrics(metrics_listener, &forwarder));
+    }
+    {
+        let forwarder = Arc::clone(&forwarder);
+        thread::spawn(move || probe_upstreams(&forwarder));
+    }
+
+    // upstream deadlines are kept on this thread
+    loop {
+        forwarder.transcriber.wait_for_deadline();
+        forwarder.expire();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Write},
+        net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket},
+        sync::{atomic::Ordering, mpsc, Arc, Mutex, RwLock},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use dns_starter_rust::{
+        acl::Acl,
+        buffer::MAX_EDNS_PACKET_SIZE,
+        cache::Cache,
+        converter::transcribe::{RetryPolicy, Transcriber},
+        edns::{Edns, BADVERS},
+        header::ResponseCode,
+        message::Message,
+        recursive::Recursor,
+        section::{Class, RData, SectionGroup, Type},
+        transport::SocketPool,
+        upstream::{Routes, Strategy, Upstreams},
+        zone::Zones,
+    };
+
+    use super::{
+        bind_upstream_sockets, read_request_head, work, work_blocking, Dropped, Forwarder,
+        Incoming, State, Upstream, METRICS_MAX_REQUEST,
+    };
+
+    fn a_record(name: &str, addr: Ipv4Addr) -> SectionGroup {
+        SectionGroup::new(
+            name.split('.').map(str::to_owned).collect(),
+            Type::A,
+            Class::In,
+            Some((300, RData::A(addr))),
+        )
+    }
+
+    /// A forwarder to `upstream` with one listening and one upstream socket,
+    /// and a worker handling what comes in on the upstream one.
+    fn forwarder(upstream: SocketAddr) -> Arc<Forwarder> {
+        forwarder_for(Upstream::Resolver(Routes::new(
+            Upstreams::new(vec![upstream], Strategy::RoundRobin),
+            Vec::new(),
+        )))
+    }
+
+    /// Like [`forwarder`], for any kind of upstream, with a blocking thread.
+    fn forwarder_for(upstream: Upstream) -> Arc<Forwarder> {
+        let (blocking, blocking_rx) = mpsc::sync_channel(1);
+        let forwarder = Arc::new(Forwarder {
+            listeners: vec![UdpSocket::bind("127.0.0.1:0").unwrap()],
+            upstream_sockets: SocketPool::new(1),
+            state: RwLock::new(Arc::new(State {
+                upstream,
+                zones: Zones::new(Vec::new()),
+                acl: Acl::new(Vec::new(), Vec::new()),
+            })),
+            transcriber: Transcriber::default(),
+            cache: Mutex::new(Cache::new(16)),
+            dropped: Dropped::default(),
+            blocking,
+        });
+        let worker = Arc::clone(&forwarder);
+        thread::spawn(move || work_blocking(&worker, &Mutex::new(blocking_rx)));
+        let (tx, rx) = mpsc::channel();
+        bind_upstream_sockets(
+            &forwarder.upstream_sockets,
+            &forwarder.state().upstream,
+            &tx,
+        )
+        .unwrap();
+        let worker = Arc::clone(&forwarder);
+        thread::spawn(move || work(&worker, &Mutex::new(rx)));
+        forwarder
+    }
+
+    /// What arrives on `socket` within a short while, if anything.
+    fn receive(socket: &UdpSocket) -> Option<Message> {
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        let mut buf = [0; MAX_EDNS_PACKET_SIZE];
+        match socket.recv(&mut buf) {
+            Ok(size) => Some(Message::try_from(&buf[..size]).unwrap()),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                None
             }
+            Err(err) => panic!("{err}"),
         }
     }
+
+    /// An upstream that answers each query twice: first with its txid but
+    /// another question, then properly.
+    fn spawn_forging_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_EDNS_PACKET_SIZE];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let query = Message::try_from(&buf[..size]).unwrap();
+                let forged = Message::query("evil.test", Type::A).with_txid(query.txid());
+                let forged = Message::response_to(&forged)
+                    .with_answer(a_record("evil.test", Ipv4Addr::new(203, 0, 113, 1)));
+                let real = Message::response_to(&query)
+                    .with_answer(a_record("example.test", Ipv4Addr::new(192, 0, 2, 1)));
+                for reply in [forged, real] {
+                    socket
+                        .send_to(&Vec::<u8>::try_from(reply).unwrap(), source)
+                        .unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_tcp_query_ignores_reply_to_another_question() {
+        let upstream = spawn_forging_upstream();
+        let forwarder = forwarder(upstream);
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let query = Message::query("example.test", Type::A).with_txid(0x1234);
+
+        let (reply, asked, cache_hit) = forwarder
+            .answer_over_tcp(client, &Vec::<u8>::try_from(query).unwrap())
+            .unwrap();
+        let reply = Message::try_from(&reply[..]).unwrap();
+        assert_eq!(reply.txid(), 0x1234);
+        assert_eq!(
+            reply.answers(),
+            &vec![a_record("example.test", Ipv4Addr::new(192, 0, 2, 1))]
+        );
+        assert_eq!((asked, cache_hit), (Some(upstream), false));
+        assert_eq!(forwarder.dropped.unmatched.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_dropped_packets_are_counted_and_not_answered() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let forwarder = forwarder(upstream_addr);
+        forwarder
+            .transcriber
+            .set_policy(RetryPolicy::new(Duration::from_millis(10), 0));
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let counts = || {
+            let dropped = &forwarder.dropped;
+            [&dropped.unmatched, &dropped.malformed, &dropped.unsolicited]
+                .map(|counter| counter.load(Ordering::Relaxed))
+        };
+
+        // a response sent to the socket clients talk to
+        let query = Message::query("example.test", Type::A);
+        let response = Vec::<u8>::try_from(Message::response_to(&query)).unwrap();
+        forwarder.handle(Incoming::Client(0, client_addr, response.clone()));
+        assert_eq!(counts(), [0, 0, 1]);
+        assert!(receive(&client).is_none());
+
+        // garbage from the upstream
+        forwarder.handle(Incoming::Upstream(0, upstream_addr, vec![0xab; 7]));
+        assert_eq!(counts(), [0, 1, 1]);
+
+        // an answer that comes in after its query timed out
+        let query = Vec::<u8>::try_from(query).unwrap();
+        forwarder.handle(Incoming::Client(0, client_addr, query));
+        let forwarded = receive(&upstream).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        forwarder.expire();
+        assert_eq!(
+            receive(&client).unwrap().rcode(),
+            &ResponseCode::ServerFailure
+        );
+        let late = Vec::<u8>::try_from(Message::response_to(&forwarded)).unwrap();
+        forwarder.handle(Incoming::Upstream(0, upstream_addr, late));
+        assert_eq!(counts(), [1, 1, 1]);
+        assert!(receive(&client).is_none());
+        assert!(receive(&upstream).is_none());
+    }
+
+    #[test]
+    fn test_bad_opt_records_are_refused() {
+        let forwarder = forwarder(
+            UdpSocket::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap(),
+        );
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let ask = |query: Message| {
+            let (reply, _, _) = forwarder
+                .answer_over_tcp(client, &Vec::<u8>::try_from(query).unwrap())
+                .unwrap();
+            Message::try_from(&reply[..]).unwrap()
+        };
+
+        let query = Message::query("example.test", Type::A);
+        let reply = ask(query
+            .clone()
+            .with_edns(Edns::new(1232, 0, 1, true, Vec::new())));
+        assert_eq!(reply.rcode(), &ResponseCode::None);
+        let edns = reply.edns().unwrap();
+        assert_eq!((edns.extended_rcode(), edns.version()), (BADVERS, 0));
+
+        let named_opt = SectionGroup::new(
+            vec!["example".to_owned()],
+            Type::Opt,
+            Class::from(1232),
+            Some((0, RData::Opt(Vec::new()))),
+        );
+        let reply = ask(query.with_additional([named_opt]));
+        assert_eq!(reply.rcode(), &ResponseCode::Format);
+    }
+
+    #[test]
+    fn test_upstream_extended_rcode_is_passed_on() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let upstream = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_EDNS_PACKET_SIZE];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let query = Message::try_from(&buf[..size]).unwrap();
+                let reply = Message::response_to(&query).with_edns(Edns::new(
+                    1232,
+                    3,
+                    0,
+                    false,
+                    Vec::new(),
+                ));
+                socket
+                    .send_to(&Vec::<u8>::try_from(reply).unwrap(), source)
+                    .unwrap();
+            }
+        });
+        let forwarder = forwarder(upstream);
+        let client = SocketAddr::from(([127, 0, 0, 1], 40000));
+        let ask = |query: Message| {
+            let (reply, _, _) = forwarder
+                .answer_over_tcp(client, &Vec::<u8>::try_from(query).unwrap())
+                .unwrap();
+            Message::try_from(&reply[..]).unwrap()
+        };
+
+        let query = Message::query("example.test", Type::A);
+        let reply = ask(query
+            .clone()
+            .with_edns(Edns::new(1232, 0, 0, false, Vec::new())));
+        assert_eq!(reply.rcode(), &ResponseCode::None);
+        assert_eq!(reply.edns().unwrap().extended_rcode(), 3);
+        // it can't be told without EDNS, and isn't cached either
+        assert_eq!(ask(query).rcode(), &ResponseCode::ServerFailure);
+    }
+
+    #[test]
+    fn test_metrics_requests_are_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let read = |request: Vec<u8>, pause| {
+            let mut client = TcpStream::connect(addr).unwrap();
+            let (mut stream, _) = listener.accept().unwrap();
+            thread::spawn(move || {
+                for byte in request {
+                    // the server may have given up already
+                    if client.write_all(&[byte]).is_err() {
+                        break;
+                    }
+                    thread::sleep(pause);
+                }
+            });
+            read_request_head(&mut stream, Instant::now() + Duration::from_millis(200))
+        };
+
+        let request = b"GET /metrics HTTP/1.1\r\nHost: a\r\n\r\n".to_vec();
+        assert_eq!(read(request.clone(), Duration::ZERO).unwrap(), request);
+        let long = [&b"GET /"[..], &[b'a'; METRICS_MAX_REQUEST]].concat();
+        assert_eq!(
+            read(long, Duration::ZERO).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        // a byte at a time doesn't keep the connection open past the deadline
+        assert_eq!(
+            read(request, Duration::from_millis(50)).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn test_slow_resolution_doesnt_hold_up_the_worker() {
+        let silent_root = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let forwarder = forwarder_for(Upstream::Recursive(Recursor::new(vec![silent_root
+            .local_addr()
+            .unwrap()])));
+        let cached = a_record("cached.test", Ipv4Addr::new(192, 0, 2, 1));
+        forwarder.cache_answer(
+            &cached,
+            &ResponseCode::None,
+            std::slice::from_ref(&cached),
+            &[],
+        );
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client_addr = client.local_addr().unwrap();
+        let query = |name| Vec::<u8>::try_from(Message::query(name, Type::A)).unwrap();
+
+        let started = Instant::now();
+        forwarder.handle(Incoming::Client(0, client_addr, query("slow.test")));
+        forwarder.handle(Incoming::Client(0, client_addr, query("cached.test")));
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(receive(&client).unwrap().answers(), &vec![cached]);
+
+        // with the blocking thread busy and its queue full, there's no
+        // waiting for a turn either
+        forwarder.handle(Incoming::Client(0, client_addr, query("slow.test")));
+        forwarder.handle(Incoming::Client(0, client_addr, query("slow.test")));
+        assert_eq!(
+            receive(&client).unwrap().rcode(),
+            &ResponseCode::ServerFailure
+        );
+    }
 }
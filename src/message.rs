@@ -0,0 +1,384 @@
+use crate::{
+    buffer::{UdpBuffer, DNS_HEADER_SIZE},
+    edns::Edns,
+    error::ParseError,
+    header::{
+        AuthAnswer, DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, OpCode,
+        QueryResponse, RecursionAvailablity, RecursionDesired, ResponseCode, SectionCount,
+        Truncation,
+    },
+    section::{Class, Section, SectionGroup, Type},
+    writer::MessageWriter,
+};
+
+/// A whole DNS message. There are no section counts to keep in step: the
+/// header's are derived from the sections whenever it's asked for or the
+/// message is written out.
+///
+/// Messages are put together with the `with_*` methods, e.g.
+/// `Message::response_to(&query).with_answer(record)`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    txid: u16,
+    qr: QueryResponse,
+    opcode: OpCode,
+    aa: AuthAnswer,
+    tc: Truncation,
+    rd: RecursionDesired,
+    ra: RecursionAvailablity,
+    rcode: ResponseCode,
+    questions: Vec<SectionGroup>,
+    answers: Vec<SectionGroup>,
+    authority: Vec<SectionGroup>,
+    additional: Vec<SectionGroup>,
+}
+
+impl Message {
+    /// An empty standard query with every flag clear.
+    pub fn new(txid: u16) -> Self {
+        Self {
+            txid,
+            qr: QueryResponse::Query,
+            opcode: OpCode::Query,
+            aa: AuthAnswer::NotAuthoritative,
+            tc: Truncation::NotTruncated,
+            rd: RecursionDesired::DontWantRecursion,
+            ra: RecursionAvailablity::NoRecursionAvailable,
+            rcode: ResponseCode::None,
+            questions: Vec::new(),
+            answers: Vec::new(),
+            authority: Vec::new(),
+            additional: Vec::new(),
+        }
+    }
+
+    /// A recursive query for `name` in class IN under a random txid.
+    pub fn query(name: &str, qtype: Type) -> Self {
+        let domain = name
+            .split('.')
+            .filter(|label| !label.is_empty())
+            .map(|label| label.to_owned())
+            .collect();
+        Self::new(rand::random())
+            .with_recursion_desired(RecursionDesired::IWantRecursion)
+            .with_question(SectionGroup::new(domain, qtype, Class::In, None))
+    }
+
+    /// An empty response to `query`, echoing its txid, opcode, RD flag and
+    /// questions.
+    pub fn response_to(query: &Message) -> Self {
+        Self {
+            qr: QueryResponse::Response,
+            opcode: query.opcode.clone(),
+            rd: query.rd.clone(),
+            questions: query.questions.clone(),
+            ..Self::new(query.txid)
+        }
+    }
+
+    /// A reply with `rcode` to a query we can't parse or won't handle,
+    /// echoing what can be read of it: the txid, opcode and RD flag, and
+    /// the first question if that much parses. `None` if there isn't a
+    /// whole header or the message is itself a response.
+    pub fn error_reply(query: &[u8], rcode: ResponseCode) -> Option<Self> {
+        let header = query.get(..DNS_HEADER_SIZE)?;
+        if QueryResponse::try_from(header[2] >> 7).ok()? == QueryResponse::Response {
+            return None;
+        }
+        let reply = Self {
+            qr: QueryResponse::Response,
+            opcode: OpCode::try_from((header[2] >> 3) & 0x0F).ok()?,
+            rd: RecursionDesired::try_from(header[2] & 0x01).ok()?,
+            rcode,
+            ..Self::new(u16::from_be_bytes([header[0], header[1]]))
+        };
+        Some(match UdpBuffer::new(query).first_question() {
+            Some(question) => reply.with_question(question),
+            None => reply,
+        })
+    }
+
+    pub fn with_txid(mut self, txid: u16) -> Self {
+        self.txid = txid;
+        self
+    }
+
+    pub fn with_opcode(mut self, opcode: OpCode) -> Self {
+        self.opcode = opcode;
+        self
+    }
+
+    pub fn with_authoritative(mut self, aa: AuthAnswer) -> Self {
+        self.aa = aa;
+        self
+    }
+
+    pub fn with_truncation(mut self, tc: Truncation) -> Self {
+        self.tc = tc;
+        self
+    }
+
+    pub fn with_recursion_desired(mut self, rd: RecursionDesired) -> Self {
+        self.rd = rd;
+        self
+    }
+
+    pub fn with_recursion_available(mut self, ra: RecursionAvailablity) -> Self {
+        self.ra = ra;
+        self
+    }
+
+    pub fn with_rcode(mut self, rcode: ResponseCode) -> Self {
+        self.rcode = rcode;
+        self
+    }
+
+    pub fn with_question(mut self, question: SectionGroup) -> Self {
+        self.questions.push(question);
+        self
+    }
+
+    pub fn with_answer(mut self, record: SectionGroup) -> Self {
+        self.answers.push(record);
+        self
+    }
+
+    pub fn with_answers(mut self, records: impl IntoIterator<Item = SectionGroup>) -> Self {
+        self.answers.extend(records);
+        self
+    }
+
+    pub fn with_authority(mut self, records: impl IntoIterator<Item = SectionGroup>) -> Self {
+        self.authority.extend(records);
+        self
+    }
+
+    pub fn with_additional(mut self, records: impl IntoIterator<Item = SectionGroup>) -> Self {
+        self.additional.extend(records);
+        self
+    }
+
+    /// Adds an OPT record, replacing any there already.
+    pub fn with_edns(mut self, edns: Edns) -> Self {
+        self.additional
+            .retain(|group| group.group_type() != &Type::Opt);
+        self.additional.push(SectionGroup::from(edns));
+        self
+    }
+
+    pub fn txid(&self) -> u16 {
+        self.txid
+    }
+
+    pub fn qr(&self) -> &QueryResponse {
+        &self.qr
+    }
+
+    pub fn opcode(&self) -> &OpCode {
+        &self.opcode
+    }
+
+    pub fn aa(&self) -> &AuthAnswer {
+        &self.aa
+    }
+
+    pub fn tc(&self) -> &Truncation {
+        &self.tc
+    }
+
+    pub fn rd(&self) -> &RecursionDesired {
+        &self.rd
+    }
+
+    pub fn ra(&self) -> &RecursionAvailablity {
+        &self.ra
+    }
+
+    pub fn rcode(&self) -> &ResponseCode {
+        &self.rcode
+    }
+
+    pub fn questions(&self) -> &Vec<SectionGroup> {
+        &self.questions
+    }
+
+    pub fn answers(&self) -> &Vec<SectionGroup> {
+        &self.answers
+    }
+
+    pub fn authority(&self) -> &Vec<SectionGroup> {
+        &self.authority
+    }
+
+    pub fn additional(&self) -> &Vec<SectionGroup> {
+        &self.additional
+    }
+
+    /// The message's EDNS parameters, if it has a well-formed OPT record.
+    pub fn edns(&self) -> Option<Edns> {
+        self.additional
+            .iter()
+            .find(|group| group.group_type() == &Type::Opt)
+            .and_then(|group| Edns::try_from(group).ok())
+    }
+
+    /// The header as it goes on the wire, counts included.
+    pub fn header(&self) -> Result<DnsHeader, ParseError> {
+        let count = |groups: &Vec<SectionGroup>| {
+            u16::try_from(groups.len()).map_err(|_| ParseError::ConversionError)
+        };
+        Ok(DnsHeader::new(
+            self.txid,
+            HeaderSecondRowFirstHalf::new(
+                self.qr.clone(),
+                self.opcode.clone(),
+                self.aa.clone(),
+                self.tc.clone(),
+                self.rd.clone(),
+            ),
+            HeaderSecondRowSecondHalf::new(self.ra.clone(), 0, self.rcode.clone())?,
+            SectionCount::new(
+                count(&self.questions)?,
+                count(&self.answers)?,
+                count(&self.authority)?,
+                count(&self.additional)?,
+            ),
+        ))
+    }
+}
+
+impl TryFrom<&[u8]> for Message {
+    type Error = ParseError;
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        let (header, [qsection, ansection, nssection, arsection]) =
+            UdpBuffer::new(value).unpack()?;
+        let groups =
+            |section: Option<Section>| section.map(|section| section.groups).unwrap_or_default();
+        let (fh, sh) = (header.header_first_half(), header.header_second_half());
+        Ok(Self {
+            txid: header.txid(),
+            qr: fh.qr().clone(),
+            opcode: fh.opcode().clone(),
+            aa: fh.aa().clone(),
+            tc: fh.tc().clone(),
+            rd: fh.rd().clone(),
+            ra: sh.ra().clone(),
+            rcode: sh.rcode().clone(),
+            questions: groups(qsection),
+            answers: groups(ansection),
+            authority: groups(nssection),
+            additional: groups(arsection),
+        })
+    }
+}
+
+impl TryFrom<Message> for Vec<u8> {
+    type Error = ParseError;
+
+    fn try_from(value: Message) -> Result<Self, Self::Error> {
+        let mut writer = MessageWriter::new();
+        writer.write_header(value.header()?);
+        value
+            .questions
+            .iter()
+            .chain(value.answers.iter())
+            .chain(value.authority.iter())
+            .chain(value.additional.iter())
+            .try_for_each(|group| writer.write_section_group(group))?;
+        Ok(writer.into_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        edns::Edns,
+        header::{OpCode, QueryResponse, RecursionDesired, ResponseCode},
+        section::{Class, RData, SectionGroup, Type},
+    };
+
+    use super::Message;
+
+    #[test]
+    fn test_query_round_trip() {
+        let query = Message::query("example.com.", Type::A).with_edns(Edns::new(
+            1232,
+            0,
+            0,
+            true,
+            Vec::new(),
+        ));
+        let bytes = Vec::<u8>::try_from(query.clone()).unwrap();
+        // one question and the OPT record, whatever went in before
+        assert_eq!(bytes[4..12], [0, 1, 0, 0, 0, 0, 0, 1]);
+
+        let parsed = Message::try_from(bytes.as_slice()).unwrap();
+        assert_eq!(parsed, query);
+        assert_eq!(parsed.rd(), &RecursionDesired::IWantRecursion);
+        assert_eq!(
+            parsed.questions()[0].domain(),
+            &vec!["example".to_owned(), "com".to_owned()]
+        );
+        assert!(parsed.edns().is_some_and(|edns| edns.dnssec_ok()));
+    }
+
+    #[test]
+    fn test_response_to_echoes_query_and_counts_sections() {
+        let query = Message::query("example.com", Type::A);
+        let record = SectionGroup::new(
+            query.questions()[0].domain().clone(),
+            Type::A,
+            Class::In,
+            Some((300, RData::A(Ipv4Addr::new(192, 0, 2, 1)))),
+        );
+        let response = Message::response_to(&query)
+            .with_rcode(ResponseCode::None)
+            .with_answers(vec![record.clone(), record]);
+        assert_eq!(response.txid(), query.txid());
+        assert_eq!(response.qr(), &QueryResponse::Response);
+        assert_eq!(response.questions(), query.questions());
+
+        let header = response.header().unwrap();
+        assert_eq!(header.counts().qdcount(), 1);
+        assert_eq!(header.counts().ancount(), 2);
+        let parsed = Message::try_from(Vec::<u8>::try_from(response).unwrap().as_slice()).unwrap();
+        assert_eq!(parsed.answers().len(), 2);
+    }
+
+    #[test]
+    fn test_error_reply_salvages_header_and_question() {
+        let query = Message::query("example.com", Type::A).with_opcode(OpCode::Status);
+        let mut bytes = Vec::<u8>::try_from(query.clone()).unwrap();
+        // claims an answer that isn't there
+        bytes[7] = 1;
+        let reply = Message::error_reply(&bytes, ResponseCode::Format).unwrap();
+        assert_eq!(reply.txid(), query.txid());
+        assert_eq!(reply.qr(), &QueryResponse::Response);
+        assert_eq!(reply.opcode(), &OpCode::Status);
+        assert_eq!(reply.rd(), &RecursionDesired::IWantRecursion);
+        assert_eq!(reply.rcode(), &ResponseCode::Format);
+        assert_eq!(reply.questions(), query.questions());
+
+        // opcodes we don't know are echoed as they came
+        let mut notify = bytes.clone();
+        notify[2] = (notify[2] & 0b1000_0111) | 4 << 3;
+        let reply = Message::error_reply(&notify, ResponseCode::NotImplemented).unwrap();
+        assert_eq!(reply.opcode(), &OpCode::FutureUse(4));
+        let reply = Vec::<u8>::try_from(reply).unwrap();
+        assert_eq!(reply[2] & 0b0111_1000, 4 << 3);
+
+        // a question that doesn't parse is left out
+        let mut looped = bytes[..12].to_vec();
+        looped.extend([0xC0, 12, 0, 1, 0, 1]);
+        let reply = Message::error_reply(&looped, ResponseCode::Format).unwrap();
+        assert!(reply.questions().is_empty());
+
+        assert!(Message::error_reply(&bytes[..11], ResponseCode::Format).is_none());
+        let response = Message::response_to(&query);
+        let response = Vec::<u8>::try_from(response).unwrap();
+        assert!(Message::error_reply(&response, ResponseCode::Format).is_none());
+    }
+}
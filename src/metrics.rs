@@ -0,0 +1,342 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use crate::{
+    error::ParseError,
+    header::{OpCode, ResponseCode},
+    log::{Protocol, QueryRecord},
+    section::Type,
+};
+
+/// Upper bounds, in seconds, of the buckets response latencies are counted in.
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Responses counted by their question type, opcode and response code.
+type QueryKey = (String, String, String);
+
+#[derive(Debug, Default, Clone)]
+struct Histogram {
+    /// How many observations fell in each bucket; the last one is `+Inf`.
+    buckets: [u64; LATENCY_BUCKETS.len() + 1],
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, latency: Duration) {
+        let secs = latency.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket] += 1;
+        self.sum += secs;
+    }
+}
+
+/// Counters for what the server has been doing, rendered in the Prometheus
+/// text format.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    queries: Mutex<BTreeMap<QueryKey, u64>>,
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    upstream_timeouts: Mutex<BTreeMap<SocketAddr, u64>>,
+    parse_failures: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<BTreeMap<Protocol, Histogram>>,
+}
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// The metrics the server counts into.
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::default)
+}
+
+impl Metrics {
+    /// Counts a response we sent and how long it took.
+    pub fn query(&self, record: &QueryRecord) {
+        let label = |value: Option<String>| value.unwrap_or_else(|| "none".to_owned());
+        let key = (
+            label(record.qtype.as_ref().map(qtype_label)),
+            label(record.opcode.as_ref().map(OpCode::to_string)),
+            label(record.rcode.as_ref().map(ResponseCode::to_string)),
+        );
+        *lock(&self.queries).entry(key).or_default() += 1;
+        lock(&self.latency)
+            .entry(record.protocol)
+            .or_default()
+            .observe(record.latency);
+    }
+
+    pub fn cache_lookup(&self, hit: bool) {
+        let counter = match hit {
+            true => &self.cache_hits,
+            false => &self.cache_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn upstream_timeout(&self, upstream: SocketAddr) {
+        *lock(&self.upstream_timeouts).entry(upstream).or_default() += 1;
+    }
+
+    pub fn parse_failure(&self, err: &ParseError) {
+        *lock(&self.parse_failures).entry(err.kind()).or_default() += 1;
+    }
+
+    /// Everything counted so far, plus the number of queries waiting on an
+    /// upstream, in the Prometheus text exposition format.
+    pub fn render(&self, pending: usize) -> String {
+        let mut out = String::new();
+        header(&mut out, "dns_queries_total", "counter", "Responses sent.");
+        for ((qtype, opcode, rcode), count) in lock(&self.queries).iter() {
+            let _ = writeln!(
+                out,
+                r#"dns_queries_total{{qtype="{qtype}",opcode="{opcode}",rcode="{rcode}"}} {count}"#
+            );
+        }
+
+        header(
+            &mut out,
+            "dns_cache_lookups_total",
+            "counter",
+            "Questions looked up in the cache.",
+        );
+        for (result, counter) in [("hit", &self.cache_hits), ("miss", &self.cache_misses)] {
+            let _ = writeln!(
+                out,
+                r#"dns_cache_lookups_total{{result="{result}"}} {}"#,
+                counter.load(Ordering::Relaxed)
+            );
+        }
+
+        header(
+            &mut out,
+            "dns_upstream_timeouts_total",
+            "counter",
+            "Queries an upstream resolver didn't answer in time.",
+        );
+        for (upstream, count) in lock(&self.upstream_timeouts).iter() {
+            let _ = writeln!(
+                out,
+                r#"dns_upstream_timeouts_total{{upstream="{upstream}"}} {count}"#
+            );
+        }
+
+        header(
+            &mut out,
+            "dns_parse_failures_total",
+            "counter",
+            "Messages that couldn't be parsed, by error.",
+        );
+        for (kind, count) in lock(&self.parse_failures).iter() {
+            let _ = writeln!(out, r#"dns_parse_failures_total{{error="{kind}"}} {count}"#);
+        }
+
+        header(
+            &mut out,
+            "dns_pending_queries",
+            "gauge",
+            "Queries waiting on an upstream answer.",
+        );
+        let _ = writeln!(out, "dns_pending_queries {pending}");
+
+        header(
+            &mut out,
+            "dns_response_latency_seconds",
+            "histogram",
+            "Time from receiving a query to sending its response.",
+        );
+        for (protocol, histogram) in lock(&self.latency).iter() {
+            let mut cumulative = 0;
+            let bounds = LATENCY_BUCKETS
+                .iter()
+                .map(f64::to_string)
+                .chain(["+Inf".to_owned()]);
+            for (bound, count) in bounds.zip(histogram.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    r#"dns_response_latency_seconds_bucket{{protocol="{protocol}",le="{bound}"}} {cumulative}"#
+                );
+            }
+            let _ = writeln!(
+                out,
+                r#"dns_response_latency_seconds_sum{{protocol="{protocol}"}} {}"#,
+                histogram.sum
+            );
+            let _ = writeln!(
+                out,
+                r#"dns_response_latency_seconds_count{{protocol="{protocol}"}} {cumulative}"#
+            );
+        }
+        out
+    }
+}
+
+/// The qtype label for a question: types we have no name for all share
+/// "other", so clients can't grow the label set one code at a time.
+fn qtype_label(qtype: &Type) -> String {
+    match qtype {
+        Type::Unknown(_) => "other".to_owned(),
+        named => named.to_string(),
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex.lock().expect("metrics lock poisoned")
+}
+
+/// The HTTP/1.1 response to a request with this request line: the metrics
+/// for `GET /metrics`, and an error for anything else.
+pub fn http_response(request_line: &str, render: impl FnOnce() -> String) -> String {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next());
+    let path = target.map(|target| target.split('?').next().unwrap_or_default());
+    let (status, content_type, body, extra) = match (method, path) {
+        ("GET", Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4; charset=utf-8",
+            render(),
+            "",
+        ),
+        ("GET", Some(_)) => ("404 Not Found", "text/plain", "not found\n".to_owned(), ""),
+        (_, Some(_)) => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "method not allowed\n".to_owned(),
+            "Allow: GET\r\n",
+        ),
+        (_, None) => (
+            "400 Bad Request",
+            "text/plain",
+            "bad request\n".to_owned(),
+            "",
+        ),
+    };
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n{extra}Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use crate::{
+        error::ParseError,
+        header::{OpCode, ResponseCode},
+        log::{Protocol, QueryRecord},
+        section::Type,
+    };
+
+    use super::{http_response, Metrics};
+
+    #[test]
+    fn test_render_counters_and_histogram() {
+        let metrics = Metrics::default();
+        let record = QueryRecord {
+            client: SocketAddr::from(([127, 0, 0, 1], 40000)),
+            protocol: Protocol::Udp,
+            txid: 1,
+            opcode: Some(OpCode::Query),
+            qname: Some("example.com.".to_owned()),
+            qtype: Some(Type::A),
+            rcode: Some(ResponseCode::None),
+            answers: 1,
+            upstream: None,
+            latency: Duration::from_millis(3),
+            cache_hit: true,
+        };
+        metrics.query(&record);
+        metrics.query(&QueryRecord {
+            latency: Duration::from_secs(10),
+            ..record
+        });
+        metrics.cache_lookup(true);
+        metrics.upstream_timeout(SocketAddr::from(([192, 0, 2, 1], 53)));
+        metrics.parse_failure(&ParseError::NameTooLong { offset: 12 });
+
+        let text = metrics.render(3);
+        for line in [
+            r#"dns_queries_total{qtype="A",opcode="QUERY",rcode="NOERROR"} 2"#,
+            r#"dns_cache_lookups_total{result="hit"} 1"#,
+            r#"dns_cache_lookups_total{result="miss"} 0"#,
+            r#"dns_upstream_timeouts_total{upstream="192.0.2.1:53"} 1"#,
+            r#"dns_parse_failures_total{error="NameTooLong"} 1"#,
+            "dns_pending_queries 3",
+            r#"dns_response_latency_seconds_bucket{protocol="udp",le="0.0025"} 0"#,
+            r#"dns_response_latency_seconds_bucket{protocol="udp",le="0.005"} 1"#,
+            r#"dns_response_latency_seconds_bucket{protocol="udp",le="2.5"} 1"#,
+            r#"dns_response_latency_seconds_bucket{protocol="udp",le="+Inf"} 2"#,
+            r#"dns_response_latency_seconds_count{protocol="udp"} 2"#,
+            "# TYPE dns_response_latency_seconds histogram",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {line:?} in\n{text}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_unnamed_qtypes_share_a_label() {
+        let metrics = Metrics::default();
+        for qtype in [Type::Aaaa, Type::Unknown(999), Type::Unknown(65280)] {
+            metrics.query(&QueryRecord {
+                client: SocketAddr::from(([127, 0, 0, 1], 40000)),
+                protocol: Protocol::Udp,
+                txid: 1,
+                opcode: Some(OpCode::Query),
+                qname: Some("example.com.".to_owned()),
+                qtype: Some(qtype),
+                rcode: Some(ResponseCode::None),
+                answers: 0,
+                upstream: None,
+                latency: Duration::from_millis(3),
+                cache_hit: false,
+            });
+        }
+
+        let text = metrics.render(0);
+        let queries: Vec<_> = text
+            .lines()
+            .filter(|l| l.starts_with("dns_queries_total{"))
+            .collect();
+        assert_eq!(
+            queries,
+            [
+                r#"dns_queries_total{qtype="AAAA",opcode="QUERY",rcode="NOERROR"} 1"#,
+                r#"dns_queries_total{qtype="other",opcode="QUERY",rcode="NOERROR"} 2"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_http_response() {
+        let ok = http_response("GET /metrics HTTP/1.1", || "up 1\n".to_owned());
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.contains("Content-Length: 5\r\n"));
+        assert!(ok.ends_with("\r\n\r\nup 1\n"));
+
+        let render = String::new;
+        assert!(http_response("GET / HTTP/1.1", render).starts_with("HTTP/1.1 404"));
+        assert!(http_response("POST /metrics HTTP/1.1", render).starts_with("HTTP/1.1 405"));
+        assert!(http_response("", render).starts_with("HTTP/1.1 400"));
+    }
+}
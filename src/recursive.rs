@@ -0,0 +1,820 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    buffer::UdpBuffer,
+    edns::{Edns, EDNS_UDP_PAYLOAD_SIZE},
+    error::ResolveError,
+    header::ResponseCode,
+    message::Message,
+    section::{Class, RData, Section, SectionGroup, Type},
+    transport,
+};
+
+/// Response code, answer records and authority records of a finished
+/// resolution, in the same shape as `cache::CachedAnswer`.
+pub type Resolution = (ResponseCode, Vec<SectionGroup>, Vec<SectionGroup>);
+
+/// Response code plus the answer, authority and additional records one
+/// server sent back.
+type ServerResponse = (
+    ResponseCode,
+    Vec<SectionGroup>,
+    Vec<SectionGroup>,
+    Vec<SectionGroup>,
+);
+
+/// a.root-servers.net through m.root-servers.net.
+pub const ROOT_HINTS: [Ipv4Addr; 13] = [
+    Ipv4Addr::new(198, 41, 0, 4),
+    Ipv4Addr::new(170, 247, 170, 2),
+    Ipv4Addr::new(192, 33, 4, 12),
+    Ipv4Addr::new(199, 7, 91, 13),
+    Ipv4Addr::new(192, 203, 230, 10),
+    Ipv4Addr::new(192, 5, 5, 241),
+    Ipv4Addr::new(192, 112, 36, 4),
+    Ipv4Addr::new(198, 97, 190, 53),
+    Ipv4Addr::new(192, 36, 148, 17),
+    Ipv4Addr::new(192, 58, 128, 30),
+    Ipv4Addr::new(193, 0, 14, 129),
+    Ipv4Addr::new(199, 7, 83, 42),
+    Ipv4Addr::new(202, 12, 27, 33),
+];
+
+const MAX_REFERRALS: usize = 16;
+const MAX_CNAME_CHAIN: usize = 8;
+/// How many levels of "resolve the name server's address first" we follow.
+const MAX_DEPTH: usize = 4;
+/// How many zone cuts we remember the name servers of.
+const MAX_DELEGATIONS: usize = 4096;
+/// Longest we trust a referral for, whatever its NS records say.
+const MAX_DELEGATION_TTL: u32 = 86400;
+
+/// Name servers for a zone, learnt from a referral and kept until the
+/// smallest TTL among its NS records runs out.
+#[derive(Debug, Clone)]
+struct Delegation {
+    servers: Vec<SocketAddr>,
+    expires: Instant,
+}
+
+/// Iterative resolver that walks down from the root hints itself instead of
+/// relying on an upstream resolver. Referrals are remembered, so later
+/// queries start at the closest zone cut we know the servers of.
+#[derive(Debug, Clone)]
+pub struct Recursor {
+    root_hints: Vec<SocketAddr>,
+    /// Port the name servers learnt from referrals are asked on.
+    port: u16,
+    /// Name servers by the lowercased name of the zone they serve.
+    delegations: Arc<Mutex<HashMap<Vec<String>, Delegation>>>,
+}
+
+impl Default for Recursor {
+    fn default() -> Self {
+        Self::new(
+            ROOT_HINTS
+                .into_iter()
+                .map(|addr| SocketAddr::new(IpAddr::V4(addr), 53))
+                .collect(),
+        )
+    }
+}
+
+impl Recursor {
+    /// Name servers learnt from referrals are contacted on port 53, whatever
+    /// port the root hints use.
+    pub fn new(root_hints: Vec<SocketAddr>) -> Self {
+        Self {
+            root_hints,
+            port: 53,
+            delegations: Arc::default(),
+        }
+    }
+
+    /// Contacts every name server on `port`, so tests can run a hierarchy of
+    /// servers on an unprivileged one.
+    #[cfg(test)]
+    fn with_port(root_hints: Vec<SocketAddr>, port: u16) -> Self {
+        Self {
+            port,
+            ..Self::new(root_hints)
+        }
+    }
+
+    pub fn root_hints(&self) -> &Vec<SocketAddr> {
+        &self.root_hints
+    }
+
+    pub fn resolve(&self, question: &SectionGroup) -> anyhow::Result<Resolution> {
+        self.resolve_at_depth(
+            question.domain(),
+            question.group_type(),
+            question.class(),
+            0,
+        )
+    }
+
+    fn resolve_at_depth(
+        &self,
+        domain: &[String],
+        group_type: &Type,
+        class: &Class,
+        depth: usize,
+    ) -> anyhow::Result<Resolution> {
+        if depth > MAX_DEPTH {
+            return Err(ResolveError::TooDeep {
+                domain: domain.join("."),
+            }
+            .into());
+        }
+        let mut name = domain.to_vec();
+        let mut answers = Vec::new();
+        // the zone the current servers are authoritative for
+        let (mut zone, mut servers) = self.closest_delegation(&name, Instant::now());
+        let mut cnames = 0;
+        for _ in 0..MAX_REFERRALS {
+            let (rcode, ansection, nssection, arsection) =
+                self.query(&servers, &name, group_type, class)?;
+            // whatever the servers say about names outside their zone, or
+            // about names we didn't ask for, can't be trusted
+            let (target, hops, ansection) = follow_chain(&name, &zone, ansection);
+            let nssection = nssection
+                .into_iter()
+                .filter(|group| is_subdomain(&group.domain, &zone))
+                .collect::<Vec<SectionGroup>>();
+            cnames += hops;
+            if cnames > MAX_CNAME_CHAIN {
+                return Err(ResolveError::CnameChain {
+                    domain: domain.join("."),
+                }
+                .into());
+            }
+            if rcode != ResponseCode::None {
+                answers.extend(ansection);
+                return Ok((rcode, answers, nssection));
+            }
+
+            let answered = ansection
+                .iter()
+                .any(|group| same_name(&group.domain, &target) && &group.group_type == group_type);
+            let followed_cname = !same_name(&target, &name);
+            let has_answers = !ansection.is_empty();
+            answers.extend(ansection);
+            if answered || (has_answers && !followed_cname) {
+                return Ok((ResponseCode::None, answers, Vec::new()));
+            }
+            if followed_cname {
+                // start over for the CNAME target, which may live in another zone
+                name = target;
+                (zone, servers) = self.closest_delegation(&name, Instant::now());
+                continue;
+            }
+
+            let Some(cut) = nssection.iter().find_map(|group| match &group.asection {
+                Some((_, RData::Ns(_))) if is_subdomain(&name, &group.domain) => {
+                    Some(group.domain.clone())
+                }
+                _ => None,
+            }) else {
+                // no answer and no referral: the name exists without this type
+                return Ok((ResponseCode::None, answers, nssection));
+            };
+            if cut.len() <= zone.len() {
+                return Err(ResolveError::LameDelegation {
+                    domain: name.join("."),
+                }
+                .into());
+            }
+            let (ttl, ns_names) = nssection
+                .iter()
+                .filter(|group| same_name(&group.domain, &cut))
+                .filter_map(|group| match &group.asection {
+                    Some((ttl, RData::Ns(ns))) => Some((*ttl, ns.clone())),
+                    _ => None,
+                })
+                .fold(
+                    (MAX_DELEGATION_TTL, Vec::new()),
+                    |(min, mut names), (ttl, ns)| {
+                        names.push(ns);
+                        (min.min(ttl), names)
+                    },
+                );
+            // glue is only believed for servers inside the zone being
+            // delegated; the others have to be looked up
+            let glued = ns_names
+                .iter()
+                .filter(|ns| is_subdomain(ns, &cut))
+                .cloned()
+                .collect::<Vec<Vec<String>>>();
+            servers = self.addresses(&glued, &arsection);
+            if servers.is_empty() {
+                servers = self.lookup_addresses(&ns_names, depth)?;
+            }
+            self.remember(&cut, &servers, ttl, Instant::now());
+            zone = cut;
+        }
+        Err(ResolveError::TooManyReferrals {
+            domain: domain.join("."),
+        }
+        .into())
+    }
+
+    /// The deepest zone above `name` we know the servers of, falling back
+    /// to the root.
+    fn closest_delegation(&self, name: &[String], now: Instant) -> (Vec<String>, Vec<SocketAddr>) {
+        let delegations = self.delegations.lock().expect("delegations lock poisoned");
+        (0..name.len())
+            .find_map(|start| {
+                let zone = &name[start..];
+                delegations
+                    .get(&lowercase(zone))
+                    .filter(|delegation| delegation.expires > now)
+                    .map(|delegation| (zone.to_vec(), delegation.servers.clone()))
+            })
+            .unwrap_or_else(|| (Vec::new(), self.root_hints.clone()))
+    }
+
+    fn remember(&self, zone: &[String], servers: &[SocketAddr], ttl: u32, now: Instant) {
+        if ttl == 0 || servers.is_empty() {
+            return;
+        }
+        let mut delegations = self.delegations.lock().expect("delegations lock poisoned");
+        if delegations.len() >= MAX_DELEGATIONS {
+            delegations.retain(|_, delegation| delegation.expires > now);
+        }
+        if delegations.len() < MAX_DELEGATIONS {
+            delegations.insert(
+                lowercase(zone),
+                Delegation {
+                    servers: servers.to_vec(),
+                    expires: now + Duration::from_secs(u64::from(ttl)),
+                },
+            );
+        }
+    }
+
+    /// Addresses of the `owners` found among `records`.
+    fn addresses(&self, owners: &[Vec<String>], records: &[SectionGroup]) -> Vec<SocketAddr> {
+        records
+            .iter()
+            .filter(|group| owners.iter().any(|owner| same_name(owner, &group.domain)))
+            .filter_map(|group| match &group.asection {
+                Some((_, RData::A(addr))) => Some(SocketAddr::new(IpAddr::V4(*addr), self.port)),
+                Some((_, RData::Aaaa(addr))) => Some(SocketAddr::new(IpAddr::V6(*addr), self.port)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Resolves the referred name servers' addresses ourselves when the
+    /// referral came without glue, falling back to IPv6 for servers without
+    /// an IPv4 address.
+    fn lookup_addresses(
+        &self,
+        ns_names: &[Vec<String>],
+        depth: usize,
+    ) -> anyhow::Result<Vec<SocketAddr>> {
+        let mut last_err = None;
+        for ns in ns_names {
+            for group_type in [Type::A, Type::Aaaa] {
+                match self.resolve_at_depth(ns, &group_type, &Class::In, depth + 1) {
+                    Ok((_, answers, _)) => {
+                        let addrs = self.addresses(&[answers_owner(ns, &answers)], &answers);
+                        if !addrs.is_empty() {
+                            return Ok(addrs);
+                        }
+                    }
+                    Err(err) => last_err = Some(err),
+                }
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            ResolveError::NoServers {
+                domain: ns_names
+                    .iter()
+                    .map(|ns| ns.join("."))
+                    .collect::<Vec<String>>()
+                    .join(", "),
+            }
+            .into()
+        }))
+    }
+
+    /// Asks each server in turn until one gives a usable answer. A server
+    /// that fails, refuses or doesn't implement the query is as good as
+    /// unreachable, so the next one is tried.
+    fn query(
+        &self,
+        servers: &[SocketAddr],
+        domain: &[String],
+        group_type: &Type,
+        class: &Class,
+    ) -> anyhow::Result<ServerResponse> {
+        let question = SectionGroup::new(domain.to_vec(), group_type.clone(), class.clone(), None);
+        let mut last_err = None;
+        for server in servers {
+            let txid = rand::random::<u16>();
+            let result = iterative_query(txid, &question)
+                .map_err(anyhow::Error::from)
+                .and_then(|query| Ok(transport::exchange(*server, &query)?))
+                .and_then(|response| Ok(UdpBuffer::new(&response).unpack()?));
+            match result {
+                Ok((header, _))
+                    if matches!(
+                        header.header_second_half().rcode(),
+                        ResponseCode::ServerFailure
+                            | ResponseCode::Refused
+                            | ResponseCode::NotImplemented
+                    ) =>
+                {
+                    last_err = Some(
+                        ResolveError::ServerFailed {
+                            server: *server,
+                            rcode: header.header_second_half().rcode().clone(),
+                            domain: domain.join("."),
+                        }
+                        .into(),
+                    )
+                }
+                Ok((header, [_, ansection, nssection, arsection])) => {
+                    let groups = |section: Option<Section>| {
+                        section.map(|section| section.groups).unwrap_or_default()
+                    };
+                    return Ok((
+                        header.header_second_half().rcode().clone(),
+                        groups(ansection),
+                        groups(nssection),
+                        groups(arsection),
+                    ));
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| {
+            ResolveError::NoServers {
+                domain: domain.join("."),
+            }
+            .into()
+        }))
+    }
+}
+
+/// A non-recursive query for `question`, offering our full EDNS payload size.
+fn iterative_query(
+    txid: u16,
+    question: &SectionGroup,
+) -> Result<Vec<u8>, crate::error::ParseError> {
+    Vec::<u8>::try_from(
+        Message::new(txid)
+            .with_question(question.clone())
+            .with_edns(Edns::new(EDNS_UDP_PAYLOAD_SIZE, 0, 0, false, Vec::new())),
+    )
+}
+
+/// Follows the CNAMEs for `name` in `ansection` for as long as they stay
+/// inside `zone`. Returns where the chain ends, how many CNAMEs that took,
+/// and the records owned by names on the chain; anything else is dropped.
+fn follow_chain(
+    name: &[String],
+    zone: &[String],
+    ansection: Vec<SectionGroup>,
+) -> (Vec<String>, usize, Vec<SectionGroup>) {
+    let mut chain = Vec::new();
+    let mut target = name.to_vec();
+    let mut hops = 0;
+    while is_subdomain(&target, zone) && hops <= MAX_CNAME_CHAIN {
+        let next = ansection.iter().find_map(|group| match &group.asection {
+            Some((_, RData::Cname(next))) if same_name(&group.domain, &target) => {
+                Some(next.clone())
+            }
+            _ => None,
+        });
+        chain.push(target.clone());
+        match next {
+            Some(next) => {
+                target = next;
+                hops += 1;
+            }
+            None => break,
+        }
+    }
+    let records = ansection
+        .into_iter()
+        .filter(|group| chain.iter().any(|owner| same_name(owner, &group.domain)))
+        .collect();
+    (target, hops, records)
+}
+
+/// The name the address records in `answers` are owned by once any CNAMEs
+/// for `name` are followed.
+fn answers_owner(name: &[String], answers: &[SectionGroup]) -> Vec<String> {
+    let mut owner = name.to_vec();
+    for _ in 0..MAX_CNAME_CHAIN {
+        match answers.iter().find_map(|group| match &group.asection {
+            Some((_, RData::Cname(next))) if same_name(&group.domain, &owner) => Some(next),
+            _ => None,
+        }) {
+            Some(next) => owner = next.clone(),
+            None => break,
+        }
+    }
+    owner
+}
+
+fn lowercase(name: &[String]) -> Vec<String> {
+    name.iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+pub(crate) fn same_name(lhs: &[String], rhs: &[String]) -> bool {
+    lhs.len() == rhs.len()
+        && lhs
+            .iter()
+            .zip(rhs.iter())
+            .all(|(lhs, rhs)| lhs.eq_ignore_ascii_case(rhs))
+}
+
+/// Whether `name` is `zone` or somewhere below it.
+pub(crate) fn is_subdomain(name: &[String], zone: &[String]) -> bool {
+    name.len() >= zone.len() && same_name(&name[name.len() - zone.len()..], zone)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    use crate::{
+        header::{AuthAnswer, ResponseCode},
+        message::Message,
+        section::{Class, RData, SectionGroup, Type},
+    };
+
+    use super::Recursor;
+
+    type StubAnswer = (
+        ResponseCode,
+        Vec<SectionGroup>,
+        Vec<SectionGroup>,
+        Vec<SectionGroup>,
+    );
+
+    fn name(name: &str) -> Vec<String> {
+        name.split('.')
+            .filter(|label| !label.is_empty())
+            .map(|label| label.to_owned())
+            .collect()
+    }
+
+    fn record(owner: &str, group_type: Type, rdata: RData) -> SectionGroup {
+        SectionGroup::new(name(owner), group_type, Class::In, Some((300, rdata)))
+    }
+
+    /// Runs a stand-in authoritative server on `ip` that answers every query
+    /// with whatever `answer` returns for its question.
+    fn spawn_stub<F>(ip: impl Into<IpAddr>, port: u16, answer: F)
+    where
+        F: Fn(&SectionGroup) -> StubAnswer + Send + 'static,
+    {
+        let socket = UdpSocket::bind(SocketAddr::new(ip.into(), port)).unwrap();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            while let Ok((size, source)) = socket.recv_from(&mut buf) {
+                let query = Message::try_from(&buf[..size]).unwrap();
+                let (rcode, answers, authority, additional) = answer(&query.questions()[0]);
+                let response = Message::response_to(&query)
+                    .with_authoritative(AuthAnswer::Authoritative)
+                    .with_rcode(rcode)
+                    .with_answers(answers)
+                    .with_authority(authority)
+                    .with_additional(additional);
+                let response = Vec::<u8>::try_from(response).unwrap();
+                socket.send_to(&response, source).unwrap();
+            }
+        });
+    }
+
+    /// Root on 127.0.0.11 delegates `test.` to 127.0.0.12, which delegates
+    /// `example.test.` to ns.example.test without glue; that name is served
+    /// by 127.0.0.12 too, and the zone itself by 127.0.0.13. The root also
+    /// delegates `other.` to ns.example.test, with glue pointing at a liar on
+    /// 127.0.0.66; `lame.` to a server on 127.0.0.67 that refuses everything
+    /// and then to 127.0.0.13; and `six.` to ns6.example.test, which only
+    /// has an IPv6 address, ::1. Returns how many queries the root has seen.
+    fn spawn_hierarchy() -> (Recursor, Arc<AtomicUsize>) {
+        let port = UdpSocket::bind("127.0.0.11:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let root_queries = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&root_queries);
+        spawn_stub(Ipv4Addr::new(127, 0, 0, 11), port, move |question| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let glue = |owner: &str, addr: Ipv4Addr| record(owner, Type::A, RData::A(addr));
+            let ns = |zone: &str, ns: &str| record(zone, Type::Ns, RData::Ns(name(ns)));
+            let (authority, additional) = match question.domain.last().map(String::as_str) {
+                Some("other") => (
+                    vec![ns("other", "ns.example.test")],
+                    vec![glue("ns.example.test", Ipv4Addr::new(127, 0, 0, 66))],
+                ),
+                Some("lame") => (
+                    vec![ns("lame", "ns1.lame"), ns("lame", "ns2.lame")],
+                    vec![
+                        glue("ns1.lame", Ipv4Addr::new(127, 0, 0, 67)),
+                        glue("ns2.lame", Ipv4Addr::new(127, 0, 0, 13)),
+                    ],
+                ),
+                Some("six") => (vec![ns("six", "ns6.example.test")], Vec::new()),
+                _ => (
+                    vec![ns("test", "ns.test")],
+                    vec![glue("ns.test", Ipv4Addr::new(127, 0, 0, 12))],
+                ),
+            };
+            (ResponseCode::None, Vec::new(), authority, additional)
+        });
+        spawn_stub(Ipv4Addr::new(127, 0, 0, 66), port, |question| {
+            (
+                ResponseCode::None,
+                vec![SectionGroup::new(
+                    question.domain.clone(),
+                    Type::A,
+                    Class::In,
+                    Some((300, RData::A(Ipv4Addr::new(203, 0, 113, 66)))),
+                )],
+                Vec::new(),
+                Vec::new(),
+            )
+        });
+        spawn_stub(Ipv4Addr::new(127, 0, 0, 67), port, |_| {
+            (ResponseCode::Refused, Vec::new(), Vec::new(), Vec::new())
+        });
+        spawn_stub(Ipv6Addr::LOCALHOST, port, |question| {
+            (
+                ResponseCode::None,
+                vec![SectionGroup::new(
+                    question.domain.clone(),
+                    Type::A,
+                    Class::In,
+                    Some((300, RData::A(Ipv4Addr::new(192, 0, 2, 6)))),
+                )],
+                Vec::new(),
+                Vec::new(),
+            )
+        });
+        spawn_stub(Ipv4Addr::new(127, 0, 0, 12), port, |question| {
+            let address = |owner: &str, addr: Ipv4Addr| {
+                (
+                    ResponseCode::None,
+                    vec![record(owner, Type::A, RData::A(addr))],
+                    Vec::new(),
+                    Vec::new(),
+                )
+            };
+            if question.domain == name("ns.example.test") {
+                address("ns.example.test", Ipv4Addr::new(127, 0, 0, 13))
+            } else if question.domain == name("target.test") {
+                address("target.test", Ipv4Addr::new(192, 0, 2, 2))
+            } else {
+                (
+                    ResponseCode::None,
+                    Vec::new(),
+                    vec![record(
+                        "example.test",
+                        Type::Ns,
+                        RData::Ns(name("ns.example.test")),
+                    )],
+                    Vec::new(),
+                )
+            }
+        });
+        spawn_stub(Ipv4Addr::new(127, 0, 0, 13), port, |question| {
+            let soa = record(
+                "example.test",
+                Type::Soa,
+                RData::Soa {
+                    mname: name("ns.example.test"),
+                    rname: name("hostmaster.example.test"),
+                    serial: 1,
+                    refresh: 3600,
+                    retry: 600,
+                    expire: 86400,
+                    minimum: 60,
+                },
+            );
+            match question.domain.join(".").as_str() {
+                "www.example.test" => (
+                    ResponseCode::None,
+                    vec![record(
+                        "www.example.test",
+                        Type::Cname,
+                        RData::Cname(name("example.test")),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "example.test" if question.group_type == Type::A => (
+                    ResponseCode::None,
+                    vec![record(
+                        "example.test",
+                        Type::A,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 1)),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "example.test" => (ResponseCode::None, Vec::new(), vec![soa], Vec::new()),
+                // a CNAME out of the zone, with made up records for the
+                // target and for a name nobody asked about
+                "alias.example.test" => (
+                    ResponseCode::None,
+                    vec![
+                        record(
+                            "alias.example.test",
+                            Type::Cname,
+                            RData::Cname(name("target.test")),
+                        ),
+                        record(
+                            "target.test",
+                            Type::A,
+                            RData::A(Ipv4Addr::new(203, 0, 113, 1)),
+                        ),
+                        record(
+                            "mail.example.test",
+                            Type::A,
+                            RData::A(Ipv4Addr::new(203, 0, 113, 2)),
+                        ),
+                    ],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "ns.example.test" => (
+                    ResponseCode::None,
+                    vec![record(
+                        "ns.example.test",
+                        Type::A,
+                        RData::A(Ipv4Addr::new(127, 0, 0, 13)),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "ns6.example.test" if question.group_type == Type::Aaaa => (
+                    ResponseCode::None,
+                    vec![record(
+                        "ns6.example.test",
+                        Type::Aaaa,
+                        RData::Aaaa(Ipv6Addr::LOCALHOST),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "ns6.example.test" => (ResponseCode::None, Vec::new(), vec![soa], Vec::new()),
+                "www.lame" => (
+                    ResponseCode::None,
+                    vec![record(
+                        "www.lame",
+                        Type::A,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 4)),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                "www.other" => (
+                    ResponseCode::None,
+                    vec![record(
+                        "www.other",
+                        Type::A,
+                        RData::A(Ipv4Addr::new(192, 0, 2, 3)),
+                    )],
+                    Vec::new(),
+                    Vec::new(),
+                ),
+                _ => (ResponseCode::Name, Vec::new(), vec![soa], Vec::new()),
+            }
+        });
+        let recursor = Recursor::with_port(
+            vec![SocketAddr::new(
+                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 11)),
+                port,
+            )],
+            port,
+        );
+        (recursor, root_queries)
+    }
+
+    #[test]
+    fn test_resolve_follows_referrals_and_cnames() {
+        let (recursor, root_queries) = spawn_hierarchy();
+
+        let question = SectionGroup::new(name("www.example.test"), Type::A, Class::In, None);
+        let (rcode, answers, _) = recursor.resolve(&question).unwrap();
+        assert_eq!(rcode, ResponseCode::None);
+        assert_eq!(
+            answers,
+            vec![
+                record(
+                    "www.example.test",
+                    Type::Cname,
+                    RData::Cname(name("example.test"))
+                ),
+                record(
+                    "example.test",
+                    Type::A,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 1))
+                ),
+            ]
+        );
+
+        let question = SectionGroup::new(name("nope.example.test"), Type::A, Class::In, None);
+        let (rcode, answers, authority) = recursor.resolve(&question).unwrap();
+        assert_eq!(rcode, ResponseCode::Name);
+        assert!(answers.is_empty());
+        assert_eq!(authority[0].group_type, Type::Soa);
+
+        let question = SectionGroup::new(name("example.test"), Type::Mx, Class::In, None);
+        let (rcode, answers, authority) = recursor.resolve(&question).unwrap();
+        assert_eq!(rcode, ResponseCode::None);
+        assert!(answers.is_empty());
+        assert_eq!(authority[0].group_type, Type::Soa);
+
+        // every referral was remembered, so only the first query went to the root
+        assert_eq!(root_queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_resolve_ignores_records_outside_bailiwick() {
+        let (recursor, _) = spawn_hierarchy();
+
+        let question = SectionGroup::new(name("alias.example.test"), Type::A, Class::In, None);
+        let (rcode, answers, _) = recursor.resolve(&question).unwrap();
+        assert_eq!(rcode, ResponseCode::None);
+        assert_eq!(
+            answers,
+            vec![
+                record(
+                    "alias.example.test",
+                    Type::Cname,
+                    RData::Cname(name("target.test"))
+                ),
+                record(
+                    "target.test",
+                    Type::A,
+                    RData::A(Ipv4Addr::new(192, 0, 2, 2))
+                ),
+            ]
+        );
+
+        let question = SectionGroup::new(name("www.other"), Type::A, Class::In, None);
+        let (_, answers, _) = recursor.resolve(&question).unwrap();
+        assert_eq!(
+            answers,
+            vec![record(
+                "www.other",
+                Type::A,
+                RData::A(Ipv4Addr::new(192, 0, 2, 3))
+            )]
+        );
+    }
+
+    #[test]
+    fn test_resolve_skips_failing_servers_and_reaches_ipv6_ones() {
+        let (recursor, _) = spawn_hierarchy();
+        let resolve = |domain: &str| {
+            let question = SectionGroup::new(name(domain), Type::A, Class::In, None);
+            recursor.resolve(&question).unwrap()
+        };
+
+        let (rcode, answers, _) = resolve("www.lame");
+        assert_eq!(rcode, ResponseCode::None);
+        assert_eq!(
+            answers,
+            vec![record(
+                "www.lame",
+                Type::A,
+                RData::A(Ipv4Addr::new(192, 0, 2, 4))
+            )]
+        );
+
+        let (_, answers, _) = resolve("www.six");
+        assert_eq!(
+            answers,
+            vec![record(
+                "www.six",
+                Type::A,
+                RData::A(Ipv4Addr::new(192, 0, 2, 6))
+            )]
+        );
+    }
+}
@@ -1,8 +1,19 @@
+use std::{
+    fmt,
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
 use crate::{
-    big_endian_convert_u16_to_u8_array, big_endian_convert_u32_to_u8_array, error::ParseError,
+    buffer::{ParseMode, UdpBuffer},
+    edns::EdnsOption,
+    error::ParseError,
+    writer::MessageWriter,
 };
 
-pub(crate) type AsectionContents = (u32, u16, Vec<u8>);
+/// TTL and typed RDATA of a resource record; RDLENGTH is derived when the
+/// record is written back out.
+pub type AsectionContents = (u32, RData);
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct SectionGroup {
@@ -23,32 +34,90 @@ impl Section {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Class {
-    In = 1,
-    Cs = 2,
-    Ch = 3,
-    Hs = 4,
+    In,
+    Cs,
+    Ch,
+    Hs,
+    None,
+    Any,
+    /// Any class we have no name for, carried through untouched (RFC 3597).
+    Unknown(u16),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum Type {
-    A = 1,
-    Ns = 2,
-    Md = 3,
-    Mf = 4,
-    Cname = 5,
-    Soa = 6,
-    Mb = 7,
-    Mg = 8,
-    Mr = 9,
-    Null = 10,
-    Wks = 11,
-    Ptr = 12,
-    Hinfo = 13,
-    Minfo = 14,
-    Mx = 15,
-    Txt = 16,
+    A,
+    Ns,
+    Md,
+    Mf,
+    Cname,
+    Soa,
+    Mb,
+    Mg,
+    Mr,
+    Null,
+    Wks,
+    Ptr,
+    Hinfo,
+    Minfo,
+    Mx,
+    Txt,
+    Aaaa,
+    Srv,
+    Opt,
+    Caa,
+    /// Any type we have no name for, carried through untouched (RFC 3597).
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(Vec<String>),
+    Ns(Vec<String>),
+    Ptr(Vec<String>),
+    /// The obsolete mailbox types of RFC 1035 section 3.3; their names may
+    /// be compressed like any other RFC 1035 type's.
+    Md(Vec<String>),
+    Mf(Vec<String>),
+    Mb(Vec<String>),
+    Mg(Vec<String>),
+    Mr(Vec<String>),
+    Minfo {
+        rmailbx: Vec<String>,
+        emailbx: Vec<String>,
+    },
+    Mx {
+        preference: u16,
+        exchange: Vec<String>,
+    },
+    Soa {
+        mname: Vec<String>,
+        rname: Vec<String>,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    Txt(Vec<Vec<u8>>),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Vec<String>,
+    },
+    Caa {
+        flags: u8,
+        tag: String,
+        value: Vec<u8>,
+    },
+    Opt(Vec<EdnsOption>),
+    /// RDATA of a type we don't interpret, kept as-is (RFC 3597).
+    Unknown(Vec<u8>),
 }
 
 impl From<Section> for Vec<u8> {
@@ -88,76 +157,338 @@ impl SectionGroup {
 impl TryFrom<SectionGroup> for Vec<u8> {
     type Error = ParseError;
     fn try_from(value: SectionGroup) -> Result<Self, Self::Error> {
-        let mut res = Vec::new();
-        for domain in value.domain {
-            let len = u8::try_from(domain.len()).map_err(|_| ParseError::ConversionError)?;
-            res.push(len);
-            domain
-                .into_bytes()
-                .into_iter()
-                .for_each(|byte| res.push(byte));
+        // standalone bytes may end up anywhere in a message, so pointers
+        // relative to this buffer would be wrong
+        let mut writer = MessageWriter::uncompressed();
+        writer.write_section_group(&value)?;
+        Ok(writer.into_bytes())
+    }
+}
+
+impl RData {
+    pub(crate) fn unpack(
+        buf: &mut UdpBuffer,
+        group_type: &Type,
+        length: u16,
+    ) -> Result<Self, ParseError> {
+        let offset = buf.pos();
+        if buf.remaining() < usize::from(length) {
+            return Err(ParseError::RdataOverrun { offset, length });
         }
-        res.push(0);
-        let group_type = big_endian_convert_u16_to_u8_array(value.group_type as u16);
-        let class = big_endian_convert_u16_to_u8_array(value.class as u16);
-        res.push(group_type[0]);
-        res.push(group_type[1]);
-        res.push(class[0]);
-        res.push(class[1]);
-        if let Some((ttl, length, data)) = value.asection {
-            big_endian_convert_u32_to_u8_array(ttl)
-                .into_iter()
-                .for_each(|elem| {
-                    res.push(elem);
-                });
-            big_endian_convert_u16_to_u8_array(length)
-                .into_iter()
-                .for_each(|elem| {
-                    res.push(elem);
-                });
-            data.into_iter().for_each(|elem| {
-                res.push(elem);
-            })
+        let end = offset + usize::from(length);
+        let rdata = match group_type {
+            Type::A => RData::A(Ipv4Addr::from(buf.get_u32()?)),
+            Type::Aaaa => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(buf.get_bytes(16)?);
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            Type::Cname => RData::Cname(buf.unpack_name()?),
+            Type::Ns => RData::Ns(buf.unpack_name()?),
+            Type::Ptr => RData::Ptr(buf.unpack_name()?),
+            Type::Md => RData::Md(buf.unpack_name()?),
+            Type::Mf => RData::Mf(buf.unpack_name()?),
+            Type::Mb => RData::Mb(buf.unpack_name()?),
+            Type::Mg => RData::Mg(buf.unpack_name()?),
+            Type::Mr => RData::Mr(buf.unpack_name()?),
+            Type::Minfo => RData::Minfo {
+                rmailbx: buf.unpack_name()?,
+                emailbx: buf.unpack_name()?,
+            },
+            Type::Mx => RData::Mx {
+                preference: buf.get_u16()?,
+                exchange: buf.unpack_name()?,
+            },
+            Type::Soa => RData::Soa {
+                mname: buf.unpack_name()?,
+                rname: buf.unpack_name()?,
+                serial: buf.get_u32()?,
+                refresh: buf.get_u32()?,
+                retry: buf.get_u32()?,
+                expire: buf.get_u32()?,
+                minimum: buf.get_u32()?,
+            },
+            Type::Txt => {
+                let mut strings = Vec::new();
+                while buf.pos() < end {
+                    let len = usize::from(buf.get_u8()?);
+                    strings.push(buf.get_bytes(len)?.to_vec());
+                }
+                RData::Txt(strings)
+            }
+            Type::Srv => RData::Srv {
+                priority: buf.get_u16()?,
+                weight: buf.get_u16()?,
+                port: buf.get_u16()?,
+                target: buf.unpack_name()?,
+            },
+            Type::Caa => {
+                let flags = buf.get_u8()?;
+                let tag_len = usize::from(buf.get_u8()?);
+                let tag = std::str::from_utf8(buf.get_bytes(tag_len)?)
+                    .map_err(|_| ParseError::ConversionError)?
+                    .to_owned();
+                let value = buf
+                    .get_bytes(end.checked_sub(buf.pos()).ok_or(ParseError::SectionError)?)?
+                    .to_vec();
+                RData::Caa { flags, tag, value }
+            }
+            Type::Opt => {
+                let mut options = Vec::new();
+                while buf.pos() < end {
+                    let (code, len) = (buf.get_u16()?, buf.get_u16()?);
+                    options.push((code, buf.get_bytes(usize::from(len))?.to_vec()));
+                }
+                RData::Opt(options)
+            }
+            _ => RData::Unknown(buf.get_bytes(usize::from(length))?.to_vec()),
+        };
+        match buf.pos() {
+            pos if pos == end => {}
+            // the rest of the RDATA is skipped
+            pos if pos < end && buf.mode() == ParseMode::Lenient => buf.seek(end)?,
+            pos => {
+                return Err(ParseError::RdataLength {
+                    offset,
+                    length,
+                    used: pos - offset,
+                })
+            }
         }
-        Ok(res)
+        Ok(rdata)
+    }
+
+    /// Names inside the RFC 1035 types (CNAME, NS, PTR, MX, SOA and the
+    /// mailbox ones) may be compressed; RFC 3597 forbids it for every type
+    /// defined later, e.g. SRV.
+    pub(crate) fn write(&self, writer: &mut MessageWriter) -> Result<(), ParseError> {
+        match self {
+            RData::A(addr) => writer.write_bytes(&addr.octets()),
+            RData::Aaaa(addr) => writer.write_bytes(&addr.octets()),
+            RData::Cname(name)
+            | RData::Ns(name)
+            | RData::Ptr(name)
+            | RData::Md(name)
+            | RData::Mf(name)
+            | RData::Mb(name)
+            | RData::Mg(name)
+            | RData::Mr(name) => writer.write_name(name)?,
+            RData::Minfo { rmailbx, emailbx } => {
+                writer.write_name(rmailbx)?;
+                writer.write_name(emailbx)?;
+            }
+            RData::Mx {
+                preference,
+                exchange,
+            } => {
+                writer.write_u16(*preference);
+                writer.write_name(exchange)?;
+            }
+            RData::Soa {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                writer.write_name(mname)?;
+                writer.write_name(rname)?;
+                [serial, refresh, retry, expire, minimum]
+                    .into_iter()
+                    .for_each(|val| writer.write_u32(*val));
+            }
+            RData::Txt(strings) => {
+                for string in strings {
+                    let len =
+                        u8::try_from(string.len()).map_err(|_| ParseError::ConversionError)?;
+                    writer.write_u8(len);
+                    writer.write_bytes(string);
+                }
+            }
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                writer.write_u16(*priority);
+                writer.write_u16(*weight);
+                writer.write_u16(*port);
+                writer.write_uncompressed_name(target)?;
+            }
+            RData::Caa { flags, tag, value } => {
+                let len = u8::try_from(tag.len()).map_err(|_| ParseError::ConversionError)?;
+                writer.write_u8(*flags);
+                writer.write_u8(len);
+                writer.write_bytes(tag.as_bytes());
+                writer.write_bytes(value);
+            }
+            RData::Opt(options) => {
+                for (code, data) in options {
+                    let len = u16::try_from(data.len()).map_err(|_| ParseError::ConversionError)?;
+                    writer.write_u16(*code);
+                    writer.write_u16(len);
+                    writer.write_bytes(data);
+                }
+            }
+            RData::Unknown(data) => writer.write_bytes(data),
+        }
+        Ok(())
     }
 }
 
-impl TryFrom<u16> for Type {
-    type Error = ParseError;
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
+impl From<u16> for Type {
+    fn from(value: u16) -> Self {
         match value {
-            1 => Ok(Type::A),
-            2 => Ok(Type::Ns),
-            3 => Ok(Type::Md),
-            4 => Ok(Type::Mf),
-            5 => Ok(Type::Cname),
-            6 => Ok(Type::Soa),
-            7 => Ok(Type::Mb),
-            8 => Ok(Type::Mg),
-            9 => Ok(Type::Mr),
-            10 => Ok(Type::Null),
-            11 => Ok(Type::Wks),
-            12 => Ok(Type::Ptr),
-            13 => Ok(Type::Hinfo),
-            14 => Ok(Type::Minfo),
-            15 => Ok(Type::Mx),
-            16 => Ok(Type::Txt),
-            _ => Err(ParseError::UnimplementedError),
+            1 => Type::A,
+            2 => Type::Ns,
+            3 => Type::Md,
+            4 => Type::Mf,
+            5 => Type::Cname,
+            6 => Type::Soa,
+            7 => Type::Mb,
+            8 => Type::Mg,
+            9 => Type::Mr,
+            10 => Type::Null,
+            11 => Type::Wks,
+            12 => Type::Ptr,
+            13 => Type::Hinfo,
+            14 => Type::Minfo,
+            15 => Type::Mx,
+            16 => Type::Txt,
+            28 => Type::Aaaa,
+            33 => Type::Srv,
+            41 => Type::Opt,
+            257 => Type::Caa,
+            _ => Type::Unknown(value),
         }
     }
 }
 
-impl TryFrom<u16> for Class {
-    type Error = ParseError;
+/// The type's mnemonic, or `TYPE<n>` for types we have no name for
+/// (RFC 3597 section 5).
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Unknown(code) => write!(f, "TYPE{code}"),
+            named => write!(f, "{}", format!("{named:?}").to_ascii_uppercase()),
+        }
+    }
+}
 
-    fn try_from(value: u16) -> Result<Self, Self::Error> {
+impl From<Type> for u16 {
+    fn from(value: Type) -> Self {
         match value {
-            1 => Ok(Class::In),
-            2 => Ok(Class::Cs),
-            3 => Ok(Class::Ch),
-            4 => Ok(Class::Hs),
-            _ => Err(ParseError::UnimplementedError),
+            Type::A => 1,
+            Type::Ns => 2,
+            Type::Md => 3,
+            Type::Mf => 4,
+            Type::Cname => 5,
+            Type::Soa => 6,
+            Type::Mb => 7,
+            Type::Mg => 8,
+            Type::Mr => 9,
+            Type::Null => 10,
+            Type::Wks => 11,
+            Type::Ptr => 12,
+            Type::Hinfo => 13,
+            Type::Minfo => 14,
+            Type::Mx => 15,
+            Type::Txt => 16,
+            Type::Aaaa => 28,
+            Type::Srv => 33,
+            Type::Opt => 41,
+            Type::Caa => 257,
+            Type::Unknown(value) => value,
         }
     }
 }
+
+impl From<u16> for Class {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Class::In,
+            2 => Class::Cs,
+            3 => Class::Ch,
+            4 => Class::Hs,
+            254 => Class::None,
+            255 => Class::Any,
+            _ => Class::Unknown(value),
+        }
+    }
+}
+
+impl From<Class> for u16 {
+    fn from(value: Class) -> Self {
+        match value {
+            Class::In => 1,
+            Class::Cs => 2,
+            Class::Ch => 3,
+            Class::Hs => 4,
+            Class::None => 254,
+            Class::Any => 255,
+            Class::Unknown(value) => value,
+        }
+    }
+}
+
+/// Parses a type mnemonic as written in master files, or the generic
+/// `TYPEnnn` form for anything else (RFC 3597 section 5).
+impl FromStr for Type {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = match s.to_ascii_uppercase().as_str() {
+            "A" => Type::A,
+            "NS" => Type::Ns,
+            "MD" => Type::Md,
+            "MF" => Type::Mf,
+            "CNAME" => Type::Cname,
+            "SOA" => Type::Soa,
+            "MB" => Type::Mb,
+            "MG" => Type::Mg,
+            "MR" => Type::Mr,
+            "NULL" => Type::Null,
+            "WKS" => Type::Wks,
+            "PTR" => Type::Ptr,
+            "HINFO" => Type::Hinfo,
+            "MINFO" => Type::Minfo,
+            "MX" => Type::Mx,
+            "TXT" => Type::Txt,
+            "AAAA" => Type::Aaaa,
+            "SRV" => Type::Srv,
+            "OPT" => Type::Opt,
+            "CAA" => Type::Caa,
+            upper => upper
+                .strip_prefix("TYPE")
+                .and_then(|num| num.parse::<u16>().ok())
+                .map(Type::from)
+                .ok_or(ParseError::ConversionError)?,
+        };
+        Ok(value)
+    }
+}
+
+/// Parses a class mnemonic, or the generic `CLASSnnn` form.
+impl FromStr for Class {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = match s.to_ascii_uppercase().as_str() {
+            "IN" => Class::In,
+            "CS" => Class::Cs,
+            "CH" => Class::Ch,
+            "HS" => Class::Hs,
+            "NONE" => Class::None,
+            "ANY" => Class::Any,
+            upper => upper
+                .strip_prefix("CLASS")
+                .and_then(|num| num.parse::<u16>().ok())
+                .map(Class::from)
+                .ok_or(ParseError::ConversionError)?,
+        };
+        Ok(value)
+    }
+}
@@ -0,0 +1,577 @@
+use std::{
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpStream, UdpSocket},
+    sync::{Arc, Condvar, Mutex, RwLock, RwLockReadGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    buffer::{DNS_HEADER_SIZE, MAX_EDNS_PACKET_SIZE},
+    header::{HeaderSecondRowFirstHalf, Truncation},
+};
+
+/// How long we wait on an upstream resolver before giving up on it.
+pub const UPSTREAM_TIMEOUT: Duration = Duration::from_secs(5);
+/// Idle time after which a client TCP connection is closed (RFC 7766 section 6.2.3).
+pub const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Queries from one TCP connection we work on at once; further ones aren't
+/// read until one of those is answered.
+pub const MAX_PIPELINED_QUERIES: usize = 16;
+/// Client TCP connections served at once; more are closed straight away.
+pub const MAX_TCP_CONNECTIONS: usize = 256;
+/// Queries worked on at once across all client TCP connections; the
+/// connections wait their turn for more.
+pub const MAX_TCP_QUERIES: usize = 512;
+
+/// UDP sockets on random ephemeral ports that upstream queries go out on,
+/// so neither the source port nor the txid of a query can be guessed. There
+/// are `size` of them for each address family an upstream has needed so
+/// far. Sockets are only ever added, so an index stays good for as long as
+/// the pool lives.
+#[derive(Debug, Default)]
+pub struct SocketPool {
+    size: usize,
+    /// Each socket, with whether it's an IPv4 one.
+    sockets: RwLock<Vec<(bool, UdpSocket)>>,
+}
+
+impl SocketPool {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            sockets: RwLock::default(),
+        }
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<(bool, UdpSocket)>> {
+        self.sockets.read().expect("socket pool lock poisoned")
+    }
+
+    /// Binds sockets for talking to `upstream` unless there are some for its
+    /// address family already; the OS picks a random port for each. Returns
+    /// the new sockets' indices with a handle on each to read from.
+    pub fn bind_for(&self, upstream: SocketAddr) -> io::Result<Vec<(usize, UdpSocket)>> {
+        let mut sockets = self.sockets.write().expect("socket pool lock poisoned");
+        let ipv4 = upstream.is_ipv4();
+        if sockets.iter().any(|(family, _)| *family == ipv4) {
+            return Ok(Vec::new());
+        }
+        let local = if ipv4 { "0.0.0.0:0" } else { "[::]:0" };
+        let bound = (0..self.size)
+            .map(|_| UdpSocket::bind(local))
+            .collect::<io::Result<Vec<UdpSocket>>>()?;
+        let readers = bound
+            .iter()
+            .map(UdpSocket::try_clone)
+            .collect::<io::Result<Vec<UdpSocket>>>()?;
+        let first = sockets.len();
+        sockets.extend(bound.into_iter().map(|socket| (ipv4, socket)));
+        Ok((first..).zip(readers).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// A random index among the sockets that can talk to `upstream`, if
+    /// any have been bound.
+    pub fn pick(&self, upstream: SocketAddr) -> Option<usize> {
+        let candidates = self
+            .read()
+            .iter()
+            .enumerate()
+            .filter(|(_, (ipv4, _))| *ipv4 == upstream.is_ipv4())
+            .map(|(idx, _)| idx)
+            .collect::<Vec<usize>>();
+        match candidates.len() {
+            0 => None,
+            len => Some(candidates[rand::thread_rng().gen_range(0..len)]),
+        }
+    }
+
+    /// Sends `msg` to `upstream` from socket `idx`.
+    pub fn send_to(&self, idx: usize, msg: &[u8], upstream: SocketAddr) -> io::Result<usize> {
+        match self.read().get(idx) {
+            Some((_, socket)) => socket.send_to(msg, upstream),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("no upstream socket {idx}"),
+            )),
+        }
+    }
+
+    pub fn local_addr(&self, idx: usize) -> Option<SocketAddr> {
+        self.read()
+            .get(idx)
+            .and_then(|(_, socket)| socket.local_addr().ok())
+    }
+}
+
+/// Reads one message prefixed with its 2-byte length (RFC 1035 section 4.2.2).
+/// Returns `None` if the peer closed the connection between messages.
+pub fn read_frame(stream: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    let mut msg = vec![0u8; usize::from(u16::from_be_bytes(len))];
+    stream.read_exact(&mut msg)?;
+    Ok(Some(msg))
+}
+
+pub fn write_frame(stream: &mut impl Write, msg: &[u8]) -> io::Result<()> {
+    let len = u16::try_from(msg.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too long for TCP"))?;
+    // one write so the length and message don't go out as separate segments
+    stream.write_all(&[&len.to_be_bytes()[..], msg].concat())?;
+    stream.flush()
+}
+
+/// How many of up to `max` things are in progress, and a signal for when
+/// one is done.
+#[derive(Debug, Clone)]
+struct InFlight {
+    max: usize,
+    count: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl InFlight {
+    fn new(max: usize) -> Self {
+        Self {
+            max,
+            count: Arc::default(),
+        }
+    }
+}
+
+/// One of the slots of an `InFlight`, given back when dropped, even if the
+/// handler panics.
+#[derive(Debug)]
+pub struct Slot(InFlight);
+
+impl Slot {
+    /// Waits for a free slot.
+    fn take(in_flight: &InFlight) -> Self {
+        let (count, freed) = &*in_flight.count;
+        let mut count = freed
+            .wait_while(count.lock().expect("TCP slots lock poisoned"), |count| {
+                *count >= in_flight.max
+            })
+            .expect("TCP slots lock poisoned");
+        *count += 1;
+        Self(in_flight.clone())
+    }
+
+    /// A free slot, if there is one now.
+    fn try_take(in_flight: &InFlight) -> Option<Self> {
+        let mut count = in_flight.count.0.lock().expect("TCP slots lock poisoned");
+        if *count >= in_flight.max {
+            return None;
+        }
+        *count += 1;
+        Some(Self(in_flight.clone()))
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (count, freed) = &*self.0.count;
+        if let Ok(mut count) = count.lock() {
+            *count -= 1;
+        }
+        freed.notify_one();
+    }
+}
+
+/// What the client TCP connections served on a listener may take up
+/// between them: how many connections there are at once, and how many
+/// queries are being worked on across all of them.
+#[derive(Debug, Clone)]
+pub struct TcpLimits {
+    connections: InFlight,
+    queries: InFlight,
+}
+
+impl TcpLimits {
+    pub fn new(connections: usize, queries: usize) -> Self {
+        Self {
+            connections: InFlight::new(connections),
+            queries: InFlight::new(queries),
+        }
+    }
+
+    /// A slot for a new connection, to hold while serving it, or `None` if
+    /// there are as many connections as allowed already.
+    pub fn admit(&self) -> Option<Slot> {
+        Slot::try_take(&self.connections)
+    }
+}
+
+impl Default for TcpLimits {
+    fn default() -> Self {
+        Self::new(MAX_TCP_CONNECTIONS, MAX_TCP_QUERIES)
+    }
+}
+
+/// Serves framed queries from one client connection until it closes or goes
+/// idle. Up to `MAX_PIPELINED_QUERIES` queries are handled concurrently and
+/// answered as they complete, so clients can pipeline several queries
+/// without waiting for each answer, as long as `limits` leaves room for
+/// them. `handler` gets the client's address and the raw query.
+pub fn serve_tcp_connection<F>(
+    stream: TcpStream,
+    handler: Arc<F>,
+    limits: &TcpLimits,
+) -> io::Result<()>
+where
+    F: Fn(SocketAddr, Vec<u8>) -> Option<Vec<u8>> + Send + Sync + 'static,
+{
+    stream.set_read_timeout(Some(TCP_IDLE_TIMEOUT))?;
+    let source = stream.peer_addr()?;
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    let in_flight = InFlight::new(MAX_PIPELINED_QUERIES);
+    let mut reader = stream;
+    loop {
+        let query = match read_frame(&mut reader) {
+            Ok(Some(query)) => query,
+            Ok(None) => return Ok(()),
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                return Ok(())
+            }
+            Err(err) => return Err(err),
+        };
+        let (handler, writer) = (Arc::clone(&handler), Arc::clone(&writer));
+        let slots = (Slot::take(&in_flight), Slot::take(&limits.queries));
+        thread::spawn(move || {
+            let _slots = slots;
+            if let Some(response) = handler(source, query) {
+                let mut writer = writer.lock().expect("TCP writer lock poisoned");
+                if let Err(msg) = write_frame(&mut *writer, &response) {
+                    crate::warn!("Error sending over TCP; {msg}");
+                }
+            }
+        });
+    }
+}
+
+/// Sends `query` to `upstream` over UDP, retrying over TCP if the answer
+/// comes back truncated.
+pub fn exchange(upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let response = query_udp(upstream, query)?;
+    if is_truncated(&response) {
+        query_tcp(upstream, query)
+    } else {
+        Ok(response)
+    }
+}
+
+pub fn query_udp(upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    query_udp_within(upstream, query, UPSTREAM_TIMEOUT)
+}
+
+/// Waits at most `timeout` in all for the answer, however much else comes
+/// in on the socket meanwhile.
+fn query_udp_within(upstream: SocketAddr, query: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+    let socket = UdpSocket::bind(match upstream {
+        SocketAddr::V4(_) => "0.0.0.0:0",
+        SocketAddr::V6(_) => "[::]:0",
+    })?;
+    socket.connect(upstream)?;
+    socket.send(query)?;
+    let deadline = Instant::now() + timeout;
+    let mut buf = [0u8; MAX_EDNS_PACKET_SIZE];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "upstream didn't answer in time",
+            ));
+        }
+        socket.set_read_timeout(Some(left))?;
+        let size = socket.recv(&mut buf)?;
+        // ignore anything that isn't the answer to this query
+        if answers(query, &buf[..size]) {
+            return Ok(buf[..size].to_vec());
+        }
+    }
+}
+
+pub fn query_tcp(upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect_timeout(&upstream, UPSTREAM_TIMEOUT)?;
+    stream.set_read_timeout(Some(UPSTREAM_TIMEOUT))?;
+    write_frame(&mut stream, query)?;
+    let response = read_frame(&mut stream)?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "upstream closed the connection without answering",
+        )
+    })?;
+    if !answers(query, &response) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "upstream answered another query",
+        ));
+    }
+    Ok(response)
+}
+
+/// Whether `response` carries the txid of `query` and repeats its question.
+fn answers(query: &[u8], response: &[u8]) -> bool {
+    response.len() >= DNS_HEADER_SIZE
+        && query.get(..2) == response.get(..2)
+        && match question(query) {
+            Some(asked) => response
+                .get(DNS_HEADER_SIZE..DNS_HEADER_SIZE + asked.len())
+                .is_some_and(|repeated| repeated.eq_ignore_ascii_case(asked)),
+            None => true,
+        }
+}
+
+/// The raw first question of a message we wrote, which is never compressed:
+/// its name, type and class.
+fn question(msg: &[u8]) -> Option<&[u8]> {
+    let mut end = DNS_HEADER_SIZE;
+    loop {
+        let len = usize::from(*msg.get(end)?);
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    msg.get(DNS_HEADER_SIZE..end + 4)
+}
+
+fn is_truncated(msg: &[u8]) -> bool {
+    msg.get(2)
+        .and_then(|byte| HeaderSecondRowFirstHalf::try_from(*byte).ok())
+        .is_some_and(|fh| fh.tc() == &Truncation::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{self, Cursor},
+        net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::buffer::DNS_HEADER_SIZE;
+
+    use super::{
+        answers, is_truncated, query_tcp, query_udp_within, read_frame, serve_tcp_connection,
+        write_frame, SocketPool, TcpLimits, MAX_PIPELINED_QUERIES,
+    };
+
+    /// A query for a.org with txid 0xabcd, and an answer to it.
+    fn exchange() -> (Vec<u8>, Vec<u8>) {
+        let query = [
+            &[0xab, 0xcd][..],
+            &[0; 10],
+            b"\x01a\x03org\x00\x00\x01\x00\x01",
+        ]
+        .concat();
+        let mut response = query.clone();
+        response[2] = 0x80;
+        response[5] = 1;
+        (query, response)
+    }
+
+    #[test]
+    fn test_pipelined_frames() {
+        let mut stream = Vec::new();
+        write_frame(&mut stream, &[1, 2, 3]).unwrap();
+        write_frame(&mut stream, &[4]).unwrap();
+        assert_eq!(stream, [0, 3, 1, 2, 3, 0, 1, 4]);
+
+        let mut stream = Cursor::new(stream);
+        assert_eq!(read_frame(&mut stream).unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(read_frame(&mut stream).unwrap(), Some(vec![4]));
+        assert_eq!(read_frame(&mut stream).unwrap(), None);
+    }
+
+    #[test]
+    fn test_pipelined_queries_are_bounded() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let handler = {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            Arc::new(move |_, query: Vec<u8>| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Some(query)
+            })
+        };
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            serve_tcp_connection(stream, handler, &TcpLimits::default()).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        let queries = 3 * MAX_PIPELINED_QUERIES;
+        for query in 0..queries {
+            write_frame(&mut client, &[query as u8]).unwrap();
+        }
+        for _ in 0..queries {
+            assert!(read_frame(&mut client).unwrap().is_some());
+        }
+        let most = most.load(Ordering::SeqCst);
+        assert!(most > 1 && most <= MAX_PIPELINED_QUERIES, "{most} at once");
+    }
+
+    #[test]
+    fn test_tcp_limits_are_shared_by_connections() {
+        let limits = TcpLimits::new(2, 1);
+        let (first, second) = (limits.admit().unwrap(), limits.admit().unwrap());
+        assert!(limits.admit().is_none());
+        drop(first);
+        assert!(limits.admit().is_some());
+        drop(second);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let handler = {
+            let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+            Arc::new(move |_, query: Vec<u8>| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                most.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(10));
+                running.fetch_sub(1, Ordering::SeqCst);
+                Some(query)
+            })
+        };
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let (handler, limits) = (Arc::clone(&handler), limits.clone());
+                thread::spawn(move || serve_tcp_connection(stream.unwrap(), handler, &limits));
+            }
+        });
+
+        let mut clients = [(); 2].map(|_| TcpStream::connect(addr).unwrap());
+        for client in &mut clients {
+            for query in 0..4 {
+                write_frame(client, &[query]).unwrap();
+            }
+        }
+        for client in &mut clients {
+            for _ in 0..4 {
+                assert!(read_frame(client).unwrap().is_some());
+            }
+        }
+        assert_eq!(most.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_socket_pool_binds_once_per_family() {
+        let pool = SocketPool::new(2);
+        let v4 = SocketAddr::from(([192, 0, 2, 1], 53));
+        let v6 = SocketAddr::from(([0x2001, 0xdb8, 0, 0, 0, 0, 0, 1], 53));
+        assert_eq!(pool.pick(v4), None);
+
+        let bound = pool.bind_for(v4).unwrap();
+        assert_eq!(
+            bound.iter().map(|(idx, _)| *idx).collect::<Vec<_>>(),
+            [0, 1]
+        );
+        assert_eq!(pool.local_addr(1), bound[1].1.local_addr().ok());
+        assert!(pool
+            .bind_for(SocketAddr::from(([192, 0, 2, 2], 53)))
+            .unwrap()
+            .is_empty());
+        assert_eq!(pool.len(), 2);
+        assert!(pool.pick(v4).is_some_and(|idx| idx < 2));
+        assert_eq!(pool.pick(v6), None);
+    }
+
+    #[test]
+    fn test_short_frame_is_an_error() {
+        let mut stream = Cursor::new(vec![0, 5, 1, 2]);
+        assert!(read_frame(&mut stream).is_err());
+    }
+
+    #[test]
+    fn test_answers_needs_txid_and_question() {
+        let (query, mut response) = exchange();
+        assert!(answers(&query, &response));
+        response[13] = b'A';
+        assert!(answers(&query, &response));
+
+        let mut wrong_txid = response.clone();
+        wrong_txid[1] = 0xce;
+        assert!(!answers(&query, &wrong_txid));
+        let mut wrong_name = response.clone();
+        wrong_name[13] = b'b';
+        assert!(!answers(&query, &wrong_name));
+        let mut wrong_type = response.clone();
+        wrong_type[20] = 28;
+        assert!(!answers(&query, &wrong_type));
+        assert!(!answers(&query, &response[..DNS_HEADER_SIZE]));
+    }
+
+    #[test]
+    fn test_is_truncated() {
+        assert!(is_truncated(&[0, 1, 0b1000_0010, 0]));
+        assert!(!is_truncated(&[0, 1, 0b1000_0000, 0]));
+        assert!(!is_truncated(&[0, 1]));
+    }
+
+    #[test]
+    fn test_tcp_answer_to_another_query_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut response = read_frame(&mut stream).unwrap().unwrap();
+                response[1] ^= 0xff;
+                write_frame(&mut stream, &response).unwrap();
+            }
+        });
+
+        let (query, _) = exchange();
+        let err = query_tcp(addr, &query).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_udp_junk_doesnt_extend_the_timeout() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = upstream.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (_, source) = upstream.recv_from(&mut buf).unwrap();
+            for _ in 0..100 {
+                let _ = upstream.send_to(&[0xff; 12], source);
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let (query, _) = exchange();
+        let started = Instant::now();
+        assert!(query_udp_within(addr, &query, Duration::from_millis(100)).is_err());
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
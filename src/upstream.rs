@@ -0,0 +1,391 @@
+use std::{
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+
+use crate::{
+    error::ParseError, message::Message, recursive::is_subdomain, section::Type, transport,
+};
+
+/// Consecutive timeouts after which an upstream is considered down.
+pub const DOWN_AFTER_TIMEOUTS: u32 = 3;
+/// How often a down upstream is sent a probe to see if it's back.
+pub const PROBE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// How the next upstream is picked among the healthy ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strategy {
+    RoundRobin,
+    Random,
+    /// The one with the lowest smoothed round trip time.
+    LowestLatency,
+    /// The first one in the configured order.
+    #[default]
+    Failover,
+}
+
+impl FromStr for Strategy {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "random" => Ok(Strategy::Random),
+            "lowest-latency" => Ok(Strategy::LowestLatency),
+            "failover" => Ok(Strategy::Failover),
+            _ => Err(ParseError::ConversionError),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Health {
+    addr: SocketAddr,
+    srtt: Option<Duration>,
+    timeouts: u32,
+    /// Set while the upstream is down: when it's next sent a probe.
+    next_probe: Option<Instant>,
+}
+
+impl Health {
+    fn is_up(&self) -> bool {
+        self.next_probe.is_none()
+    }
+}
+
+/// The upstream resolvers we forward to and how healthy each one is.
+#[derive(Debug, Clone)]
+pub struct Upstreams {
+    servers: Vec<Health>,
+    strategy: Strategy,
+    cursor: usize,
+}
+
+impl Upstreams {
+    /// `addrs` must not be empty.
+    pub fn new(addrs: Vec<SocketAddr>, strategy: Strategy) -> Self {
+        let servers = addrs
+            .into_iter()
+            .map(|addr| Health {
+                addr,
+                srtt: None,
+                timeouts: 0,
+                next_probe: None,
+            })
+            .collect();
+        Self {
+            servers,
+            strategy,
+            cursor: 0,
+        }
+    }
+
+    pub fn addrs(&self) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.servers.iter().map(|server| server.addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.servers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.servers.is_empty()
+    }
+
+    pub fn strategy(&self) -> Strategy {
+        self.strategy
+    }
+
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        self.servers.iter().any(|server| server.addr == addr)
+    }
+
+    pub fn is_up(&self, addr: SocketAddr) -> bool {
+        self.servers
+            .iter()
+            .any(|server| server.addr == addr && server.is_up())
+    }
+
+    pub fn srtt(&self, addr: SocketAddr) -> Option<Duration> {
+        self.servers
+            .iter()
+            .find(|server| server.addr == addr)
+            .and_then(|server| server.srtt)
+    }
+
+    /// Picks the upstream for the next query, passing over `avoid` (say, the
+    /// upstream a retry timed out on) if there's any other choice. Down
+    /// upstreams are left to `probe`, unless every upstream is down, in
+    /// which case we try them anyway.
+    pub fn select(&mut self, avoid: Option<SocketAddr>) -> SocketAddr {
+        let eligible = |healthy_only: bool, avoiding: bool| {
+            self.servers
+                .iter()
+                .enumerate()
+                .filter(|(_, server)| !healthy_only || server.is_up())
+                .filter(|(_, server)| !avoiding || Some(server.addr) != avoid)
+                .map(|(idx, _)| idx)
+                .collect::<Vec<usize>>()
+        };
+        // loosen the criteria until something's left
+        let candidates = [(true, true), (true, false), (false, true), (false, false)]
+            .into_iter()
+            .map(|(healthy_only, avoiding)| eligible(healthy_only, avoiding))
+            .find(|candidates| !candidates.is_empty())
+            .expect("there is at least one upstream");
+        let idx = match self.strategy {
+            Strategy::RoundRobin => {
+                let idx = candidates
+                    .iter()
+                    .copied()
+                    .find(|idx| *idx >= self.cursor)
+                    .unwrap_or(candidates[0]);
+                self.cursor = idx + 1;
+                idx
+            }
+            Strategy::Random => candidates[rand::thread_rng().gen_range(0..candidates.len())],
+            // unmeasured upstreams go first so each one gets measured
+            Strategy::LowestLatency => candidates
+                .iter()
+                .copied()
+                .min_by_key(|idx| self.servers[*idx].srtt.unwrap_or_default())
+                .expect("there is always a candidate"),
+            Strategy::Failover => candidates[0],
+        };
+        self.servers[idx].addr
+    }
+
+    /// The down upstreams whose probe is due, which aren't due again for
+    /// another `PROBE_INTERVAL`.
+    pub fn due_probes(&mut self, now: Instant) -> Vec<SocketAddr> {
+        self.servers
+            .iter_mut()
+            .filter(|server| server.next_probe.is_some_and(|probe| probe <= now))
+            .map(|server| {
+                server.next_probe = Some(now + PROBE_INTERVAL);
+                server.addr
+            })
+            .collect()
+    }
+
+    /// Records an answer from `addr` after `rtt`, bringing it back up if it
+    /// was down.
+    pub fn record_success(&mut self, addr: SocketAddr, rtt: Duration) {
+        if let Some(server) = self.servers.iter_mut().find(|server| server.addr == addr) {
+            // the usual 7/8 smoothing (RFC 6298)
+            server.srtt = Some(server.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+            server.timeouts = 0;
+            server.next_probe = None;
+        }
+    }
+
+    /// Records a query to `addr` that went unanswered after `timeout`. The
+    /// upstream looks slower from now on, as slow as the timeout if it had
+    /// never answered, and is marked down after `DOWN_AFTER_TIMEOUTS` in a
+    /// row.
+    pub fn record_timeout(&mut self, addr: SocketAddr, timeout: Duration, now: Instant) {
+        if let Some(server) = self.servers.iter_mut().find(|server| server.addr == addr) {
+            server.srtt = Some(server.srtt.map_or(timeout, |srtt| srtt.saturating_mul(2)));
+            server.timeouts += 1;
+            if server.timeouts >= DOWN_AFTER_TIMEOUTS && server.is_up() {
+                server.next_probe = Some(now + PROBE_INTERVAL);
+            }
+        }
+    }
+}
+
+/// Conditional forwarding: names at or under a rule's suffix go to that
+/// rule's upstreams, the longest matching suffix winning, and the rest go to
+/// the default upstreams. Clones share health with the original.
+#[derive(Debug, Clone)]
+pub struct Routes {
+    rules: Vec<(Vec<String>, Arc<Mutex<Upstreams>>)>,
+    default: Arc<Mutex<Upstreams>>,
+}
+
+impl Routes {
+    pub fn new(default: Upstreams, rules: Vec<(Vec<String>, Upstreams)>) -> Self {
+        let mut rules = rules
+            .into_iter()
+            .map(|(suffix, upstreams)| (suffix, Arc::new(Mutex::new(upstreams))))
+            .collect::<Vec<_>>();
+        // most specific first, so the first match is the longest
+        rules.sort_by_key(|(suffix, _)| std::cmp::Reverse(suffix.len()));
+        Self {
+            rules,
+            default: Arc::new(Mutex::new(default)),
+        }
+    }
+
+    /// The upstreams queries for `domain` are forwarded to.
+    pub fn route(&self, domain: &[String]) -> &Arc<Mutex<Upstreams>> {
+        self.rules
+            .iter()
+            .find(|(suffix, _)| is_subdomain(domain, suffix))
+            .map_or(&self.default, |(_, upstreams)| upstreams)
+    }
+
+    /// Every set of upstreams, the default one first.
+    pub fn all(&self) -> impl Iterator<Item = &Arc<Mutex<Upstreams>>> {
+        std::iter::once(&self.default).chain(self.rules.iter().map(|(_, upstreams)| upstreams))
+    }
+
+    /// Every upstream address, the default ones first.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.all()
+            .flat_map(|upstreams| {
+                upstreams
+                    .lock()
+                    .expect("upstreams lock poisoned")
+                    .addrs()
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+/// Asks the down upstream `addr` for the root's name servers, outside of
+/// any client's query, bringing it back up if it answers at all.
+pub fn probe(upstreams: &Mutex<Upstreams>, addr: SocketAddr) {
+    let started = Instant::now();
+    let answered = Vec::<u8>::try_from(Message::query(".", Type::Ns))
+        .map_err(anyhow::Error::from)
+        .and_then(|query| Ok(transport::query_udp(addr, &query)?));
+    let mut upstreams = upstreams.lock().expect("upstreams lock poisoned");
+    match answered {
+        Ok(_) => upstreams.record_success(addr, started.elapsed()),
+        Err(_) => upstreams.record_timeout(addr, transport::UPSTREAM_TIMEOUT, Instant::now()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::message::Message;
+
+    use super::{probe, Routes, Strategy, Upstreams, DOWN_AFTER_TIMEOUTS, PROBE_INTERVAL};
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    fn addrs() -> Vec<SocketAddr> {
+        (1..=3)
+            .map(|host| SocketAddr::from(([192, 0, 2, host], 53)))
+            .collect()
+    }
+
+    #[test]
+    fn test_round_robin_skips_down_upstreams() {
+        let [a, b, c] = addrs()[..] else { panic!() };
+        let mut upstreams = Upstreams::new(addrs(), Strategy::RoundRobin);
+        let now = Instant::now();
+        let picks = (0..4).map(|_| upstreams.select(None)).collect::<Vec<_>>();
+        assert_eq!(picks, vec![a, b, c, a]);
+
+        (0..DOWN_AFTER_TIMEOUTS).for_each(|_| upstreams.record_timeout(b, TIMEOUT, now));
+        assert!(!upstreams.is_up(b));
+        let picks = (0..3).map(|_| upstreams.select(None)).collect::<Vec<_>>();
+        assert_eq!(picks, vec![c, a, c]);
+        assert_eq!(upstreams.select(Some(a)), c);
+    }
+
+    #[test]
+    fn test_failover_probes_and_recovers() {
+        let [a, b, _] = addrs()[..] else { panic!() };
+        let mut upstreams = Upstreams::new(addrs(), Strategy::Failover);
+        let now = Instant::now();
+        assert_eq!(upstreams.select(None), a);
+        (0..DOWN_AFTER_TIMEOUTS).for_each(|_| upstreams.record_timeout(a, TIMEOUT, now));
+        assert_eq!(upstreams.select(None), b);
+
+        // queries stay away from the down upstream while it's probed
+        assert!(upstreams.due_probes(now).is_empty());
+        let later = now + PROBE_INTERVAL;
+        assert_eq!(upstreams.select(None), b);
+        assert_eq!(upstreams.due_probes(later), vec![a]);
+        assert!(upstreams.due_probes(later).is_empty());
+        assert_eq!(upstreams.select(None), b);
+        upstreams.record_success(a, Duration::from_millis(20));
+        assert!(upstreams.is_up(a));
+        assert_eq!(upstreams.select(None), a);
+    }
+
+    #[test]
+    fn test_probe_brings_upstream_back() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 512];
+            let (size, source) = socket.recv_from(&mut buf).unwrap();
+            let query = Message::try_from(&buf[..size]).unwrap();
+            let response = Vec::<u8>::try_from(Message::response_to(&query)).unwrap();
+            socket.send_to(&response, source).unwrap();
+        });
+        let upstreams = Mutex::new(Upstreams::new(vec![addr], Strategy::default()));
+        let now = Instant::now();
+        (0..DOWN_AFTER_TIMEOUTS)
+            .for_each(|_| upstreams.lock().unwrap().record_timeout(addr, TIMEOUT, now));
+        assert!(!upstreams.lock().unwrap().is_up(addr));
+
+        probe(&upstreams, addr);
+        assert!(upstreams.lock().unwrap().is_up(addr));
+    }
+
+    #[test]
+    fn test_lowest_latency_uses_smoothed_rtt() {
+        let [a, b, c] = addrs()[..] else { panic!() };
+        let mut upstreams = Upstreams::new(addrs(), Strategy::LowestLatency);
+        upstreams.record_success(a, Duration::from_millis(80));
+        upstreams.record_success(b, Duration::from_millis(40));
+        upstreams.record_success(c, Duration::from_millis(60));
+        assert_eq!(upstreams.select(None), b);
+
+        upstreams.record_success(b, Duration::from_millis(360));
+        assert_eq!(upstreams.srtt(b), Some(Duration::from_millis(80)));
+        assert_eq!(upstreams.select(None), c);
+    }
+
+    #[test]
+    fn test_lowest_latency_passes_over_unmeasured_timeouts() {
+        let [a, b, _] = addrs()[..] else { panic!() };
+        let mut upstreams = Upstreams::new(addrs(), Strategy::LowestLatency);
+        assert_eq!(upstreams.select(None), a);
+        upstreams.record_timeout(a, TIMEOUT, Instant::now());
+        assert_eq!(upstreams.srtt(a), Some(TIMEOUT));
+        assert!(upstreams.is_up(a));
+        assert_eq!(upstreams.select(None), b);
+    }
+
+    #[test]
+    fn test_routes_pick_longest_suffix() {
+        let [a, b, c] = addrs()[..] else { panic!() };
+        let labels = |name: &str| name.split('.').map(str::to_owned).collect::<Vec<_>>();
+        let upstreams = |addr| Upstreams::new(vec![addr], Strategy::default());
+        let routes = Routes::new(
+            upstreams(a),
+            vec![
+                (labels("example"), upstreams(b)),
+                (labels("corp.example"), upstreams(c)),
+            ],
+        );
+        let route = |name: &str| {
+            let upstreams = routes.route(&labels(name)).lock().unwrap();
+            let addr = upstreams.addrs().next().unwrap();
+            addr
+        };
+        assert_eq!(route("www.CORP.example"), c);
+        assert_eq!(route("corp.example"), c);
+        assert_eq!(route("notcorp.example"), b);
+        assert_eq!(route("example.com"), a);
+        assert_eq!(routes.addrs(), vec![a, c, b]);
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashMap;
+
+use crate::{
+    big_endian_convert_u16_to_u8_array, big_endian_convert_u32_to_u8_array,
+    buffer::DNS_HEADER_SIZE, error::ParseError, header::DnsHeader, section::SectionGroup,
+};
+
+/// Compression pointers only have 14 bits for the offset.
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Longest name on the wire, counting length bytes and the root label.
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Serializes a DNS message while remembering where every owner name suffix
+/// was emitted, so later occurrences can be written as a 2-byte pointer
+/// (RFC 1035 section 4.1.4) instead of the full label sequence.
+#[derive(Debug)]
+pub struct MessageWriter {
+    raw: Vec<u8>,
+    names: HashMap<Vec<String>, u16>,
+    compress: bool,
+}
+
+impl Default for MessageWriter {
+    fn default() -> Self {
+        Self {
+            raw: Vec::new(),
+            names: HashMap::new(),
+            compress: true,
+        }
+    }
+}
+
+impl MessageWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A writer that never emits pointers, for bytes that aren't a whole
+    /// message on their own.
+    pub fn uncompressed() -> Self {
+        Self {
+            compress: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn write_header(&mut self, hdr: DnsHeader) {
+        self.raw.extend(<[u8; 12]>::from(hdr));
+    }
+
+    /// Replaces an already written header, e.g. once the final counts and
+    /// truncation flag are known.
+    pub fn rewrite_header(&mut self, hdr: DnsHeader) {
+        self.raw[..DNS_HEADER_SIZE].copy_from_slice(&<[u8; 12]>::from(hdr));
+    }
+
+    pub fn write_name(&mut self, domain: &[String]) -> Result<(), ParseError> {
+        self.write_name_inner(domain, self.compress)
+    }
+
+    /// Writes the full labels of `domain`; suffixes are still remembered so
+    /// later names can point into it.
+    pub fn write_uncompressed_name(&mut self, domain: &[String]) -> Result<(), ParseError> {
+        self.write_name_inner(domain, false)
+    }
+
+    fn write_name_inner(&mut self, domain: &[String], compress: bool) -> Result<(), ParseError> {
+        // checked up front so a bad name leaves nothing half written; only
+        // the root name ends in an empty label, and that one's implicit
+        if domain
+            .iter()
+            .any(|label| label.is_empty() || label.len() > MAX_LABEL_LENGTH)
+        {
+            return Err(ParseError::ConversionError);
+        }
+        let length = domain.iter().map(|label| 1 + label.len()).sum::<usize>() + 1;
+        if length > MAX_NAME_LENGTH {
+            return Err(ParseError::NameTooLong {
+                offset: self.raw.len(),
+            });
+        }
+        for idx in 0..domain.len() {
+            // names compare case-insensitively, so the suffix table does too
+            let suffix = domain[idx..]
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect::<Vec<String>>();
+            match self.names.get(&suffix) {
+                Some(offset) if compress => {
+                    self.write_u16(0xC000 | offset);
+                    return Ok(());
+                }
+                Some(_) => {}
+                None if self.raw.len() <= MAX_POINTER_OFFSET => {
+                    self.names.insert(suffix, self.raw.len() as u16);
+                }
+                None => {}
+            }
+            let label = &domain[idx];
+            self.raw.push(label.len() as u8);
+            self.raw.extend(label.as_bytes());
+        }
+        self.raw.push(0);
+        Ok(())
+    }
+
+    /// Writes a question if `group` has no answer contents, or a full
+    /// resource record otherwise.
+    pub fn write_section_group(&mut self, group: &SectionGroup) -> Result<(), ParseError> {
+        self.write_name(group.domain())?;
+        self.write_u16(u16::from(group.group_type().clone()));
+        self.write_u16(u16::from(group.class().clone()));
+        if let Some((ttl, rdata)) = &group.asection {
+            self.write_u32(*ttl);
+            let length_pos = self.raw.len();
+            self.write_u16(0);
+            rdata.write(self)?;
+            let length = u16::try_from(self.raw.len() - length_pos - 2)
+                .map_err(|_| ParseError::ConversionError)?;
+            self.raw[length_pos..length_pos + 2]
+                .copy_from_slice(&big_endian_convert_u16_to_u8_array(length));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn write_u8(&mut self, val: u8) {
+        self.raw.push(val);
+    }
+
+    pub(crate) fn write_u16(&mut self, val: u16) {
+        self.raw.extend(big_endian_convert_u16_to_u8_array(val));
+    }
+
+    pub(crate) fn write_u32(&mut self, val: u32) {
+        self.raw.extend(big_endian_convert_u32_to_u8_array(val));
+    }
+
+    pub(crate) fn write_bytes(&mut self, bytes: &[u8]) {
+        self.raw.extend(bytes);
+    }
+
+    /// Drops everything written after `len`, including any names that later
+    /// pointers could otherwise have targeted.
+    pub fn truncate(&mut self, len: usize) {
+        self.raw.truncate(len);
+        self.names.retain(|_, offset| usize::from(*offset) < len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.raw.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.raw.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.raw
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        buffer::UdpBuffer,
+        error::ParseError,
+        header::{
+            AuthAnswer, DnsHeader, HeaderSecondRowFirstHalf, HeaderSecondRowSecondHalf, OpCode,
+            QueryResponse, RecursionAvailablity, RecursionDesired, ResponseCode, SectionCount,
+            Truncation,
+        },
+        section::{Class, RData, SectionGroup, Type},
+    };
+
+    use super::MessageWriter;
+
+    fn domain(name: &str) -> Vec<String> {
+        name.split('.').map(|label| label.to_owned()).collect()
+    }
+
+    #[test]
+    fn test_write_name_compresses_suffix() {
+        let mut writer = MessageWriter::new();
+        writer.write_name(&domain("google.com")).unwrap();
+        writer.write_name(&domain("photos.GOOGLE.com")).unwrap();
+        writer.write_name(&domain("google.com")).unwrap();
+        assert_eq!(
+            writer.into_bytes(),
+            [
+                6, b'g', b'o', b'o', b'g', b'l', b'e', 3, b'c', b'o', b'm', 0, 6, b'p', b'h', b'o',
+                b't', b'o', b's', 0xC0, 0, 0xC0, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_write_name_label_too_long() {
+        let mut writer = MessageWriter::new();
+        assert!(writer.write_name(&["a".repeat(64)]).is_err());
+    }
+
+    #[test]
+    fn test_write_name_rejects_what_the_parser_would() {
+        let mut writer = MessageWriter::new();
+        assert!(writer.write_name(&domain("a..com")).is_err());
+        assert!(writer.write_name(&domain("example.com.")).is_err());
+        assert!(writer.is_empty());
+
+        // four 63-byte labels take 4 * 64 + 1 = 257 bytes; three and a
+        // 61-byte one take exactly 255
+        let longest = [vec!["a".repeat(63); 3], vec!["b".repeat(61)]].concat();
+        assert!(writer.write_name(&longest).is_ok());
+        assert_eq!(writer.len(), 255);
+        let too_long = vec!["a".repeat(63); 4];
+        assert!(matches!(
+            writer.write_name(&too_long),
+            Err(ParseError::NameTooLong { offset: 255 })
+        ));
+        assert_eq!(writer.len(), 255);
+        assert!(writer.write_name(&[]).is_ok());
+    }
+
+    #[test]
+    fn test_compressed_message_round_trip() {
+        let hdr = DnsHeader::new(
+            0x1234,
+            HeaderSecondRowFirstHalf::new(
+                QueryResponse::Response,
+                OpCode::Query,
+                AuthAnswer::NotAuthoritative,
+                Truncation::NotTruncated,
+                RecursionDesired::IWantRecursion,
+            ),
+            HeaderSecondRowSecondHalf::new(
+                RecursionAvailablity::RecursionAvailable,
+                0,
+                ResponseCode::None,
+            )
+            .unwrap(),
+            SectionCount::new(1, 2, 0, 0),
+        );
+        let question = SectionGroup::new(domain("codecrafters.io"), Type::A, Class::In, None);
+        let answers = [
+            SectionGroup::new(
+                domain("codecrafters.io"),
+                Type::A,
+                Class::In,
+                Some((60, RData::A(Ipv4Addr::new(8, 8, 8, 8)))),
+            ),
+            SectionGroup::new(
+                domain("codecrafters.io"),
+                Type::A,
+                Class::In,
+                Some((60, RData::A(Ipv4Addr::new(1, 1, 1, 1)))),
+            ),
+        ];
+        let mut writer = MessageWriter::new();
+        writer.write_header(hdr.clone());
+        writer.write_section_group(&question).unwrap();
+        answers
+            .iter()
+            .for_each(|answer| writer.write_section_group(answer).unwrap());
+        let raw = writer.into_bytes();
+        // 12 header + 21 question + 2 * (2 pointer + 14 fixed/rdata)
+        assert_eq!(raw.len(), 12 + 21 + 2 * 16);
+
+        let (hdr_actual, [qsection, ansection, _, _]) = UdpBuffer::new(&raw).unpack().unwrap();
+        assert_eq!(hdr_actual, hdr);
+        assert_eq!(qsection.unwrap().groups, vec![question]);
+        assert_eq!(ansection.unwrap().groups, answers.to_vec());
+    }
+
+    #[test]
+    fn test_rdata_round_trip() {
+        let hdr = DnsHeader::new(
+            0x4321,
+            HeaderSecondRowFirstHalf::new(
+                QueryResponse::Response,
+                OpCode::Query,
+                AuthAnswer::Authoritative,
+                Truncation::NotTruncated,
+                RecursionDesired::DontWantRecursion,
+            ),
+            HeaderSecondRowSecondHalf::new(
+                RecursionAvailablity::NoRecursionAvailable,
+                0,
+                ResponseCode::None,
+            )
+            .unwrap(),
+            SectionCount::new(0, 5, 0, 0),
+        );
+        let answers = vec![
+            SectionGroup::new(
+                domain("example.com"),
+                Type::Soa,
+                Class::In,
+                Some((
+                    3600,
+                    RData::Soa {
+                        mname: domain("ns1.example.com"),
+                        rname: domain("hostmaster.example.com"),
+                        serial: 2024010101,
+                        refresh: 7200,
+                        retry: 900,
+                        expire: 1209600,
+                        minimum: 300,
+                    },
+                )),
+            ),
+            SectionGroup::new(
+                domain("example.com"),
+                Type::Mx,
+                Class::In,
+                Some((
+                    300,
+                    RData::Mx {
+                        preference: 10,
+                        exchange: domain("mail.example.com"),
+                    },
+                )),
+            ),
+            SectionGroup::new(
+                domain("_sip._tcp.example.com"),
+                Type::Srv,
+                Class::In,
+                Some((
+                    300,
+                    RData::Srv {
+                        priority: 1,
+                        weight: 5,
+                        port: 5060,
+                        target: domain("sip.example.com"),
+                    },
+                )),
+            ),
+            SectionGroup::new(
+                domain("example.com"),
+                Type::Txt,
+                Class::In,
+                Some((300, RData::Txt(vec![b"v=spf1".to_vec(), b"-all".to_vec()]))),
+            ),
+            SectionGroup::new(
+                domain("example.com"),
+                Type::Caa,
+                Class::In,
+                Some((
+                    300,
+                    RData::Caa {
+                        flags: 0,
+                        tag: "issue".to_owned(),
+                        value: b"letsencrypt.org".to_vec(),
+                    },
+                )),
+            ),
+        ];
+        let mut writer = MessageWriter::new();
+        writer.write_header(hdr);
+        answers
+            .iter()
+            .for_each(|answer| writer.write_section_group(answer).unwrap());
+        let raw = writer.into_bytes();
+
+        let (_, [_, ansection, _, _]) = UdpBuffer::new(&raw).unpack().unwrap();
+        assert_eq!(ansection.unwrap().groups, answers);
+    }
+}
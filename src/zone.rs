@@ -0,0 +1,844 @@
+use std::{
+    collections::HashMap,
+    fs, mem,
+    net::{Ipv4Addr, Ipv6Addr},
+    path::Path,
+    str::{self, FromStr},
+};
+
+use crate::{
+    buffer::UdpBuffer,
+    converter::packet::ResponseSections,
+    error::ZoneError,
+    header::{AuthAnswer, ResponseCode},
+    recursive::is_subdomain,
+    section::{Class, RData, SectionGroup, Type},
+};
+
+/// Whether we were authoritative, the response code and the records of an
+/// answer served from one of our zones.
+pub type ZoneAnswer = (AuthAnswer, ResponseCode, ResponseSections);
+
+const MAX_INCLUDE_DEPTH: usize = 8;
+const MAX_CNAME_CHAIN: usize = 8;
+/// QTYPE `*`, which matches every record at the name.
+const ANY_TYPE: u16 = 255;
+
+/// One logical line of a master file: the line it starts on, whether it
+/// starts with whitespace (and so reuses the previous owner) and its fields
+/// with escapes still in place.
+#[derive(Debug)]
+struct Entry {
+    line: usize,
+    blank_owner: bool,
+    fields: Vec<String>,
+}
+
+/// What carries over from one record to the next while reading a zone,
+/// including across `$INCLUDE`s.
+#[derive(Debug, Default)]
+struct ParseState {
+    default_ttl: Option<u32>,
+    last_owner: Option<Vec<String>>,
+    last_ttl: Option<u32>,
+    last_class: Option<Class>,
+}
+
+/// A zone we're authoritative for, loaded from an RFC 1035 master file.
+#[derive(Debug, Clone)]
+pub struct Zone {
+    origin: Vec<String>,
+    class: Class,
+    soa: SectionGroup,
+    /// Records by lowercased owner name.
+    records: HashMap<Vec<String>, Vec<SectionGroup>>,
+}
+
+impl Zone {
+    /// Reads a master file. Relative names before any `$ORIGIN` are taken
+    /// relative to the root; the zone's apex is wherever its SOA is.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ZoneError> {
+        let path = path.as_ref();
+        let mut records = Vec::new();
+        load_into(
+            path,
+            Vec::new(),
+            0,
+            &mut ParseState::default(),
+            &mut records,
+        )?;
+        Self::from_records(&path.display().to_string(), records)
+    }
+
+    /// Parses master file text; `$INCLUDE`s are relative to the working
+    /// directory.
+    pub fn parse(text: &str, origin: &[String]) -> Result<Self, ZoneError> {
+        let mut records = Vec::new();
+        parse_into(
+            text,
+            Path::new("<inline>"),
+            origin.to_vec(),
+            0,
+            &mut ParseState::default(),
+            &mut records,
+        )?;
+        Self::from_records("<inline>", records)
+    }
+
+    fn from_records(path: &str, records: Vec<SectionGroup>) -> Result<Self, ZoneError> {
+        let soas = records
+            .iter()
+            .filter(|group| group.group_type() == &Type::Soa)
+            .collect::<Vec<&SectionGroup>>();
+        let [soa] = soas[..] else {
+            return Err(ZoneError::Soa {
+                path: path.to_owned(),
+                found: soas.len(),
+            });
+        };
+        let soa = soa.clone();
+        let origin = lowercase(soa.domain());
+        let mut by_owner: HashMap<Vec<String>, Vec<SectionGroup>> = HashMap::new();
+        for group in records {
+            if !is_subdomain(group.domain(), &origin) {
+                return Err(ZoneError::OutOfZone {
+                    name: group.domain().join("."),
+                    origin: origin.join("."),
+                });
+            }
+            by_owner
+                .entry(lowercase(group.domain()))
+                .or_default()
+                .push(group);
+        }
+        Ok(Self {
+            origin,
+            class: soa.class().clone(),
+            soa,
+            records: by_owner,
+        })
+    }
+
+    pub fn origin(&self) -> &[String] {
+        &self.origin
+    }
+
+    pub fn class(&self) -> &Class {
+        &self.class
+    }
+
+    /// Answers `question`, which must be at or below our origin: records
+    /// for the name, a referral if it's below a delegation, or NXDOMAIN and
+    /// NODATA with our SOA in the authority section (RFC 1034 section 4.3.2).
+    pub fn lookup(&self, question: &SectionGroup) -> ZoneAnswer {
+        self.lookup_chain(question.domain(), question.group_type(), MAX_CNAME_CHAIN)
+    }
+
+    fn lookup_chain(&self, domain: &[String], group_type: &Type, hops: usize) -> ZoneAnswer {
+        let name = lowercase(domain);
+        // the topmost delegation between our apex and the name wins
+        for cut_len in self.origin.len() + 1..=name.len() {
+            let cut = &name[name.len() - cut_len..];
+            let ns = self.rrset(cut, &Type::Ns);
+            if !ns.is_empty() {
+                let glue = self.addresses(&ns);
+                return (
+                    AuthAnswer::NotAuthoritative,
+                    ResponseCode::None,
+                    (Vec::new(), ns, glue),
+                );
+            }
+        }
+        let Some(groups) = self.records.get(&name) else {
+            // a name that only exists because something below it does is
+            // NODATA rather than NXDOMAIN (RFC 8020)
+            let rcode = match self
+                .records
+                .keys()
+                .any(|owner| owner.len() > name.len() && is_subdomain(owner, &name))
+            {
+                true => ResponseCode::None,
+                false => ResponseCode::Name,
+            };
+            return self.negative(rcode);
+        };
+        let answers = groups
+            .iter()
+            .filter(|group| {
+                group.group_type() == group_type || group_type == &Type::Unknown(ANY_TYPE)
+            })
+            .cloned()
+            .collect::<Vec<SectionGroup>>();
+        if !answers.is_empty() {
+            let additional = self.addresses(&answers);
+            return (
+                AuthAnswer::Authoritative,
+                ResponseCode::None,
+                (answers, Vec::new(), additional),
+            );
+        }
+        let Some(cname) = groups
+            .iter()
+            .find(|group| group.group_type() == &Type::Cname)
+        else {
+            return self.negative(ResponseCode::None);
+        };
+        let (mut rcode, mut answers, mut authority, mut additional) = (
+            ResponseCode::None,
+            vec![cname.clone()],
+            Vec::new(),
+            Vec::new(),
+        );
+        // keep following the chain while it stays inside this zone
+        if let Some((_, RData::Cname(target))) = &cname.asection {
+            if hops > 0 && is_subdomain(target, &self.origin) {
+                let (_, chased_rcode, (chased, chased_authority, chased_additional)) =
+                    self.lookup_chain(target, group_type, hops - 1);
+                rcode = chased_rcode;
+                answers.extend(chased);
+                authority = chased_authority;
+                additional = chased_additional;
+            }
+        }
+        (
+            AuthAnswer::Authoritative,
+            rcode,
+            (answers, authority, additional),
+        )
+    }
+
+    fn rrset(&self, name: &[String], group_type: &Type) -> Vec<SectionGroup> {
+        self.records
+            .get(name)
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter(|group| group.group_type() == group_type)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Our A and AAAA records for the hosts named by NS, MX and SRV records,
+    /// i.e. glue for referrals and additional data for answers.
+    fn addresses(&self, groups: &[SectionGroup]) -> Vec<SectionGroup> {
+        let mut additional = Vec::new();
+        for group in groups {
+            let host = match &group.asection {
+                Some((_, RData::Ns(host)))
+                | Some((_, RData::Mx { exchange: host, .. }))
+                | Some((_, RData::Srv { target: host, .. })) => lowercase(host),
+                _ => continue,
+            };
+            for group_type in [Type::A, Type::Aaaa] {
+                for address in self.rrset(&host, &group_type) {
+                    if !additional.contains(&address) {
+                        additional.push(address);
+                    }
+                }
+            }
+        }
+        additional
+    }
+
+    /// NXDOMAIN or NODATA, with the SOA's TTL capped at its MINIMUM as the
+    /// negative caching TTL (RFC 2308 section 3).
+    fn negative(&self, rcode: ResponseCode) -> ZoneAnswer {
+        let mut soa = self.soa.clone();
+        if let Some((ttl, RData::Soa { minimum, .. })) = soa.asection.as_mut() {
+            *ttl = (*ttl).min(*minimum);
+        }
+        (
+            AuthAnswer::Authoritative,
+            rcode,
+            (Vec::new(), vec![soa], Vec::new()),
+        )
+    }
+}
+
+/// Every zone we serve; questions go to the one closest to the name asked.
+#[derive(Debug, Clone, Default)]
+pub struct Zones {
+    zones: Vec<Zone>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<Zone>) -> Self {
+        Self { zones }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.is_empty()
+    }
+
+    /// The most specific zone covering `question`, if we have one.
+    pub fn find(&self, question: &SectionGroup) -> Option<&Zone> {
+        self.zones
+            .iter()
+            .filter(|zone| {
+                is_subdomain(question.domain(), zone.origin())
+                    && (question.class() == zone.class() || question.class() == &Class::Any)
+            })
+            .max_by_key(|zone| zone.origin().len())
+    }
+
+    /// Answers `question` from our zones, or `None` if it isn't ours to
+    /// answer.
+    pub fn answer(&self, question: &SectionGroup) -> Option<ZoneAnswer> {
+        self.find(question).map(|zone| zone.lookup(question))
+    }
+}
+
+fn lowercase(domain: &[String]) -> Vec<String> {
+    domain
+        .iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+fn load_into(
+    path: &Path,
+    origin: Vec<String>,
+    depth: usize,
+    state: &mut ParseState,
+    records: &mut Vec<SectionGroup>,
+) -> Result<(), ZoneError> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(ZoneError::IncludeDepth {
+            path: path.display().to_string(),
+        });
+    }
+    let text = fs::read_to_string(path).map_err(|source| ZoneError::Io {
+        path: path.display().to_string(),
+        source,
+    })?;
+    parse_into(&text, path, origin, depth, state, records)
+}
+
+fn parse_into(
+    text: &str,
+    path: &Path,
+    mut origin: Vec<String>,
+    depth: usize,
+    state: &mut ParseState,
+    records: &mut Vec<SectionGroup>,
+) -> Result<(), ZoneError> {
+    let syntax = |line: usize, reason: String| ZoneError::Syntax {
+        path: path.display().to_string(),
+        line,
+        reason,
+    };
+    for entry in tokenize(text).map_err(|(line, reason)| syntax(line, reason))? {
+        let fields = entry
+            .fields
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        match fields[..] {
+            ["$ORIGIN", name] => {
+                origin = parse_name(name, &origin).map_err(|reason| syntax(entry.line, reason))?
+            }
+            ["$TTL", ttl] => {
+                state.default_ttl = Some(
+                    parse_ttl(ttl).ok_or_else(|| syntax(entry.line, format!("bad TTL {ttl:?}")))?,
+                )
+            }
+            ["$INCLUDE", file, ref rest @ ..] if rest.len() <= 1 => {
+                let include_origin = match rest.first() {
+                    Some(name) => {
+                        parse_name(name, &origin).map_err(|reason| syntax(entry.line, reason))?
+                    }
+                    None => origin.clone(),
+                };
+                let file = path
+                    .parent()
+                    .map_or_else(|| Path::new(file).to_path_buf(), |dir| dir.join(file));
+                // $ORIGIN changes inside the included file don't leak back out
+                load_into(&file, include_origin, depth + 1, state, records)?;
+            }
+            [directive, ..] if directive.starts_with('$') => {
+                return Err(syntax(
+                    entry.line,
+                    format!("bad or unsupported directive {directive:?}"),
+                ))
+            }
+            _ => records.push(
+                parse_record(&entry, &origin, state)
+                    .map_err(|reason| syntax(entry.line, reason))?,
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Splits master file text into entries, joining lines inside parentheses
+/// and dropping comments.
+fn tokenize(text: &str) -> Result<Vec<Entry>, (usize, String)> {
+    let mut entries = Vec::new();
+    let mut fields = Vec::new();
+    let (mut line, mut start_line, mut depth) = (1, 1, 0usize);
+    let (mut at_line_start, mut blank_owner) = (true, false);
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\n' {
+            line += 1;
+            if depth == 0 {
+                if !fields.is_empty() {
+                    entries.push(Entry {
+                        line: start_line,
+                        blank_owner,
+                        fields: mem::take(&mut fields),
+                    });
+                }
+                at_line_start = true;
+            }
+            continue;
+        }
+        if at_line_start {
+            at_line_start = false;
+            start_line = line;
+            blank_owner = c == ' ' || c == '\t';
+        }
+        match c {
+            ' ' | '\t' | '\r' => {}
+            ';' => while chars.next_if(|c| *c != '\n').is_some() {},
+            '(' => depth += 1,
+            ')' => {
+                depth = depth
+                    .checked_sub(1)
+                    .ok_or_else(|| (line, "unbalanced ')'".to_owned()))?
+            }
+            '"' => {
+                let mut field = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => {
+                            field.push('\\');
+                            field.extend(chars.next());
+                        }
+                        Some('\n') | None => return Err((line, "unterminated string".to_owned())),
+                        Some(c) => field.push(c),
+                    }
+                }
+                fields.push(field);
+            }
+            _ => {
+                let mut field = String::from(c);
+                if c == '\\' {
+                    field.extend(chars.next());
+                }
+                while let Some(c) = chars.next_if(|c| !" \t\r\n;()\"".contains(*c)) {
+                    field.push(c);
+                    if c == '\\' {
+                        field.extend(chars.next());
+                    }
+                }
+                fields.push(field);
+            }
+        }
+    }
+    if depth > 0 {
+        return Err((line, "unbalanced '('".to_owned()));
+    }
+    if !fields.is_empty() {
+        entries.push(Entry {
+            line: start_line,
+            blank_owner,
+            fields,
+        });
+    }
+    Ok(entries)
+}
+
+/// `<owner> [<ttl>] [<class>] <type> <rdata>`, where TTL and class may come
+/// in either order and owner, TTL and class default to the previous record's.
+fn parse_record(
+    entry: &Entry,
+    origin: &[String],
+    state: &mut ParseState,
+) -> Result<SectionGroup, String> {
+    let mut fields = entry.fields.iter().map(String::as_str);
+    let owner = match entry.blank_owner {
+        true => state
+            .last_owner
+            .clone()
+            .ok_or("no previous owner name to reuse")?,
+        false => parse_name(fields.next().ok_or("missing owner name")?, origin)?,
+    };
+    let (mut ttl, mut class) = (None, None);
+    let group_type = loop {
+        let field = fields.next().ok_or("missing record type")?;
+        if ttl.is_none() {
+            if let Some(value) = parse_ttl(field) {
+                ttl = Some(value);
+                continue;
+            }
+        }
+        if class.is_none() {
+            if let Ok(value) = Class::from_str(field) {
+                class = Some(value);
+                continue;
+            }
+        }
+        break Type::from_str(field).map_err(|_| format!("unknown record type {field:?}"))?;
+    };
+    let rdata = parse_rdata(&group_type, &fields.collect::<Vec<&str>>(), origin)?;
+    let ttl = ttl
+        .or(state.default_ttl)
+        .or(state.last_ttl)
+        .or(match &rdata {
+            RData::Soa { minimum, .. } => Some(*minimum),
+            _ => None,
+        })
+        .ok_or("no TTL given and no $TTL to default to")?;
+    let class = class
+        .or_else(|| state.last_class.clone())
+        .unwrap_or(Class::In);
+    state.last_owner = Some(owner.clone());
+    state.last_ttl = Some(ttl);
+    state.last_class = Some(class.clone());
+    Ok(SectionGroup::new(
+        owner,
+        group_type,
+        class,
+        Some((ttl, rdata)),
+    ))
+}
+
+fn parse_rdata(group_type: &Type, fields: &[&str], origin: &[String]) -> Result<RData, String> {
+    let number = |field: &str| {
+        field
+            .parse::<u16>()
+            .map_err(|_| format!("bad number {field:?}"))
+    };
+    let time = |field: &str| parse_ttl(field).ok_or_else(|| format!("bad time value {field:?}"));
+    let rdata = match (group_type, fields) {
+        // RFC 3597 generic form, which works for every type
+        (_, ["\\#", len, hex @ ..]) => {
+            let len = number(len)?;
+            let data = decode_hex(&hex.concat()).ok_or("bad hex in generic RDATA")?;
+            if data.len() != usize::from(len) {
+                return Err(format!("generic RDATA is {} bytes, not {len}", data.len()));
+            }
+            RData::unpack(&mut UdpBuffer::new(&data), group_type, len).map_err(|e| e.to_string())?
+        }
+        (Type::A, [addr]) => {
+            RData::A(Ipv4Addr::from_str(addr).map_err(|_| format!("bad IPv4 address {addr:?}"))?)
+        }
+        (Type::Aaaa, [addr]) => {
+            RData::Aaaa(Ipv6Addr::from_str(addr).map_err(|_| format!("bad IPv6 address {addr:?}"))?)
+        }
+        (Type::Ns, [name]) => RData::Ns(parse_name(name, origin)?),
+        (Type::Cname, [name]) => RData::Cname(parse_name(name, origin)?),
+        (Type::Ptr, [name]) => RData::Ptr(parse_name(name, origin)?),
+        (Type::Mx, [preference, exchange]) => RData::Mx {
+            preference: number(preference)?,
+            exchange: parse_name(exchange, origin)?,
+        },
+        (Type::Soa, [mname, rname, serial, refresh, retry, expire, minimum]) => RData::Soa {
+            mname: parse_name(mname, origin)?,
+            rname: parse_name(rname, origin)?,
+            serial: serial
+                .parse()
+                .map_err(|_| format!("bad serial {serial:?}"))?,
+            refresh: time(refresh)?,
+            retry: time(retry)?,
+            expire: time(expire)?,
+            minimum: time(minimum)?,
+        },
+        (Type::Txt, strings) if !strings.is_empty() => RData::Txt(
+            strings
+                .iter()
+                .map(|string| character_string(string))
+                .collect::<Result<Vec<Vec<u8>>, String>>()?,
+        ),
+        (Type::Srv, [priority, weight, port, target]) => RData::Srv {
+            priority: number(priority)?,
+            weight: number(weight)?,
+            port: number(port)?,
+            target: parse_name(target, origin)?,
+        },
+        (Type::Caa, [flags, tag, value]) => RData::Caa {
+            flags: flags
+                .parse()
+                .map_err(|_| format!("bad CAA flags {flags:?}"))?,
+            tag: String::from_utf8(character_string(tag)?)
+                .map_err(|_| format!("bad CAA tag {tag:?}"))?,
+            value: unescape(value)?,
+        },
+        (Type::A | Type::Aaaa | Type::Ns | Type::Cname | Type::Ptr, _)
+        | (Type::Mx | Type::Soa | Type::Txt | Type::Srv | Type::Caa, _) => {
+            return Err(format!("wrong number of fields for {group_type:?}"))
+        }
+        _ => {
+            return Err(format!(
+                "no text form for {group_type:?}; use the \\# generic form"
+            ))
+        }
+    };
+    Ok(rdata)
+}
+
+/// `@` is the origin, names ending in `.` are absolute and anything else is
+/// relative to the origin.
+fn parse_name(field: &str, origin: &[String]) -> Result<Vec<String>, String> {
+    if field == "@" {
+        return Ok(origin.to_vec());
+    }
+    if field == "." {
+        return Ok(Vec::new());
+    }
+    let mut labels = vec![String::new()];
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '.' => labels.push(String::new()),
+            '\\' => {
+                let last = labels.last_mut().expect("always one label");
+                last.push(c);
+                last.extend(chars.next());
+            }
+            _ => labels.last_mut().expect("always one label").push(c),
+        }
+    }
+    let absolute = labels.last().is_some_and(String::is_empty);
+    if absolute {
+        labels.pop();
+    }
+    let mut domain = labels
+        .iter()
+        .map(|label| {
+            let label = String::from_utf8(unescape(label)?)
+                .map_err(|_| format!("label {label:?} isn't valid UTF-8"))?;
+            match label.len() {
+                0 => Err(format!("empty label in {field:?}")),
+                1..=63 => Ok(label),
+                _ => Err(format!("label {label:?} is longer than 63 bytes")),
+            }
+        })
+        .collect::<Result<Vec<String>, String>>()?;
+    if !absolute {
+        domain.extend(origin.iter().cloned());
+    }
+    if domain.iter().map(|label| label.len() + 1).sum::<usize>() + 1 > 255 {
+        return Err(format!("{field:?} is longer than 255 bytes"));
+    }
+    Ok(domain)
+}
+
+/// A TTL in seconds, optionally written with units as in `1h30m` or `2w`.
+fn parse_ttl(field: &str) -> Option<u32> {
+    if field.is_empty() {
+        return None;
+    }
+    let (mut total, mut value) = (0u32, None);
+    for c in field.chars() {
+        if let Some(digit) = c.to_digit(10) {
+            value = Some(value.unwrap_or(0u32).checked_mul(10)?.checked_add(digit)?);
+            continue;
+        }
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            'w' => 7 * 24 * 60 * 60,
+            _ => return None,
+        };
+        total = total.checked_add(value.take()?.checked_mul(unit)?)?;
+    }
+    total.checked_add(value.unwrap_or(0))
+}
+
+fn character_string(field: &str) -> Result<Vec<u8>, String> {
+    let string = unescape(field)?;
+    match string.len() {
+        0..=255 => Ok(string),
+        _ => Err(format!("{field:?} is longer than 255 bytes")),
+    }
+}
+
+/// Resolves `\X` and `\DDD` escapes.
+fn unescape(field: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            bytes.extend(c.to_string().bytes());
+            continue;
+        }
+        let next = chars
+            .next()
+            .ok_or_else(|| format!("dangling '\\' in {field:?}"))?;
+        if !next.is_ascii_digit() {
+            bytes.extend(next.to_string().bytes());
+            continue;
+        }
+        let digits = [Some(next), chars.next(), chars.next()]
+            .into_iter()
+            .collect::<Option<String>>()
+            .filter(|digits| digits.chars().all(|c| c.is_ascii_digit()))
+            .ok_or_else(|| format!("bad \\DDD escape in {field:?}"))?;
+        bytes.push(
+            digits
+                .parse::<u8>()
+                .map_err(|_| format!("bad \\DDD escape in {field:?}"))?,
+        );
+    }
+    Ok(bytes)
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::{
+        header::{AuthAnswer, ResponseCode},
+        section::{Class, RData, SectionGroup, Type},
+    };
+
+    use super::{Zone, Zones};
+
+    const ZONE: &str = r#"
+$ORIGIN example.com.
+$TTL 1h
+@   IN  SOA ns1 hostmaster (
+            2024010101 ; serial
+            2h 30m 1w
+            300 )
+    IN  NS  ns1
+    IN  MX  10 mail
+ns1     A   192.0.2.1
+mail    A   192.0.2.2
+www 60  CNAME   web.example.com.
+web     A   192.0.2.3
+txt     TXT "hello world" "a\"b"
+a.b.c   A   192.0.2.4
+sub     NS  ns.sub
+ns.sub  A   192.0.2.53
+opaque  TYPE999 \# 2 abcd
+"#;
+
+    fn name(name: &str) -> Vec<String> {
+        name.split('.').map(|label| label.to_owned()).collect()
+    }
+
+    fn question(domain: &str, group_type: Type) -> SectionGroup {
+        SectionGroup::new(name(domain), group_type, Class::In, None)
+    }
+
+    fn a(domain: &str, ttl: u32, addr: [u8; 4]) -> SectionGroup {
+        SectionGroup::new(
+            name(domain),
+            Type::A,
+            Class::In,
+            Some((ttl, RData::A(Ipv4Addr::from(addr)))),
+        )
+    }
+
+    #[test]
+    fn test_parse_master_file() {
+        let zone = Zone::parse(ZONE, &[]).unwrap();
+        assert_eq!(zone.origin(), name("example.com"));
+
+        let (aa, rcode, (answers, _, additional)) = zone.lookup(&question("example.com", Type::Mx));
+        assert_eq!(aa, AuthAnswer::Authoritative);
+        assert_eq!(rcode, ResponseCode::None);
+        assert_eq!(
+            &answers[0].asection,
+            &Some((
+                3600,
+                RData::Mx {
+                    preference: 10,
+                    exchange: name("mail.example.com"),
+                }
+            ))
+        );
+        assert_eq!(
+            additional,
+            vec![a("mail.example.com", 3600, [192, 0, 2, 2])]
+        );
+
+        let (_, _, (answers, _, _)) = zone.lookup(&question("txt.example.com", Type::Txt));
+        assert_eq!(
+            &answers[0].asection,
+            &Some((
+                3600,
+                RData::Txt(vec![b"hello world".to_vec(), b"a\"b".to_vec()])
+            ))
+        );
+        let (_, _, (answers, _, _)) =
+            zone.lookup(&question("opaque.example.com", Type::Unknown(999)));
+        assert_eq!(
+            &answers[0].asection,
+            &Some((3600, RData::Unknown(vec![0xab, 0xcd])))
+        );
+    }
+
+    #[test]
+    fn test_cname_nxdomain_nodata_and_referral() {
+        let zones = Zones::new(vec![Zone::parse(ZONE, &[]).unwrap()]);
+        assert!(zones.answer(&question("example.org", Type::A)).is_none());
+
+        let (_, rcode, (answers, _, _)) =
+            zones.answer(&question("WWW.example.com", Type::A)).unwrap();
+        assert_eq!(rcode, ResponseCode::None);
+        assert_eq!(answers.len(), 2);
+        assert_eq!(answers[1], a("web.example.com", 3600, [192, 0, 2, 3]));
+
+        let (aa, rcode, (answers, authority, _)) = zones
+            .answer(&question("nope.example.com", Type::A))
+            .unwrap();
+        assert_eq!(aa, AuthAnswer::Authoritative);
+        assert_eq!(rcode, ResponseCode::Name);
+        assert!(answers.is_empty());
+        // negative TTL is capped at the SOA minimum
+        assert_eq!(authority[0].group_type(), &Type::Soa);
+        assert_eq!(authority[0].asection.as_ref().unwrap().0, 300);
+
+        let (_, rcode, (answers, authority, _)) =
+            zones.answer(&question("b.c.example.com", Type::A)).unwrap();
+        assert_eq!(rcode, ResponseCode::None);
+        assert!(answers.is_empty());
+        assert_eq!(authority[0].group_type(), &Type::Soa);
+
+        let (aa, rcode, (answers, authority, additional)) = zones
+            .answer(&question("www.sub.example.com", Type::A))
+            .unwrap();
+        assert_eq!(aa, AuthAnswer::NotAuthoritative);
+        assert_eq!(rcode, ResponseCode::None);
+        assert!(answers.is_empty());
+        assert_eq!(authority[0].group_type(), &Type::Ns);
+        assert_eq!(
+            additional,
+            vec![a("ns.sub.example.com", 3600, [192, 0, 2, 53])]
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        let err = Zone::parse(
+            "$TTL 60\n@ SOA a b 1 2 3 4 5\nx A 999.0.0.1\n",
+            &name("example"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "<inline>:3: bad IPv4 address \"999.0.0.1\""
+        );
+        assert!(Zone::parse("$TTL 60\nx A 192.0.2.1\n", &name("example")).is_err());
+        assert!(Zone::parse("@ 60 SOA a b ( 1 2 3 4 5\n", &name("example")).is_err());
+    }
+}